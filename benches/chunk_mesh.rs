@@ -0,0 +1,96 @@
+// This file is part of Hashlife3d.
+//
+// Hashlife3d is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Hashlife3d is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with Hashlife3d.  If not, see <https://www.gnu.org/licenses/>
+#[macro_use]
+extern crate bencher;
+extern crate hashlife3d;
+extern crate voxels_math as math;
+
+use bencher::{black_box, Bencher};
+use hashlife3d::block::{self, Block, GlobalRenderProperties};
+use hashlife3d::chunk_cache::{generate_chunk_mesh, get_chunk_neighborhood, Blocks, CHUNK_SIZE};
+use hashlife3d::hashtable::DefaultBuildHasher;
+use hashlife3d::registry::{Registry, RegistryBuilder};
+use hashlife3d::world3d::{State, World};
+use math::Mappable;
+
+fn create_registry() -> Registry {
+    let mut registry_builder = RegistryBuilder::new();
+    block::register_blocks(&mut registry_builder);
+    registry_builder.finish_startup()
+}
+
+// a ball of stone with a rough surface, so the chunk has lots of visible faces
+fn create_state(registry: &Registry) -> State<Block, DefaultBuildHasher> {
+    let stone_block_id = registry.find_block_by_name("voxels:stone").unwrap();
+    let air_block_id = registry.find_block_by_name("voxels:air").unwrap();
+    let mut world = World::new(
+        |neighborhood: &[[[Block; 3]; 3]; 3]| neighborhood[1][1][1],
+        DefaultBuildHasher::new(),
+    );
+    let mut state = State::create_empty(&mut world);
+    let size = CHUNK_SIZE as i32 * 2;
+    state.set_cube_pow2(
+        &mut world,
+        math::Vec3::splat(-size),
+        size as u32 * 2,
+        |position: math::Vec3<u32>, original: Block| {
+            let position = position.map(|v| v as i32 - size);
+            let distance_squared = position.x * position.x
+                + position.y * position.y
+                + position.z * position.z
+                + (position.x ^ position.y ^ position.z) % 7;
+            if distance_squared < size * size {
+                Block::with_light_from(stone_block_id, original)
+            } else {
+                Block::with_light_from(air_block_id, original)
+            }
+        },
+    );
+    state
+}
+
+fn generate_chunk_meshes(bench: &mut Bencher) {
+    let registry = create_registry();
+    let state = create_state(&registry);
+    let mut blocks = Blocks::new();
+    let chunk_range = -2..2;
+    let mut chunks = Vec::new();
+    for x in chunk_range.clone() {
+        for y in chunk_range.clone() {
+            for z in chunk_range.clone() {
+                let chunk_position =
+                    math::Vec3::new(x, y, z) * math::Vec3::splat(CHUNK_SIZE as i32);
+                chunks.push((
+                    chunk_position,
+                    get_chunk_neighborhood(&state, chunk_position),
+                ));
+            }
+        }
+    }
+    bench.iter(|| {
+        for (chunk_position, neighborhood) in &chunks {
+            black_box(generate_chunk_mesh(
+                neighborhood,
+                GlobalRenderProperties::default(),
+                *chunk_position,
+                &mut blocks,
+                &registry,
+            ));
+        }
+    });
+}
+
+benchmark_group!(benches, generate_chunk_meshes);
+benchmark_main!(benches);
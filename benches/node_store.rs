@@ -0,0 +1,132 @@
+// This file is part of Hashlife3d.
+//
+// Hashlife3d is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Hashlife3d is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with Hashlife3d.  If not, see <https://www.gnu.org/licenses/>
+#[macro_use]
+extern crate bencher;
+extern crate hashlife3d;
+extern crate voxels_math as math;
+
+use bencher::{black_box, Bencher};
+use hashlife3d::hashtable::{ArenaHashTable, DefaultBuildHasher, HashTable};
+use hashlife3d::world3d::{State, World};
+
+// about the size of a nonleaf node key, with 32-bit indexes for the children
+type Key = [u32; 9];
+
+const KEY_COUNT: usize = 100000;
+
+fn get_keys() -> Vec<Key> {
+    let mut state = 0x12345678u64;
+    (0..KEY_COUNT)
+        .map(|_| {
+            let mut key = [0; 9];
+            for v in key.iter_mut() {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                *v = (state % 64) as u32;
+            }
+            key
+        }).collect()
+}
+
+fn chained_table_insert(bench: &mut Bencher) {
+    let keys = get_keys();
+    bench.iter(|| {
+        let mut table = HashTable::with_hasher(DefaultBuildHasher::new());
+        for &key in &keys {
+            table.insert(key);
+        }
+        table
+    });
+}
+
+fn arena_table_insert(bench: &mut Bencher) {
+    let keys = get_keys();
+    bench.iter(|| {
+        let mut table = ArenaHashTable::with_hasher(DefaultBuildHasher::new());
+        for &key in &keys {
+            table.insert(key);
+        }
+        table
+    });
+}
+
+fn chained_table_lookup(bench: &mut Bencher) {
+    let keys = get_keys();
+    let mut table = HashTable::with_hasher(DefaultBuildHasher::new());
+    for &key in &keys {
+        table.insert(key);
+    }
+    bench.iter(|| {
+        for key in &keys {
+            black_box(table.get(key));
+        }
+    });
+}
+
+fn arena_table_lookup(bench: &mut Bencher) {
+    let keys = get_keys();
+    let mut table = ArenaHashTable::with_hasher(DefaultBuildHasher::new());
+    for &key in &keys {
+        table.insert(key);
+    }
+    bench.iter(|| {
+        for key in &keys {
+            black_box(table.get(key));
+        }
+    });
+}
+
+fn parity_rule(neighborhood: &[[[u8; 3]; 3]; 3]) -> u8 {
+    let mut retval = 0;
+    for x in neighborhood {
+        for y in x {
+            for z in y {
+                retval ^= z;
+            }
+        }
+    }
+    retval
+}
+
+// the world's node table is picked at build time, so compare
+// `cargo bench --bench node_store -- step_large` with the same command run
+// with `--features chained-node-table` added, which also makes the children
+// of nodes pointers to the boxed nodes instead of arena indexes
+fn step_large(bench: &mut Bencher) {
+    bench.iter(|| {
+        let mut world = World::new(parity_rule, DefaultBuildHasher::new());
+        let mut state = State::create_empty(&mut world);
+        let mut random_state = 0x12345678u32;
+        state.set_cube_pow2(&mut world, math::Vec3::splat(-16), 16, |_, _| {
+            random_state ^= random_state << 13;
+            random_state ^= random_state >> 17;
+            random_state ^= random_state << 5;
+            (random_state % 2) as u8
+        });
+        state.step(&mut world, 6);
+        state
+    });
+}
+
+benchmark_group!(
+    benches,
+    chained_table_insert,
+    arena_table_insert,
+    chained_table_lookup,
+    arena_table_lookup,
+    step_large
+);
+benchmark_main!(benches);
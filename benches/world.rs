@@ -0,0 +1,150 @@
+// This file is part of Hashlife3d.
+//
+// Hashlife3d is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Hashlife3d is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with Hashlife3d.  If not, see <https://www.gnu.org/licenses/>
+#[macro_use]
+extern crate bencher;
+extern crate hashlife3d;
+extern crate voxels_math as math;
+
+use bencher::{black_box, Bencher};
+use hashlife3d::hashtable::{ConcurrentHashTable, DefaultBuildHasher};
+use hashlife3d::rules::OuterTotalisticRule;
+use hashlife3d::world3d::{State, World};
+
+const KEY_COUNT: u64 = 100000;
+
+// the world stores its nodes in a ConcurrentHashTable
+fn get_filled_hash_table() -> ConcurrentHashTable<u64> {
+    let table = ConcurrentHashTable::with_hasher(DefaultBuildHasher::new());
+    for key in 0..KEY_COUNT {
+        table.lock_shard(&key).insert(key);
+    }
+    table
+}
+
+fn hashtable_insert(bench: &mut Bencher) {
+    bench.iter(get_filled_hash_table);
+}
+
+fn hashtable_lookup(bench: &mut Bencher) {
+    let table = get_filled_hash_table();
+    bench.iter(|| {
+        for key in KEY_COUNT / 2..KEY_COUNT * 3 / 2 {
+            black_box(table.lock_shard(&key).get(&key).is_some());
+        }
+    });
+}
+
+fn hashtable_retain(bench: &mut Bencher) {
+    bench.iter(|| {
+        let mut table = get_filled_hash_table();
+        table.retain(|v| *v % 3 != 0);
+        table
+    });
+}
+
+// 3D life variant: born with 5 neighbors, survives with 4 or 5
+const LIFE_RULE: &str = "B5/S4,5";
+
+fn create_random_state(
+    world: &mut World<bool, OuterTotalisticRule, DefaultBuildHasher>,
+    size: u32,
+) -> State<bool, DefaultBuildHasher> {
+    let mut state = State::create_empty(world);
+    let mut random_state = 0x12345678u32;
+    state.set_cube_pow2(
+        world,
+        math::Vec3::splat(-(size as i32)),
+        size,
+        |_, _| {
+            random_state ^= random_state << 13;
+            random_state ^= random_state >> 17;
+            random_state ^= random_state << 5;
+            random_state % 3 == 0
+        },
+    );
+    state
+}
+
+fn create_world() -> World<bool, OuterTotalisticRule, DefaultBuildHasher> {
+    World::new(LIFE_RULE.parse().unwrap(), DefaultBuildHasher::new())
+}
+
+fn set_cube_pow2(bench: &mut Bencher) {
+    bench.iter(|| {
+        let mut world = create_world();
+        create_random_state(&mut world, 32)
+    });
+}
+
+fn step(bench: &mut Bencher, size: u32) {
+    bench.iter(|| {
+        let mut world = create_world();
+        let mut state = create_random_state(&mut world, size);
+        state.step(&mut world, 4);
+        state
+    });
+}
+
+fn step_8(bench: &mut Bencher) {
+    step(bench, 8);
+}
+
+fn step_16(bench: &mut Bencher) {
+    step(bench, 16);
+}
+
+fn step_32(bench: &mut Bencher) {
+    step(bench, 32);
+}
+
+fn step_32_parallel(bench: &mut Bencher) {
+    bench.iter(|| {
+        let mut world = create_world();
+        let mut state = create_random_state(&mut world, 32);
+        state.step_parallel(&mut world, 4);
+        state
+    });
+}
+
+fn full_gc(bench: &mut Bencher) {
+    let mut world = create_world();
+    let mut state = create_random_state(&mut world, 32);
+    state.step(&mut world, 4);
+    bench.iter(|| world.full_gc());
+}
+
+fn step_and_gc(bench: &mut Bencher) {
+    let mut world = create_world();
+    let mut state = create_random_state(&mut world, 32);
+    bench.iter(|| {
+        state.step(&mut world, 0);
+        world.gc()
+    });
+}
+
+benchmark_group!(
+    benches,
+    hashtable_insert,
+    hashtable_lookup,
+    hashtable_retain,
+    set_cube_pow2,
+    step_8,
+    step_16,
+    step_32,
+    step_32_parallel,
+    full_gc,
+    step_and_gc
+);
+benchmark_main!(benches);
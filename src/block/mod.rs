@@ -127,6 +127,23 @@ mod block_definition {
                 natural_direct_light_level,
             )
         }
+        pub fn propagate_lighting(self, neighborhood: &[[[Block; 3]; 3]; 3]) -> BlockLighting {
+            let mut retval = BlockLighting::default();
+            for &source_face in &BlockFace::ALL {
+                let source_position =
+                    math::Vec3::<i32>::from(source_face).map(|v| (v + 1) as usize);
+                let source =
+                    neighborhood[source_position.x][source_position.y][source_position.z];
+                // space that was never generated is treated as open sky
+                let source_lighting = if source.id() == BlockId::default() {
+                    BlockLighting::SKY
+                } else {
+                    source.lighting()
+                };
+                retval = retval.max(self.propagate_lighting_from(source_lighting, source_face));
+            }
+            retval
+        }
     }
 
     impl Default for BlockLightProperties {
@@ -203,6 +220,9 @@ mod block_definition {
     pub struct BlockLighting(Block);
 
     impl BlockLighting {
+        pub const SKY: BlockLighting = BlockLighting(Block(
+            (LightLevel::MAX.0 << 24) | (LightLevel::MAX.0 << 28),
+        ));
         pub fn artificial_diffuse_light_level(self) -> LightLevel {
             self.0.artificial_diffuse_light_level()
         }
@@ -370,6 +390,10 @@ mod block_definition {
         fn on_register(&self, block_id: BlockId, registry_builder: &mut registry::RegistryBuilder) {
             mem::drop((block_id, registry_builder));
         }
+        fn step(&self, neighborhood: &[[[Block; 3]; 3]; 3], registry: &Registry) -> Block {
+            mem::drop(registry);
+            neighborhood[1][1][1]
+        }
         fn render(
             &self,
             neighborhood: &[[[Block; 3]; 3]; 3],
@@ -456,6 +480,14 @@ mod block_definition {
     }
 
     impl BlockFace {
+        pub const ALL: [BlockFace; 6] = [
+            BlockFace::NX,
+            BlockFace::PX,
+            BlockFace::NY,
+            BlockFace::PY,
+            BlockFace::NZ,
+            BlockFace::PZ,
+        ];
         pub fn opposite(self) -> Self {
             match self {
                 BlockFace::NX => BlockFace::PX,
@@ -848,3 +880,70 @@ pub fn register_blocks(registry_builder: &mut registry::RegistryBuilder) {
         registry_builder.register_block(block);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dark_neighborhood() -> [[[Block; 3]; 3]; 3] {
+        [[[Block::new(BlockId::new(1), BlockLighting::default()); 3]; 3]; 3]
+    }
+
+    #[test]
+    fn test_propagate_lighting() {
+        let mut neighborhood = dark_neighborhood();
+        assert_eq!(
+            BlockLightProperties::AIR.propagate_lighting(&neighborhood),
+            BlockLighting::default()
+        );
+        // direct light only travels down
+        neighborhood[1][2][1] = Block::new(BlockId::new(1), BlockLighting::SKY);
+        assert_eq!(
+            BlockLightProperties::AIR.propagate_lighting(&neighborhood),
+            BlockLighting::SKY
+        );
+        neighborhood = dark_neighborhood();
+        neighborhood[2][1][1] = Block::new(BlockId::new(1), BlockLighting::SKY);
+        neighborhood[1][1][0] = Block::new(
+            BlockId::new(1),
+            BlockLighting::new(LightLevel::new(10), LightLevel::ZERO, LightLevel::ZERO),
+        );
+        assert_eq!(
+            BlockLightProperties::AIR.propagate_lighting(&neighborhood),
+            BlockLighting::new(LightLevel::new(9), LightLevel::new(14), LightLevel::ZERO)
+        );
+        assert_eq!(
+            BlockLightProperties::OPAQUE.propagate_lighting(&neighborhood),
+            BlockLighting::default()
+        );
+        let emissive = BlockLightProperties {
+            emissive: LightLevel::new(12),
+            ..BlockLightProperties::OPAQUE
+        };
+        assert_eq!(
+            emissive.propagate_lighting(&neighborhood),
+            BlockLighting::new(LightLevel::new(12), LightLevel::ZERO, LightLevel::ZERO)
+        );
+    }
+
+    #[test]
+    fn test_propagate_lighting_from_empty() {
+        // empty neighbors count as sky even though they hold no lighting
+        let mut neighborhood = dark_neighborhood();
+        neighborhood[1][2][1] = Block::default();
+        assert_eq!(
+            BlockLightProperties::AIR.propagate_lighting(&neighborhood),
+            BlockLighting::SKY
+        );
+        neighborhood = dark_neighborhood();
+        neighborhood[0][1][1] = Block::default();
+        assert_eq!(
+            BlockLightProperties::AIR.propagate_lighting(&neighborhood),
+            BlockLighting::new(LightLevel::ZERO, LightLevel::new(14), LightLevel::ZERO)
+        );
+        assert_eq!(
+            BlockLightProperties::AIR.propagate_lighting(&[[[Block::default(); 3]; 3]; 3]),
+            BlockLighting::SKY
+        );
+    }
+}
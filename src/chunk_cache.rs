@@ -75,16 +75,16 @@ struct GenerateThreadArgs<DR: DeviceReference> {
 }
 
 const CHUNK_SIZE_SHIFT: u32 = 3;
-const CHUNK_SIZE: u32 = 1 << CHUNK_SIZE_SHIFT;
+pub const CHUNK_SIZE: u32 = 1 << CHUNK_SIZE_SHIFT;
 const CHUNK_MOD_MASK: i32 = CHUNK_SIZE as i32 - 1;
 const CHUNK_FLOOR_MASK: i32 = !CHUNK_MOD_MASK;
 const NEIGHBORHOOD_SIZE: usize = 3;
 
-struct Blocks(Box<[Block]>);
+pub struct Blocks(Box<[Block]>);
 
 impl Blocks {
     const SIZE: usize = CHUNK_SIZE as usize * NEIGHBORHOOD_SIZE;
-    fn new() -> Self {
+    pub fn new() -> Self {
         let blocks_len = Self::SIZE * Self::SIZE * Self::SIZE;
         let mut blocks = Vec::with_capacity(blocks_len);
         for _ in 0..blocks_len {
@@ -123,15 +123,27 @@ fn make_neighborhood<T, F: FnMut(usize, usize, usize) -> T>(
     make_array(|xi| make_array(|yi| make_array(|zi| f(xi, yi, zi))))
 }
 
-fn render_chunk<DR: DeviceReference>(
-    neighborhood: [[[Substate<Block, DefaultBuildHasher>; 3]; 3]; 3],
+pub fn get_chunk_neighborhood(
+    world_state: &State<Block, DefaultBuildHasher>,
+    chunk_position: math::Vec3<i32>,
+) -> [[[Substate<Block, DefaultBuildHasher>; 3]; 3]; 3] {
+    make_neighborhood(|xi, yi, zi| {
+        world_state.get_substate(
+            (math::Vec3::new(xi as i32, yi as i32, zi as i32) - math::Vec3::splat(1))
+                * math::Vec3::splat(CHUNK_SIZE as i32)
+                + chunk_position,
+            CHUNK_SIZE,
+        )
+    })
+}
+
+pub fn generate_chunk_mesh(
+    neighborhood: &[[[Substate<Block, DefaultBuildHasher>; 3]; 3]; 3],
     global_render_properties: GlobalRenderProperties,
-    device: &DR,
     chunk_position: math::Vec3<i32>,
     blocks: &mut Blocks,
-    loader_command_buffers_sender: &mpsc::Sender<LoaderCommandBufferQueueEntry<DR>>,
     registry: &Registry,
-) -> Result<GenerateThreadChunk, DR::Error> {
+) -> Mesh {
     for xi in 0..NEIGHBORHOOD_SIZE {
         for yi in 0..NEIGHBORHOOD_SIZE {
             for zi in 0..NEIGHBORHOOD_SIZE {
@@ -171,6 +183,25 @@ fn render_chunk<DR: DeviceReference>(
             }
         }
     }
+    mesh
+}
+
+fn render_chunk<DR: DeviceReference>(
+    neighborhood: [[[Substate<Block, DefaultBuildHasher>; 3]; 3]; 3],
+    global_render_properties: GlobalRenderProperties,
+    device: &DR,
+    chunk_position: math::Vec3<i32>,
+    blocks: &mut Blocks,
+    loader_command_buffers_sender: &mpsc::Sender<LoaderCommandBufferQueueEntry<DR>>,
+    registry: &Registry,
+) -> Result<GenerateThreadChunk, DR::Error> {
+    let mesh = generate_chunk_mesh(
+        &neighborhood,
+        global_render_properties,
+        chunk_position,
+        blocks,
+        registry,
+    );
     let rendered_chunk;
     let loader_command_buffer;
     if mesh.triangle_count() != 0 {
@@ -371,14 +402,7 @@ fn generate_thread_fn<DR: DeviceReference>(args: GenerateThreadArgs<DR>) {
             None => continue,
             Some(chunk) => chunk,
         };
-        let neighborhood = make_neighborhood(|xi, yi, zi| {
-            world_state.get_substate(
-                (math::Vec3::new(xi as i32, yi as i32, zi as i32) - math::Vec3::splat(1))
-                    * math::Vec3::splat(CHUNK_SIZE as i32)
-                    + chunk_position,
-                CHUNK_SIZE,
-            )
-        });
+        let neighborhood = get_chunk_neighborhood(&world_state, chunk_position);
         match chunks.entry(chunk_position) {
             Entry::Occupied(ref entry)
                 if entry.get().neighborhood == neighborhood
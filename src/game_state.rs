@@ -13,7 +13,7 @@
 // You should have received a copy of the GNU Lesser General Public License
 // along with Hashlife3d.  If not, see <https://www.gnu.org/licenses/>
 
-use block::{Block, BlockLighting, GlobalRenderProperties, LightLevel};
+use block::{Block, BlockId, BlockLighting, GlobalRenderProperties, LightLevel};
 use chunk_cache::ChunkCache;
 use hashtable::DefaultBuildHasher;
 use math::{self, Dot, Mappable, Reducible};
@@ -28,12 +28,28 @@ use std::thread;
 use std::time;
 use world3d;
 
-struct StepFn;
+struct StepFn {
+    registry: Registry,
+}
 
 impl world3d::StepFn<Block> for StepFn {
     fn step(&self, neighborhood: &[[[Block; 3]; 3]; 3]) -> Block {
-        // FIXME: finish implementing
-        neighborhood[1][1][1]
+        let block_id = self
+            .registry
+            .get_block(neighborhood[1][1][1].id())
+            .descriptor
+            .step(neighborhood, &self.registry)
+            .id();
+        if block_id == BlockId::default() {
+            // keep empty space empty so it stays shared in the world
+            return Block::default();
+        }
+        let lighting = self
+            .registry
+            .get_block(block_id)
+            .light_properties
+            .propagate_lighting(neighborhood);
+        Block::new(block_id, lighting)
     }
 }
 
@@ -83,9 +99,9 @@ impl GameState {
                                     return original;
                                 }
                                 if position.dot(position) >= size * size {
-                                    stone_block
+                                    Block::with_light_from(stone_block_id, original)
                                 } else {
-                                    air_block
+                                    Block::with_light_from(air_block_id, original)
                                 }
                             },
                         );
@@ -112,7 +128,7 @@ impl GameState {
             }
             let _ = elapsed_time;
             //println!("step duration: {:?}", elapsed_time);
-            world_state.step(&mut world, 1);
+            world_state.step_parallel(&mut world, 1);
             angle += 1;
             if angle >= angle_step_count {
                 angle = 0;
@@ -142,9 +158,9 @@ impl GameState {
                                     ).map(|v| v as f32)).reduce(|a, b| a * b)
                                         > 0.0
                                 {
-                                    stone_block
+                                    Block::with_light_from(stone_block_id, original)
                                 } else {
-                                    air_block
+                                    Block::with_light_from(air_block_id, original)
                                 }
                             },
                         );
@@ -180,7 +196,12 @@ impl GameState {
         }
     }
     pub fn new(registry: Registry) -> Self {
-        let mut world = world3d::World::new(StepFn, Default::default());
+        let mut world = world3d::World::new(
+            StepFn {
+                registry: registry.clone(),
+            },
+            Default::default(),
+        );
         let current_state = world3d::State::create_empty(&mut world);
         let game_thread_world_state = current_state.clone();
         let (game_state_sender, game_state_receiver) = mpsc::sync_channel(1);
@@ -395,3 +416,84 @@ impl<'a, D: Device> RenderState<'a, D> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use block::{
+        self, AdjacentBlockFaceVisibilities, BlockDescriptor, BlockLightProperties, BlockProperties,
+    };
+    use hashlife3d::geometry::Mesh;
+    use registry::RegistryBuilder;
+
+    // turns into stone when resting on stone
+    #[derive(Debug)]
+    struct Sprout(());
+
+    impl BlockDescriptor for Sprout {
+        fn get() -> &'static BlockProperties {
+            const DESCRIPTOR: Sprout = Sprout(());
+            const BLOCK: BlockProperties = BlockProperties {
+                descriptor: &DESCRIPTOR,
+                id_string: "test:sprout",
+                light_properties: BlockLightProperties::AIR,
+                adjacent_block_face_visibilities: AdjacentBlockFaceVisibilities::ALL_VISIBLE,
+            };
+            &BLOCK
+        }
+        fn step(&self, neighborhood: &[[[Block; 3]; 3]; 3], registry: &Registry) -> Block {
+            let stone_block_id = registry.find_block_by_name("voxels:stone").unwrap();
+            if neighborhood[1][0][1].id() == stone_block_id {
+                Block::with_light_from(stone_block_id, neighborhood[1][1][1])
+            } else {
+                neighborhood[1][1][1]
+            }
+        }
+        fn render(
+            &self,
+            _neighborhood: &[[[Block; 3]; 3]; 3],
+            _mesh: &mut Mesh,
+            _position: math::Vec3<i32>,
+            _global_render_properties: GlobalRenderProperties,
+            _registry: &Registry,
+        ) {
+        }
+    }
+
+    #[test]
+    fn test_step_dispatch() {
+        let mut registry_builder = RegistryBuilder::new();
+        block::register_blocks(&mut registry_builder);
+        let sprout_block_id = registry_builder.register_block(Sprout::get());
+        let registry = registry_builder.finish_startup();
+        let stone_block_id = registry.find_block_by_name("voxels:stone").unwrap();
+        let step_fn = StepFn {
+            registry: registry.clone(),
+        };
+        let lighting = BlockLighting::new(LightLevel::MAX, LightLevel::MAX, LightLevel::MAX);
+        let mut neighborhood = [[[Block::default(); 3]; 3]; 3];
+        neighborhood[1][1][1] = Block::new(sprout_block_id, lighting);
+        assert_eq!(
+            world3d::StepFn::step(&step_fn, &neighborhood).id(),
+            sprout_block_id
+        );
+        neighborhood[1][0][1] = Block::new(stone_block_id, lighting);
+        assert_eq!(
+            world3d::StepFn::step(&step_fn, &neighborhood).id(),
+            stone_block_id
+        );
+        // stone doesn't override step, so it keeps the center block as is
+        neighborhood[1][1][1] = Block::new(stone_block_id, lighting);
+        assert_eq!(
+            registry
+                .get_block(stone_block_id)
+                .descriptor
+                .step(&neighborhood, &registry),
+            neighborhood[1][1][1]
+        );
+        assert_eq!(
+            world3d::StepFn::step(&step_fn, &neighborhood).id(),
+            stone_block_id
+        );
+    }
+}
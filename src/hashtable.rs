@@ -17,7 +17,14 @@ use std::collections::hash_map;
 use std::fmt;
 use std::hash::{BuildHasher, Hash, Hasher};
 use std::iter::*;
+use std::marker::PhantomData;
 use std::mem;
+#[cfg(feature = "chained-node-table")]
+use std::ptr::NonNull;
+use std::slice;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::{ptr, u32};
 
 struct Node<T: Eq + Hash> {
     value: T,
@@ -452,6 +459,10 @@ impl<T: Eq + Hash, H: BuildHasher> HashTable<T, H> {
             Entry::Vacant(entry) => (true, entry.insert()),
         }
     }
+    pub fn remove(&mut self, key: &T) -> Option<T> {
+        let index = self.get_index(key);
+        self.entry_helper(key, index).map(|entry| entry.remove())
+    }
     pub fn clear(&mut self) {
         self.drain();
     }
@@ -521,3 +532,558 @@ impl<T: Eq + Hash, H: BuildHasher> IntoIterator for HashTable<T, H> {
         self.drain()
     }
 }
+
+const ARENA_FIRST_CHUNK_SIZE_LOG2: u32 = 5;
+const ARENA_EMPTY_SLOT: u32 = u32::MAX;
+const ARENA_REMOVED_SLOT: u32 = u32::MAX - 1;
+const ARENA_DEFAULT_LOAD_FACTOR: f32 = 0.75;
+// enough chunks for every index below ARENA_REMOVED_SLOT
+const ARENA_MAX_CHUNK_COUNT: usize = 33 - ARENA_FIRST_CHUNK_SIZE_LOG2 as usize;
+
+// each chunk is twice the size of the previous one. chunks are only ever
+// added, so values can be read by index without whatever lock guards the table
+struct ArenaChunks<T> {
+    chunks: Vec<AtomicPtr<Option<T>>>,
+    phantom: PhantomData<Option<T>>,
+}
+
+fn get_arena_chunk_size(chunk: usize) -> usize {
+    1 << (ARENA_FIRST_CHUNK_SIZE_LOG2 as usize + chunk)
+}
+
+impl<T> ArenaChunks<T> {
+    fn new() -> Self {
+        Self {
+            chunks: (0..ARENA_MAX_CHUNK_COUNT)
+                .map(|_| AtomicPtr::new(ptr::null_mut()))
+                .collect(),
+            phantom: PhantomData,
+        }
+    }
+    // the allocated chunks' pointers and sizes
+    fn raw_chunks<'a>(&'a self) -> impl Iterator<Item = (*mut Option<T>, usize)> + 'a {
+        self.chunks
+            .iter()
+            .map(|chunk_ptr| chunk_ptr.load(Ordering::Acquire))
+            .take_while(|chunk_ptr| !chunk_ptr.is_null())
+            .enumerate()
+            .map(|(chunk, chunk_ptr)| (chunk_ptr, get_arena_chunk_size(chunk)))
+    }
+    fn slot(&self, index: u32) -> *mut Option<T> {
+        let (chunk, offset) = get_arena_chunk_and_offset(index);
+        let chunk_ptr = self.chunks[chunk].load(Ordering::Acquire);
+        debug_assert!(!chunk_ptr.is_null());
+        unsafe { chunk_ptr.add(offset) }
+    }
+    fn add_chunk(&self, chunk: usize) {
+        let new_chunk: Box<[Option<T>]> = repeat(0)
+            .map(|_| None)
+            .take(get_arena_chunk_size(chunk))
+            .collect();
+        let chunk_ptr = Box::into_raw(new_chunk) as *mut Option<T>;
+        self.chunks[chunk].store(chunk_ptr, Ordering::Release);
+    }
+    // the value must stay in its slot while the reference is used
+    unsafe fn get(&self, index: u32) -> &T {
+        (*self.slot(index)).as_ref().unwrap()
+    }
+}
+
+impl<T> Drop for ArenaChunks<T> {
+    fn drop(&mut self) {
+        for (chunk, chunk_ptr) in self.chunks.iter_mut().enumerate() {
+            let chunk_ptr = *chunk_ptr.get_mut();
+            if !chunk_ptr.is_null() {
+                let chunk_size = get_arena_chunk_size(chunk);
+                mem::drop(unsafe {
+                    Box::from_raw(slice::from_raw_parts_mut(chunk_ptr, chunk_size))
+                });
+            }
+        }
+    }
+}
+
+// stores values in chunks that are never reallocated, so values don't move
+// until they are removed. the table is open addressed using linear probing
+// and holds 32-bit indexes into the chunks.
+pub struct ArenaHashTable<T: Eq + Hash, H: BuildHasher = DefaultBuildHasher> {
+    // shared with a ConcurrentHashTable holding this table as a shard
+    chunks: Arc<ArenaChunks<T>>,
+    allocated_count: u32,
+    free_indexes: Vec<u32>,
+    table: Vec<u32>,
+    size: usize,
+    removed_slot_count: usize,
+    build_hasher: H,
+    load_factor: f32,
+}
+
+impl<T: Eq + Hash + fmt::Debug, H: BuildHasher> fmt::Debug for ArenaHashTable<T, H> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+fn get_arena_chunk_and_offset(index: u32) -> (usize, usize) {
+    let biased_index = index as u64 + (1 << ARENA_FIRST_CHUNK_SIZE_LOG2);
+    let index_log2 = 63 - biased_index.leading_zeros();
+    (
+        (index_log2 - ARENA_FIRST_CHUNK_SIZE_LOG2) as usize,
+        (biased_index - (1 << index_log2)) as usize,
+    )
+}
+
+impl<T: Eq + Hash, H: BuildHasher> ArenaHashTable<T, H> {
+    pub fn with_hasher_and_load_factor(build_hasher: H, load_factor: f32) -> Self {
+        assert!(load_factor > 0.0 && load_factor < 1.0);
+        Self {
+            chunks: Arc::new(ArenaChunks::new()),
+            allocated_count: 0,
+            free_indexes: Vec::new(),
+            table: repeat(ARENA_EMPTY_SLOT).take(INITIAL_SIZE).collect(),
+            size: 0,
+            removed_slot_count: 0,
+            build_hasher: build_hasher,
+            load_factor: load_factor,
+        }
+    }
+    pub fn with_hasher(build_hasher: H) -> Self {
+        Self::with_hasher_and_load_factor(build_hasher, ARENA_DEFAULT_LOAD_FACTOR)
+    }
+    fn get_slot(&self, key: &T) -> usize {
+        assert!(is_power_of_2(self.table.len()));
+        let mask = (self.table.len() - 1) as u64;
+        let mut hasher = self.build_hasher.build_hasher();
+        key.hash(&mut hasher);
+        (hasher.finish() & mask) as usize
+    }
+    fn value(&self, index: u32) -> &T {
+        unsafe { self.chunks.get(index) }
+    }
+    fn value_mut(&mut self, index: u32) -> &mut T {
+        unsafe { (*self.chunks.slot(index)).as_mut().unwrap() }
+    }
+    fn allocate(&mut self, value: T) -> u32 {
+        let index = match self.free_indexes.pop() {
+            Some(index) => index,
+            None => {
+                assert!(self.allocated_count < ARENA_REMOVED_SLOT, "arena is full");
+                let index = self.allocated_count;
+                self.allocated_count += 1;
+                let (chunk, offset) = get_arena_chunk_and_offset(index);
+                if offset == 0 {
+                    self.chunks.add_chunk(chunk);
+                }
+                index
+            }
+        };
+        unsafe {
+            *self.chunks.slot(index) = Some(value);
+        }
+        index
+    }
+    // returns Ok with the slot holding key or Err with the slot to insert key into
+    fn find_slot(&self, key: &T) -> Result<usize, usize> {
+        let mask = self.table.len() - 1;
+        let mut slot = self.get_slot(key);
+        let mut first_removed_slot = None;
+        loop {
+            match self.table[slot] {
+                ARENA_EMPTY_SLOT => return Err(first_removed_slot.unwrap_or(slot)),
+                ARENA_REMOVED_SLOT => if first_removed_slot.is_none() {
+                    first_removed_slot = Some(slot);
+                },
+                index => if self.value(index) == key {
+                    return Ok(slot);
+                },
+            }
+            slot = (slot + 1) & mask;
+        }
+    }
+    fn rehash(&mut self, new_table_size: usize) {
+        let old_table = mem::replace(
+            &mut self.table,
+            repeat(ARENA_EMPTY_SLOT).take(new_table_size).collect(),
+        );
+        self.removed_slot_count = 0;
+        let mask = new_table_size - 1;
+        for index in old_table {
+            if index == ARENA_EMPTY_SLOT || index == ARENA_REMOVED_SLOT {
+                continue;
+            }
+            let mut slot = self.get_slot(self.value(index));
+            while self.table[slot] != ARENA_EMPTY_SLOT {
+                slot = (slot + 1) & mask;
+            }
+            self.table[slot] = index;
+        }
+    }
+    fn expand_if_needed(&mut self) {
+        let max_used_slots = (self.table.len() as f32 * self.load_factor) as usize;
+        if self.size + self.removed_slot_count < max_used_slots {
+            return;
+        }
+        if self.size < max_used_slots / 2 {
+            // mostly removed slots, so just clean them up
+            let table_size = self.table.len();
+            self.rehash(table_size);
+        } else {
+            let table_size = self.table.len() * 2;
+            self.rehash(table_size);
+        }
+    }
+    pub fn get(&self, key: &T) -> Option<&T> {
+        match self.find_slot(key) {
+            Ok(slot) => Some(self.value(self.table[slot])),
+            Err(_) => None,
+        }
+    }
+    pub fn get_mut(&mut self, key: &T) -> Option<&mut T> {
+        match self.find_slot(key) {
+            Ok(slot) => {
+                let index = self.table[slot];
+                Some(self.value_mut(index))
+            }
+            Err(_) => None,
+        }
+    }
+    pub fn insert<'a>(&'a mut self, value: T) -> (bool, &'a mut T) {
+        let (inserted, _, value) = self.insert_with_index(value);
+        (inserted, value)
+    }
+    // also returns the value's index into the arena, which stays the same
+    // until the value is removed
+    pub fn insert_with_index<'a>(&'a mut self, value: T) -> (bool, u32, &'a mut T) {
+        self.expand_if_needed();
+        match self.find_slot(&value) {
+            Ok(slot) => {
+                let index = self.table[slot];
+                (false, index, self.value_mut(index))
+            }
+            Err(slot) => {
+                if self.table[slot] == ARENA_REMOVED_SLOT {
+                    self.removed_slot_count -= 1;
+                }
+                let index = self.allocate(value);
+                self.table[slot] = index;
+                self.size += 1;
+                (true, index, self.value_mut(index))
+            }
+        }
+    }
+    fn remove_slot(&mut self, slot: usize) -> T {
+        let index = self.table[slot];
+        self.table[slot] = ARENA_REMOVED_SLOT;
+        self.removed_slot_count += 1;
+        self.size -= 1;
+        self.free_indexes.push(index);
+        unsafe { (*self.chunks.slot(index)).take().unwrap() }
+    }
+    pub fn remove(&mut self, key: &T) -> Option<T> {
+        match self.find_slot(key) {
+            Ok(slot) => Some(self.remove_slot(slot)),
+            Err(_) => None,
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.size
+    }
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.chunks
+            .raw_chunks()
+            .flat_map(|(chunk_ptr, chunk_size)| unsafe {
+                slice::from_raw_parts(chunk_ptr, chunk_size)
+            }).filter_map(|value| value.as_ref())
+    }
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.chunks
+            .raw_chunks()
+            .flat_map(|(chunk_ptr, chunk_size)| unsafe {
+                slice::from_raw_parts_mut(chunk_ptr, chunk_size)
+            }).filter_map(|value| value.as_mut())
+    }
+    pub fn retain<F: FnMut(&mut T) -> bool>(&mut self, mut f: F) {
+        for slot in 0..self.table.len() {
+            let index = self.table[slot];
+            if index == ARENA_EMPTY_SLOT || index == ARENA_REMOVED_SLOT {
+                continue;
+            }
+            if !f(self.value_mut(index)) {
+                self.remove_slot(slot);
+            }
+        }
+    }
+}
+
+const CONCURRENT_SHARD_COUNT_LOG2: u32 = 6;
+#[cfg(not(feature = "chained-node-table"))]
+const CONCURRENT_ARENA_INDEX_BITS: u32 = 32 - CONCURRENT_SHARD_COUNT_LOG2;
+
+// refers to a value in a ConcurrentHashTable until the value is removed. it's
+// the shard in the high bits and the index into the shard's arena in the low
+// bits, or the value's address with the chained table
+#[cfg(not(feature = "chained-node-table"))]
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct ValueIndex(u32);
+#[cfg(feature = "chained-node-table")]
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct ValueIndex(NonNull<u8>);
+
+// doesn't refer to any value, for values that aren't in a table yet
+impl Default for ValueIndex {
+    #[cfg(not(feature = "chained-node-table"))]
+    fn default() -> Self {
+        ValueIndex(u32::MAX)
+    }
+    #[cfg(feature = "chained-node-table")]
+    fn default() -> Self {
+        ValueIndex(NonNull::dangling())
+    }
+}
+
+// the chained table is only kept around to compare against, build with the
+// chained-node-table feature to use it for the world's nodes
+#[cfg(not(feature = "chained-node-table"))]
+pub type ShardHashTable<T, H> = ArenaHashTable<T, H>;
+#[cfg(not(feature = "chained-node-table"))]
+const SHARD_DEFAULT_LOAD_FACTOR: f32 = ARENA_DEFAULT_LOAD_FACTOR;
+#[cfg(feature = "chained-node-table")]
+pub type ShardHashTable<T, H> = HashTable<T, H>;
+#[cfg(feature = "chained-node-table")]
+const SHARD_DEFAULT_LOAD_FACTOR: f32 = 1.0;
+
+// splits the table into separately locked shards so different threads
+// mostly don't contend for the same lock
+pub struct ConcurrentHashTable<T: Eq + Hash, H: BuildHasher = DefaultBuildHasher> {
+    shards: Vec<Mutex<ShardHashTable<T, H>>>,
+    // the shards' chunks, for reading values by index without locking
+    #[cfg(not(feature = "chained-node-table"))]
+    arenas: Vec<Arc<ArenaChunks<T>>>,
+    build_hasher: H,
+}
+
+impl<T: Eq + Hash + fmt::Debug, H: BuildHasher> fmt::Debug for ConcurrentHashTable<T, H> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.shards.iter()).finish()
+    }
+}
+
+impl<T: Eq + Hash, H: BuildHasher + Clone> ConcurrentHashTable<T, H> {
+    #[cfg(not(feature = "chained-node-table"))]
+    pub fn with_hasher_and_load_factor(build_hasher: H, load_factor: f32) -> Self {
+        let shards: Vec<_> = (0..1 << CONCURRENT_SHARD_COUNT_LOG2)
+            .map(|_| ArenaHashTable::with_hasher_and_load_factor(build_hasher.clone(), load_factor))
+            .collect();
+        Self {
+            arenas: shards.iter().map(|shard| shard.chunks.clone()).collect(),
+            shards: shards.into_iter().map(Mutex::new).collect(),
+            build_hasher: build_hasher,
+        }
+    }
+    #[cfg(feature = "chained-node-table")]
+    pub fn with_hasher_and_load_factor(build_hasher: H, load_factor: f32) -> Self {
+        Self {
+            shards: (0..1 << CONCURRENT_SHARD_COUNT_LOG2)
+                .map(|_| {
+                    Mutex::new(HashTable::with_hasher_and_load_factor(
+                        build_hasher.clone(),
+                        load_factor,
+                    ))
+                }).collect(),
+            build_hasher: build_hasher,
+        }
+    }
+    pub fn with_hasher(build_hasher: H) -> Self {
+        Self::with_hasher_and_load_factor(build_hasher, SHARD_DEFAULT_LOAD_FACTOR)
+    }
+}
+
+impl<T: Eq + Hash, H: BuildHasher> ConcurrentHashTable<T, H> {
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+    // for keeping other per-shard data next to the shards
+    pub fn get_shard_index(&self, key: &T) -> usize {
+        let mut hasher = self.build_hasher.build_hasher();
+        key.hash(&mut hasher);
+        // use the high bits since the shards use the low bits
+        (hasher.finish() >> (64 - CONCURRENT_SHARD_COUNT_LOG2)) as usize
+    }
+    // the approximate number of bytes used to hold value_count values
+    #[cfg(not(feature = "chained-node-table"))]
+    pub fn get_memory_usage(value_count: usize) -> usize {
+        // a slot in the arena and a few 32-bit slots in the table
+        value_count * (mem::size_of::<Option<T>>() + 2 * mem::size_of::<u32>())
+    }
+    #[cfg(feature = "chained-node-table")]
+    pub fn get_memory_usage(value_count: usize) -> usize {
+        // boxed in a chain, with a slot in the table
+        value_count * (mem::size_of::<T>() + 3 * mem::size_of::<usize>())
+    }
+    pub fn lock_shard(&self, key: &T) -> MutexGuard<ShardHashTable<T, H>> {
+        self.lock_shard_at(self.get_shard_index(key))
+    }
+    pub fn lock_shard_at(&self, shard_index: usize) -> MutexGuard<ShardHashTable<T, H>> {
+        self.shards[shard_index].lock().unwrap()
+    }
+    // inserts into the shard locked by lock_shard_at(shard_index), also
+    // returning the value's index
+    #[cfg(not(feature = "chained-node-table"))]
+    pub fn insert_into_shard<'a>(
+        &self,
+        shard: &'a mut ShardHashTable<T, H>,
+        shard_index: usize,
+        value: T,
+    ) -> (bool, ValueIndex, &'a mut T) {
+        let (inserted, index, value) = shard.insert_with_index(value);
+        // all ones is left for ValueIndex::default()
+        assert!(index < (1 << CONCURRENT_ARENA_INDEX_BITS) - 1, "shard is full");
+        let index = (shard_index as u32) << CONCURRENT_ARENA_INDEX_BITS | index;
+        (inserted, ValueIndex(index), value)
+    }
+    #[cfg(feature = "chained-node-table")]
+    pub fn insert_into_shard<'a>(
+        &self,
+        shard: &'a mut ShardHashTable<T, H>,
+        _shard_index: usize,
+        value: T,
+    ) -> (bool, ValueIndex, &'a mut T) {
+        let (inserted, value) = shard.insert(value);
+        (inserted, ValueIndex(NonNull::from(&mut *value).cast()), value)
+    }
+    // doesn't lock the shard, so the value must not be removed while the
+    // reference is used
+    #[cfg(not(feature = "chained-node-table"))]
+    pub unsafe fn get_at(&self, index: ValueIndex) -> &T {
+        let shard_index = index.0 >> CONCURRENT_ARENA_INDEX_BITS;
+        let index = index.0 & ((1 << CONCURRENT_ARENA_INDEX_BITS) - 1);
+        self.arenas[shard_index as usize].get(index)
+    }
+    #[cfg(feature = "chained-node-table")]
+    pub unsafe fn get_at(&self, index: ValueIndex) -> &T {
+        &*(index.0.as_ptr() as *const T)
+    }
+    pub fn shards_mut(&mut self) -> impl Iterator<Item = &mut ShardHashTable<T, H>> {
+        self.shards.iter_mut().map(|shard| shard.get_mut().unwrap())
+    }
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.shards_mut().flat_map(|shard| shard.iter_mut())
+    }
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().len())
+            .sum()
+    }
+    // locks each shard in turn
+    pub fn for_each<F: FnMut(&T)>(&self, mut f: F) {
+        for shard in &self.shards {
+            for value in shard.lock().unwrap().iter() {
+                f(value);
+            }
+        }
+    }
+    pub fn retain<F: FnMut(&mut T) -> bool>(&mut self, mut f: F) {
+        for shard in self.shards_mut() {
+            shard.retain(&mut f);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{HashMap, HashSet};
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_concurrent_insert() {
+        let table = Arc::new(ConcurrentHashTable::with_hasher(DefaultBuildHasher::new()));
+        let threads: Vec<_> = (0..4)
+            .map(|thread_index| {
+                let table = table.clone();
+                thread::spawn(move || {
+                    let mut inserted_count = 0;
+                    for i in 0..10000u32 {
+                        let value = (i + thread_index * 5000) % 20000;
+                        let shard_index = table.get_shard_index(&value);
+                        let (inserted, index) = {
+                            let mut shard = table.lock_shard_at(shard_index);
+                            let (inserted, index, entry) =
+                                table.insert_into_shard(&mut shard, shard_index, value);
+                            assert_eq!(*entry, value);
+                            (inserted, index)
+                        };
+                        // nothing is removed, so reading by index is safe
+                        assert_eq!(unsafe { *table.get_at(index) }, value);
+                        if inserted {
+                            inserted_count += 1;
+                        }
+                    }
+                    inserted_count
+                })
+            }).collect();
+        let inserted_count: usize = threads.into_iter().map(|v| v.join().unwrap()).sum();
+        assert_eq!(inserted_count, 20000);
+        let mut table = Arc::try_unwrap(table).ok().unwrap();
+        assert_eq!(table.len(), 20000);
+        table.retain(|v| *v % 2 == 0);
+        assert_eq!(table.len(), 10000);
+        assert!(table.iter_mut().all(|v| *v % 2 == 0));
+    }
+
+    #[test]
+    fn test_arena() {
+        let mut table = ArenaHashTable::with_hasher(DefaultBuildHasher::new());
+        let mut expected = HashSet::new();
+        let mut addresses = HashMap::new();
+        let mut state = 1u32;
+        for i in 0..20000 {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            let value = state % 3000;
+            if i % 3 == 2 {
+                assert_eq!(table.remove(&value), expected.take(&value));
+                addresses.remove(&value);
+            } else {
+                let (inserted, entry) = table.insert(value);
+                assert_eq!(*entry, value);
+                assert_eq!(inserted, expected.insert(value));
+                // values must not move while they're in the table
+                let address = entry as *mut u32;
+                assert_eq!(*addresses.entry(value).or_insert(address), address);
+            }
+            assert_eq!(table.len(), expected.len());
+        }
+        for value in 0..3000 {
+            assert_eq!(table.get(&value), expected.get(&value));
+        }
+        table.retain(|v| *v % 2 == 0);
+        expected.retain(|v| *v % 2 == 0);
+        let mut values: Vec<u32> = table.iter().cloned().collect();
+        values.sort();
+        let mut expected_values: Vec<u32> = expected.into_iter().collect();
+        expected_values.sort();
+        assert_eq!(values, expected_values);
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut table = HashTable::with_hasher(DefaultBuildHasher::new());
+        for value in 0..100u32 {
+            table.insert(value);
+        }
+        let address = table.get(&50).unwrap() as *const u32;
+        for value in 0..100 {
+            if value % 2 == 1 {
+                assert_eq!(table.remove(&value), Some(value));
+            }
+        }
+        assert_eq!(table.remove(&1), None);
+        assert_eq!(table.len(), 50);
+        // values are boxed, so they don't move when other values are removed
+        assert_eq!(table.get(&50).unwrap() as *const u32, address);
+    }
+}
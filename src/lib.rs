@@ -0,0 +1,38 @@
+// This file is part of Hashlife3d.
+//
+// Hashlife3d is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Hashlife3d is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with Hashlife3d.  If not, see <https://www.gnu.org/licenses/>
+extern crate deflate;
+#[macro_use]
+extern crate enum_map;
+extern crate inflate;
+extern crate rayon;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[cfg(test)]
+extern crate serde_test;
+extern crate voxels_math as math;
+extern crate voxels_renderer as renderer;
+extern crate voxels_resources as resources;
+
+pub mod block;
+pub mod chunk_cache;
+pub mod geometry;
+pub mod hashtable;
+pub mod pattern;
+pub mod registry;
+pub mod rules;
+pub mod save;
+pub mod vox;
+pub mod world3d;
@@ -13,26 +13,16 @@
 // You should have received a copy of the GNU Lesser General Public License
 // along with Hashlife3d.  If not, see <https://www.gnu.org/licenses/>
 #![cfg_attr(not(test), no_main)]
-#[macro_use]
-extern crate enum_map;
+extern crate hashlife3d;
 extern crate quantiles;
-extern crate serde;
-#[macro_use]
-extern crate serde_derive;
-extern crate serde_test;
 extern crate voxels_image as image;
 extern crate voxels_math as math;
 extern crate voxels_renderer as renderer;
 extern crate voxels_resources as resources;
 extern crate voxels_sdl as sdl;
 
-mod block;
-mod chunk_cache;
 mod game_state;
-mod geometry;
-mod hashtable;
-mod registry;
-mod world3d;
+use hashlife3d::{block, chunk_cache, hashtable, registry, world3d};
 use registry::RegistryBuilder;
 use renderer::*;
 use sdl::event::Event;
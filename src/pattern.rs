@@ -0,0 +1,620 @@
+// This file is part of Hashlife3d.
+//
+// Hashlife3d is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Hashlife3d is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with Hashlife3d.  If not, see <https://www.gnu.org/licenses/>
+use math;
+use rules::StateEncoding;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::hash::BuildHasher;
+use world3d::{Region, State, StepFn, World};
+
+// patterns use the states from rules::StateEncoding, 0 is dead
+//
+// RLE is extended to 3D like other 3D CA tools do it: the header can have z,
+// '$' ends a row (along x) and moves to the next y, '/' ends a layer and moves
+// to the next z. cells are 'b'/'o' or '.'/'A'-'X' with 'p'-'y' prefixes for
+// states above 24.
+//
+// cell lists have one "x y z" or "x y z state" line per live cell, '#' starts a
+// comment and "#R rule" gives the rule, like in Life 1.05.
+
+const MAX_RLE_LINE_LENGTH: usize = 70;
+const MAX_STATE: u32 = 255;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PatternError(&'static str);
+
+impl fmt::Display for PatternError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl Error for PatternError {}
+
+#[derive(Clone, Debug, Eq, PartialEq, Default)]
+pub struct Pattern {
+    pub rule: Option<String>,
+    // live cells, sorted by z, then y, then x
+    pub cells: Vec<(math::Vec3<i32>, u32)>,
+}
+
+fn sort_cells(cells: &mut Vec<(math::Vec3<i32>, u32)>) {
+    cells.sort_by_key(|&(position, _)| (position.z, position.y, position.x));
+}
+
+fn parse_rle_header(line: &str) -> Result<(math::Vec3<u32>, Option<String>), PatternError> {
+    // the rule can have commas, so it has to be split off first
+    let (line, rule) = match line.find("rule") {
+        Some(index) => {
+            let rule = line[index + "rule".len()..].trim_start();
+            if !rule.starts_with('=') {
+                return Err(PatternError("invalid RLE header"));
+            }
+            (&line[..index], Some(rule[1..].trim().to_string()))
+        }
+        None => (line, None),
+    };
+    let mut size = [None; 3];
+    for part in line.split(',') {
+        let part = part.trim();
+        if part.is_empty() {
+            continue;
+        }
+        let mut key_value = part.splitn(2, '=');
+        let key = key_value.next().unwrap().trim();
+        let value = match key_value.next().map(|v| v.trim().parse::<u32>()) {
+            Some(Ok(value)) if value <= i32::max_value() as u32 => value,
+            _ => return Err(PatternError("invalid RLE header")),
+        };
+        let index = match key {
+            "x" => 0,
+            "y" => 1,
+            "z" => 2,
+            _ => return Err(PatternError("invalid RLE header")),
+        };
+        if size[index].is_some() {
+            return Err(PatternError("invalid RLE header"));
+        }
+        size[index] = Some(value);
+    }
+    match size {
+        [Some(x), Some(y), z] => Ok((math::Vec3::new(x, y, z.unwrap_or(1)), rule)),
+        _ => Err(PatternError("RLE header must have x and y")),
+    }
+}
+
+fn rle_cell(state: u32, is_multistate: bool) -> String {
+    match (state, is_multistate) {
+        (0, false) => "b".to_string(),
+        (_, false) => "o".to_string(),
+        (0, true) => ".".to_string(),
+        (1..=24, true) => ((b'A' + state as u8 - 1) as char).to_string(),
+        (_, true) => {
+            let prefix = (b'p' + ((state - 25) / 24) as u8) as char;
+            let suffix = (b'A' + ((state - 25) % 24) as u8) as char;
+            format!("{}{}", prefix, suffix)
+        }
+    }
+}
+
+fn push_run(runs: &mut Vec<(u32, String)>, count: u32, item: String) {
+    if count == 0 {
+        return;
+    }
+    if let Some(last) = runs.last_mut() {
+        if last.1 == item {
+            last.0 += count;
+            return;
+        }
+    }
+    runs.push((count, item));
+}
+
+impl Pattern {
+    pub fn from_state<E: StateEncoding, H: BuildHasher>(
+        state: &State<E::Block, H>,
+        encoding: &E,
+        rule: Option<String>,
+    ) -> Self {
+        let mut cells = Vec::new();
+        if let Some(bounding_box) = state.bounding_box() {
+            for (position, block) in state.iter_non_default(bounding_box) {
+                let cell_state = encoding.decode(block);
+                if cell_state != 0 {
+                    cells.push((position, cell_state));
+                }
+            }
+        }
+        sort_cells(&mut cells);
+        Self {
+            rule: rule,
+            cells: cells,
+        }
+    }
+    // sets the cells at offset + their position, dead cells are left alone
+    pub fn write_to_state<E: StateEncoding, Step: StepFn<E::Block>, H: BuildHasher>(
+        &self,
+        state: &mut State<E::Block, H>,
+        world: &mut World<E::Block, Step, H>,
+        offset: math::Vec3<i32>,
+        encoding: &E,
+    ) -> Result<(), PatternError> {
+        if self
+            .cells
+            .iter()
+            .any(|&(_, cell_state)| cell_state >= encoding.state_count())
+        {
+            return Err(PatternError("cell state is too big for the encoding"));
+        }
+        // check everything first so nothing is written if the pattern doesn't fit
+        let fits = {
+            let world = &*world;
+            self.cells.iter().all(|&(position, _)| {
+                let position = math::Vec3::new(
+                    position.x.checked_add(offset.x),
+                    position.y.checked_add(offset.y),
+                    position.z.checked_add(offset.z),
+                );
+                match (position.x, position.y, position.z) {
+                    (Some(x), Some(y), Some(z)) => world.contains(math::Vec3::new(x, y, z)),
+                    _ => false,
+                }
+            })
+        };
+        if !fits {
+            return Err(PatternError("pattern doesn't fit in the world"));
+        }
+        for &(position, cell_state) in &self.cells {
+            let position = position + offset;
+            state.set(world, position, encoding.encode(cell_state));
+        }
+        Ok(())
+    }
+    pub fn bounding_box(&self) -> Option<Region<i32>> {
+        let mut cells = self.cells.iter().map(|&(position, _)| position);
+        let first = cells.next()?;
+        let mut retval = Region::new(first, first);
+        for position in cells {
+            retval.min = math::Vec3::new(
+                retval.min.x.min(position.x),
+                retval.min.y.min(position.y),
+                retval.min.z.min(position.z),
+            );
+            retval.max = math::Vec3::new(
+                retval.max.x.max(position.x),
+                retval.max.y.max(position.y),
+                retval.max.z.max(position.z),
+            );
+        }
+        retval.max = math::Vec3::new(
+            retval.max.x.wrapping_add(1),
+            retval.max.y.wrapping_add(1),
+            retval.max.z.wrapping_add(1),
+        );
+        Some(retval)
+    }
+    // the cells start at the origin
+    pub fn parse_rle(text: &str) -> Result<Self, PatternError> {
+        let mut lines = text
+            .lines()
+            .filter(|line| !line.trim_start().starts_with('#'));
+        let (size, rule) = match lines.next() {
+            Some(line) => parse_rle_header(line)?,
+            None => return Err(PatternError("missing RLE header")),
+        };
+        let mut cells = Vec::new();
+        let mut position = math::Vec3::<u32>::new(0, 0, 0);
+        let mut count: Option<u32> = None;
+        let mut prefix = None;
+        let mut finished = false;
+        'lines: for line in lines {
+            for c in line.chars() {
+                let run_length = count.unwrap_or(1);
+                let cell_state = match (prefix, c) {
+                    (None, '0'..='9') => {
+                        let digit = c as u32 - '0' as u32;
+                        count = match count.unwrap_or(0).checked_mul(10) {
+                            Some(v) if v + digit <= i32::max_value() as u32 => Some(v + digit),
+                            _ => return Err(PatternError("run length too big")),
+                        };
+                        continue;
+                    }
+                    (None, 'p'..='y') => {
+                        prefix = Some(c);
+                        continue;
+                    }
+                    (None, 'b') | (None, '.') => Some(0),
+                    (None, 'o') => Some(1),
+                    (None, 'A'..='X') => Some(c as u32 - 'A' as u32 + 1),
+                    (Some(prefix), 'A'..='X') => {
+                        let cell_state =
+                            25 + (prefix as u32 - 'p' as u32) * 24 + (c as u32 - 'A' as u32);
+                        if cell_state > MAX_STATE {
+                            return Err(PatternError("cell state too big"));
+                        }
+                        Some(cell_state)
+                    }
+                    (None, '$') => {
+                        position.x = 0;
+                        position.y = position.y.saturating_add(run_length);
+                        None
+                    }
+                    (None, '/') => {
+                        position.x = 0;
+                        position.y = 0;
+                        position.z = position.z.saturating_add(run_length);
+                        None
+                    }
+                    (None, '!') => {
+                        finished = true;
+                        break 'lines;
+                    }
+                    (None, c) if c.is_whitespace() && count.is_none() => continue,
+                    _ => return Err(PatternError("invalid RLE cell")),
+                };
+                count = None;
+                prefix = None;
+                if let Some(cell_state) = cell_state {
+                    if position.x + run_length > size.x {
+                        return Err(PatternError("RLE pattern is bigger than its header"));
+                    }
+                    if cell_state != 0 {
+                        // trailing '$' and '/' are fine, so this is only checked for live cells
+                        if position.y >= size.y || position.z >= size.z {
+                            return Err(PatternError("RLE pattern is bigger than its header"));
+                        }
+                        for x in position.x..position.x + run_length {
+                            cells.push((
+                                math::Vec3::new(x as i32, position.y as i32, position.z as i32),
+                                cell_state,
+                            ));
+                        }
+                    }
+                    position.x += run_length;
+                }
+            }
+        }
+        if !finished {
+            return Err(PatternError("RLE pattern must end with '!'"));
+        }
+        Ok(Self {
+            rule: rule,
+            cells: cells,
+        })
+    }
+    // the cells are moved so the bounding box starts at the origin
+    pub fn to_rle(&self) -> String {
+        let bounding_box = self
+            .bounding_box()
+            .unwrap_or(Region::new(math::Vec3::splat(0), math::Vec3::splat(0)));
+        let size = math::Vec3::new(
+            bounding_box.max.x.wrapping_sub(bounding_box.min.x) as u32,
+            bounding_box.max.y.wrapping_sub(bounding_box.min.y) as u32,
+            bounding_box.max.z.wrapping_sub(bounding_box.min.z) as u32,
+        );
+        let mut retval = format!("x = {}, y = {}, z = {}", size.x, size.y, size.z);
+        if let Some(rule) = &self.rule {
+            retval += &format!(", rule = {}", rule);
+        }
+        retval.push('\n');
+        let is_multistate = self.cells.iter().any(|&(_, cell_state)| cell_state > 1);
+        let mut cells = self.cells.clone();
+        sort_cells(&mut cells);
+        let mut runs = Vec::new();
+        let mut current = math::Vec3::<u32>::new(0, 0, 0);
+        for (position, cell_state) in cells {
+            let position = math::Vec3::new(
+                position.x.wrapping_sub(bounding_box.min.x) as u32,
+                position.y.wrapping_sub(bounding_box.min.y) as u32,
+                position.z.wrapping_sub(bounding_box.min.z) as u32,
+            );
+            if position.z > current.z {
+                push_run(&mut runs, position.z - current.z, "/".to_string());
+                current = math::Vec3::new(0, 0, position.z);
+            }
+            if position.y > current.y {
+                push_run(&mut runs, position.y - current.y, "$".to_string());
+                current.x = 0;
+                current.y = position.y;
+            }
+            push_run(
+                &mut runs,
+                position.x - current.x,
+                rle_cell(0, is_multistate),
+            );
+            push_run(&mut runs, 1, rle_cell(cell_state, is_multistate));
+            current.x = position.x + 1;
+        }
+        runs.push((1, "!".to_string()));
+        let mut line_length = 0;
+        for (count, item) in runs {
+            let run = if count == 1 {
+                item
+            } else {
+                format!("{}{}", count, item)
+            };
+            if line_length + run.len() > MAX_RLE_LINE_LENGTH {
+                retval.push('\n');
+                line_length = 0;
+            }
+            line_length += run.len();
+            retval += &run;
+        }
+        retval.push('\n');
+        retval
+    }
+    pub fn parse_cell_list(text: &str) -> Result<Self, PatternError> {
+        let mut rule = None;
+        let mut cells = Vec::new();
+        let mut positions = HashSet::new();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.starts_with("#R") {
+                if rule.is_some() {
+                    return Err(PatternError("duplicate rule in cell list"));
+                }
+                rule = Some(line["#R".len()..].trim().to_string());
+                continue;
+            }
+            let line = match line.find('#') {
+                Some(index) => &line[..index],
+                None => line,
+            };
+            let mut values = Vec::new();
+            for value in line.split_whitespace() {
+                match value.parse::<i64>() {
+                    Ok(value) => values.push(value),
+                    Err(_) => return Err(PatternError("invalid number in cell list")),
+                }
+            }
+            let (position, cell_state) = match values[..] {
+                [] => continue,
+                [x, y, z] => ([x, y, z], 1),
+                [x, y, z, cell_state] => ([x, y, z], cell_state),
+                _ => return Err(PatternError("cell list lines must be \"x y z [state]\"")),
+            };
+            if position
+                .iter()
+                .any(|&v| v < i32::min_value() as i64 || v > i32::max_value() as i64)
+            {
+                return Err(PatternError("cell position out of range"));
+            }
+            if cell_state < 0 || cell_state > MAX_STATE as i64 {
+                return Err(PatternError("cell state out of range"));
+            }
+            let position =
+                math::Vec3::new(position[0] as i32, position[1] as i32, position[2] as i32);
+            if !positions.insert(position) {
+                return Err(PatternError("duplicate cell in cell list"));
+            }
+            if cell_state != 0 {
+                cells.push((position, cell_state as u32));
+            }
+        }
+        sort_cells(&mut cells);
+        Ok(Self {
+            rule: rule,
+            cells: cells,
+        })
+    }
+    pub fn to_cell_list(&self) -> String {
+        let mut retval = String::new();
+        if let Some(rule) = &self.rule {
+            retval += &format!("#R {}\n", rule);
+        }
+        for &(position, cell_state) in &self.cells {
+            retval += &format!("{} {} {}", position.x, position.y, position.z);
+            if cell_state != 1 {
+                retval += &format!(" {}", cell_state);
+            }
+            retval.push('\n');
+        }
+        retval
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hashtable::DefaultBuildHasher;
+    use rules::{BinaryStateEncoding, DirectStateEncoding, GenerationsRule, OuterTotalisticRule};
+
+    fn cells(cells: &[(i32, i32, i32, u32)]) -> Vec<(math::Vec3<i32>, u32)> {
+        let mut retval: Vec<_> = cells
+            .iter()
+            .map(|&(x, y, z, cell_state)| (math::Vec3::new(x, y, z), cell_state))
+            .collect();
+        sort_cells(&mut retval);
+        retval
+    }
+
+    #[test]
+    fn test_rle() {
+        let text = "#N test\nx = 3, y = 2, z = 2, rule = B4/S5,6\n\
+                    bo$3o/\n$o!\n";
+        let pattern = Pattern::parse_rle(text).unwrap();
+        assert_eq!(pattern.rule, Some("B4/S5,6".to_string()));
+        assert!(pattern
+            .rule
+            .as_ref()
+            .unwrap()
+            .parse::<OuterTotalisticRule>()
+            .is_ok());
+        assert_eq!(
+            pattern.cells,
+            cells(&[
+                (1, 0, 0, 1),
+                (0, 1, 0, 1),
+                (1, 1, 0, 1),
+                (2, 1, 0, 1),
+                (0, 1, 1, 1)
+            ])
+        );
+        assert_eq!(
+            pattern.to_rle(),
+            "x = 3, y = 2, z = 2, rule = B4/S5,6\nbo$3o/$o!\n"
+        );
+        assert_eq!(Pattern::parse_rle(&pattern.to_rle()).unwrap(), pattern);
+        // 2D patterns without a rule
+        let pattern = Pattern::parse_rle("x = 3, y = 1\n2bo!").unwrap();
+        assert_eq!(pattern.rule, None);
+        assert_eq!(pattern.cells, cells(&[(2, 0, 0, 1)]));
+        // multiple states
+        let pattern = Pattern {
+            rule: Some("B4/S5/C3".to_string()),
+            cells: cells(&[(0, 0, 0, 2), (1, 0, 0, 2), (5, 0, 0, 30), (-3, 7, 0, 255)]),
+        };
+        let text = pattern.to_rle();
+        assert_eq!(
+            text,
+            "x = 9, y = 8, z = 1, rule = B4/S5/C3\n3.2B3.pF7$yO!\n"
+        );
+        let parsed = Pattern::parse_rle(&text).unwrap();
+        assert_eq!(
+            parsed.cells,
+            cells(&[(3, 0, 0, 2), (4, 0, 0, 2), (8, 0, 0, 30), (0, 7, 0, 255)])
+        );
+        // long patterns are wrapped
+        let pattern = Pattern {
+            rule: None,
+            cells: (0..100)
+                .map(|v| (math::Vec3::new(v * 2, 0, 0), 1))
+                .collect(),
+        };
+        let text = pattern.to_rle();
+        assert!(text.lines().all(|line| line.len() <= MAX_RLE_LINE_LENGTH));
+        assert_eq!(Pattern::parse_rle(&text).unwrap(), pattern);
+        assert_eq!(Pattern::default().to_rle(), "x = 0, y = 0, z = 0\n!\n");
+        assert_eq!(
+            Pattern::parse_rle(&Pattern::default().to_rle()).unwrap(),
+            Pattern::default()
+        );
+        for text in &[
+            "",
+            "bo!",
+            "x = 1!",
+            "x = 1, y = 1, w = 1\no!",
+            "x = 1, y = 1, rule B3/S23\no!",
+            "x = 1, y = 1\no",
+            "x = 1, y = 1\n2o!",
+            "x = 1, y = 1\n$o!",
+            "x = 1, y = 1\n/o!",
+            "x = 1, y = 1\nq!",
+            "x = 1, y = 1\n2 o!",
+            "x = 1, y = 1\nyX!",
+            "x = 1, y = 1\n99999999999o!",
+        ] {
+            assert!(Pattern::parse_rle(text).is_err(), "{:?}", text);
+        }
+        assert!(Pattern::parse_rle("x = 1, y = 1\no$/!").is_ok());
+    }
+
+    #[test]
+    fn test_cell_list() {
+        let text = "#R B4/S5,6\n# a comment\n1 2 3\n\n-4 5 -6 2 # trailing comment\n7 8 9 0\n";
+        let pattern = Pattern::parse_cell_list(text).unwrap();
+        assert_eq!(pattern.rule, Some("B4/S5,6".to_string()));
+        assert_eq!(pattern.cells, cells(&[(1, 2, 3, 1), (-4, 5, -6, 2)]));
+        assert_eq!(pattern.to_cell_list(), "#R B4/S5,6\n-4 5 -6 2\n1 2 3\n");
+        assert_eq!(
+            Pattern::parse_cell_list(&pattern.to_cell_list()).unwrap(),
+            pattern
+        );
+        for text in &[
+            "1 2",
+            "1 2 3 4 5",
+            "1 2 x",
+            "1 2 3\n1 2 3",
+            "1 2 3 256",
+            "1 2 3 -1",
+            "1 2 3000000000",
+            "#R B3/S23\n#R B3/S23",
+        ] {
+            assert!(Pattern::parse_cell_list(text).is_err(), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn test_state() {
+        let pattern = Pattern::parse_rle("x = 2, y = 2, z = 2, rule = 5766\n2o$2o/2o$2o!").unwrap();
+        let rule: OuterTotalisticRule = pattern.rule.as_ref().unwrap().parse().unwrap();
+        let mut world = World::new(rule, DefaultBuildHasher::new());
+        let mut state = State::create_empty(&mut world);
+        let offset = math::Vec3::new(-10, 20, 3);
+        pattern
+            .write_to_state(&mut state, &mut world, offset, &BinaryStateEncoding)
+            .unwrap();
+        assert_eq!(state.population(), 8);
+        // a still life, so stepping doesn't change it
+        state.step(&mut world, 3);
+        let exported = Pattern::from_state(&state, &BinaryStateEncoding, pattern.rule.clone());
+        assert_eq!(exported.bounding_box().unwrap().min, offset);
+        assert_eq!(exported.to_rle(), pattern.to_rle());
+        let multistate = Pattern {
+            rule: None,
+            cells: cells(&[(0, 0, 0, 1), (1, 0, 0, 2)]),
+        };
+        assert!(multistate
+            .write_to_state(&mut state, &mut world, offset, &BinaryStateEncoding)
+            .is_err());
+        let rule: GenerationsRule<DirectStateEncoding> = "B4/S5/C3".parse().unwrap();
+        let mut world = World::new(rule, DefaultBuildHasher::new());
+        let mut state = State::create_empty(&mut world);
+        multistate
+            .write_to_state(&mut state, &mut world, offset, &DirectStateEncoding)
+            .unwrap();
+        let exported = Pattern::from_state(&state, &DirectStateEncoding, None);
+        assert_eq!(exported.to_cell_list(), "-10 20 3\n-9 20 3 2\n");
+        // positions outside of the world are rejected before anything is written
+        let rule: GenerationsRule<DirectStateEncoding> = "B4/S5/C3".parse().unwrap();
+        let mut world = World::with_max_level(rule, DefaultBuildHasher::new(), 3);
+        let mut state = State::create_empty(&mut world);
+        let pattern = Pattern {
+            rule: None,
+            cells: cells(&[(0, 0, 0, 1), (100, 0, 0, 1)]),
+        };
+        assert!(pattern
+            .write_to_state(
+                &mut state,
+                &mut world,
+                math::Vec3::splat(0),
+                &DirectStateEncoding
+            )
+            .is_err());
+        assert_eq!(state.population(), 0);
+        assert!(pattern
+            .write_to_state(
+                &mut state,
+                &mut world,
+                math::Vec3::new(i32::max_value() - 50, 0, 0),
+                &DirectStateEncoding
+            )
+            .is_err());
+        let pattern = Pattern {
+            rule: None,
+            cells: cells(&[(-8, 0, 0, 1), (7, 0, 0, 1)]),
+        };
+        pattern
+            .write_to_state(
+                &mut state,
+                &mut world,
+                math::Vec3::splat(0),
+                &DirectStateEncoding,
+            )
+            .unwrap();
+        assert_eq!(state.population(), 2);
+    }
+}
@@ -0,0 +1,679 @@
+// This file is part of Hashlife3d.
+//
+// Hashlife3d is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Hashlife3d is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with Hashlife3d.  If not, see <https://www.gnu.org/licenses/>
+use block::{Block, BlockId, BlockLighting};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use std::u8;
+use world3d::{BlockType, StepFn};
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ParseRuleError(&'static str);
+
+impl fmt::Display for ParseRuleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl Error for ParseRuleError {}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum Neighborhood {
+    Moore,
+    VonNeumann,
+}
+
+impl Neighborhood {
+    pub fn neighbor_count(self) -> u32 {
+        match self {
+            Neighborhood::Moore => 26,
+            Neighborhood::VonNeumann => 6,
+        }
+    }
+    pub fn count_matching<Block: BlockType, F: FnMut(Block) -> bool>(
+        self,
+        neighborhood: &[[[Block; 3]; 3]; 3],
+        mut f: F,
+    ) -> u32 {
+        let mut retval = 0;
+        for x in 0..3 {
+            for y in 0..3 {
+                for z in 0..3 {
+                    let distance = (x != 1) as u32 + (y != 1) as u32 + (z != 1) as u32;
+                    let is_neighbor = match self {
+                        Neighborhood::Moore => distance != 0,
+                        Neighborhood::VonNeumann => distance == 1,
+                    };
+                    if is_neighbor && f(neighborhood[x][y][z]) {
+                        retval += 1;
+                    }
+                }
+            }
+        }
+        retval
+    }
+}
+
+impl Default for Neighborhood {
+    fn default() -> Self {
+        Neighborhood::Moore
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub struct NeighborCounts(u32);
+
+impl NeighborCounts {
+    pub const MAX_COUNT: u32 = 26;
+    pub fn new() -> Self {
+        NeighborCounts(0)
+    }
+    pub fn with_range(first: u32, last: u32) -> Self {
+        let mut retval = Self::new();
+        for count in first..=last {
+            retval.insert(count);
+        }
+        retval
+    }
+    pub fn contains(self, count: u32) -> bool {
+        count <= Self::MAX_COUNT && (self.0 & (1 << count)) != 0
+    }
+    pub fn insert(&mut self, count: u32) {
+        assert!(count <= Self::MAX_COUNT);
+        self.0 |= 1 << count;
+    }
+    pub fn max(self) -> Option<u32> {
+        if self.0 == 0 {
+            None
+        } else {
+            Some(31 - self.0.leading_zeros())
+        }
+    }
+    pub fn iter(self) -> impl Iterator<Item = u32> {
+        (0..=Self::MAX_COUNT).filter(move |&count| self.contains(count))
+    }
+}
+
+impl fmt::Display for NeighborCounts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut count = 0;
+        let mut first = true;
+        while count <= Self::MAX_COUNT {
+            if !self.contains(count) {
+                count += 1;
+                continue;
+            }
+            let mut last = count;
+            while self.contains(last + 1) {
+                last += 1;
+            }
+            if !first {
+                f.write_str(",")?;
+            }
+            first = false;
+            if last >= count + 2 {
+                write!(f, "{}-{}", count, last)?;
+            } else {
+                write!(f, "{}", count)?;
+                if last != count {
+                    write!(f, ",{}", last)?;
+                }
+            }
+            count = last + 1;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for NeighborCounts {
+    type Err = ParseRuleError;
+    fn from_str(text: &str) -> Result<Self, ParseRuleError> {
+        let mut retval = Self::new();
+        if text.is_empty() {
+            return Ok(retval);
+        }
+        fn parse_count(text: &str) -> Result<u32, ParseRuleError> {
+            match text.parse() {
+                Ok(count) if count <= NeighborCounts::MAX_COUNT => Ok(count),
+                Ok(_) => Err(ParseRuleError("neighbor count out of range")),
+                Err(_) => Err(ParseRuleError("invalid neighbor count")),
+            }
+        }
+        for item in text.split(',') {
+            let mut range = item.splitn(2, '-');
+            let first = parse_count(range.next().unwrap())?;
+            let last = match range.next() {
+                Some(last) => parse_count(last)?,
+                None => first,
+            };
+            if last < first {
+                return Err(ParseRuleError("invalid neighbor count range"));
+            }
+            for count in first..=last {
+                retval.insert(count);
+            }
+        }
+        Ok(retval)
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct OuterTotalisticRule {
+    birth: NeighborCounts,
+    survival: NeighborCounts,
+    neighborhood: Neighborhood,
+}
+
+impl OuterTotalisticRule {
+    pub fn new(
+        birth: NeighborCounts,
+        survival: NeighborCounts,
+        neighborhood: Neighborhood,
+    ) -> Result<Self, ParseRuleError> {
+        if birth.contains(0) {
+            // empty space must stay empty or the world would fill up
+            return Err(ParseRuleError("birth on 0 neighbors is not supported"));
+        }
+        let max_count = NeighborCounts(birth.0 | survival.0).max().unwrap_or(0);
+        if max_count > neighborhood.neighbor_count() {
+            return Err(ParseRuleError("neighbor count out of range"));
+        }
+        Ok(Self {
+            birth: birth,
+            survival: survival,
+            neighborhood: neighborhood,
+        })
+    }
+    pub fn birth(&self) -> NeighborCounts {
+        self.birth
+    }
+    pub fn survival(&self) -> NeighborCounts {
+        self.survival
+    }
+    pub fn neighborhood(&self) -> Neighborhood {
+        self.neighborhood
+    }
+    pub fn is_alive_next(&self, is_alive: bool, live_neighbor_count: u32) -> bool {
+        if is_alive {
+            self.survival.contains(live_neighbor_count)
+        } else {
+            self.birth.contains(live_neighbor_count)
+        }
+    }
+}
+
+impl StepFn<bool> for OuterTotalisticRule {
+    fn step(&self, neighborhood: &[[[bool; 3]; 3]; 3]) -> bool {
+        self.is_alive_next(
+            neighborhood[1][1][1],
+            self.neighborhood.count_matching(neighborhood, |v| v),
+        )
+    }
+}
+
+impl fmt::Display for OuterTotalisticRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "B{}/S{}", self.birth, self.survival)?;
+        match self.neighborhood {
+            Neighborhood::Moore => Ok(()),
+            Neighborhood::VonNeumann => f.write_str("/V"),
+        }
+    }
+}
+
+fn parse_rule_string(text: &str) -> Result<(OuterTotalisticRule, Option<u32>), ParseRuleError> {
+    let text = text.trim();
+    if text.len() == 4 && text.bytes().all(|v| v.is_ascii_digit()) {
+        // Bays's notation: survival range followed by birth range
+        let digit = |index: usize| (text.as_bytes()[index] - b'0') as u32;
+        if digit(1) < digit(0) || digit(3) < digit(2) {
+            return Err(ParseRuleError("invalid neighbor count range"));
+        }
+        let rule = OuterTotalisticRule::new(
+            NeighborCounts::with_range(digit(2), digit(3)),
+            NeighborCounts::with_range(digit(0), digit(1)),
+            Neighborhood::Moore,
+        )?;
+        return Ok((rule, None));
+    }
+    let mut birth = None;
+    let mut survival = None;
+    let mut state_count = None;
+    let mut neighborhood = None;
+    for part in text.split('/') {
+        let mut chars = part.chars();
+        let kind = chars.next().map(|v| v.to_ascii_uppercase());
+        let rest = chars.as_str();
+        match kind {
+            Some('B') if birth.is_none() => birth = Some(rest.parse()?),
+            Some('S') if survival.is_none() => survival = Some(rest.parse()?),
+            Some('C') if state_count.is_none() => match rest.parse() {
+                Ok(count) => state_count = Some(count),
+                Err(_) => return Err(ParseRuleError("invalid state count")),
+            },
+            Some('M') if neighborhood.is_none() && rest.is_empty() => {
+                neighborhood = Some(Neighborhood::Moore)
+            }
+            Some('V') if neighborhood.is_none() && rest.is_empty() => {
+                neighborhood = Some(Neighborhood::VonNeumann)
+            }
+            _ => return Err(ParseRuleError("invalid rule string")),
+        }
+    }
+    match (birth, survival) {
+        (Some(birth), Some(survival)) => Ok((
+            OuterTotalisticRule::new(birth, survival, neighborhood.unwrap_or_default())?,
+            state_count,
+        )),
+        _ => Err(ParseRuleError(
+            "rule string must have both birth and survival",
+        )),
+    }
+}
+
+impl FromStr for OuterTotalisticRule {
+    type Err = ParseRuleError;
+    fn from_str(text: &str) -> Result<Self, ParseRuleError> {
+        match parse_rule_string(text)? {
+            (rule, None) => Ok(rule),
+            (_, Some(_)) => Err(ParseRuleError(
+                "state count is only supported by generations rules",
+            )),
+        }
+    }
+}
+
+pub trait StateEncoding {
+    type Block: BlockType;
+    fn state_count(&self) -> u32;
+    fn decode(&self, block: Self::Block) -> u32;
+    fn encode(&self, state: u32) -> Self::Block;
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub struct DirectStateEncoding;
+
+impl StateEncoding for DirectStateEncoding {
+    type Block = u8;
+    fn state_count(&self) -> u32 {
+        u8::MAX as u32 + 1
+    }
+    fn decode(&self, block: u8) -> u32 {
+        block as u32
+    }
+    fn encode(&self, state: u32) -> u8 {
+        assert!(state <= u8::MAX as u32);
+        state as u8
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub struct BinaryStateEncoding;
+
+impl StateEncoding for BinaryStateEncoding {
+    type Block = bool;
+    fn state_count(&self) -> u32 {
+        2
+    }
+    fn decode(&self, block: bool) -> u32 {
+        block as u32
+    }
+    fn encode(&self, state: u32) -> bool {
+        assert!(state < 2);
+        state != 0
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PaletteStateEncoding {
+    blocks: Vec<Block>,
+    states: HashMap<BlockId, u32>,
+}
+
+impl PaletteStateEncoding {
+    pub fn new(block_ids: &[BlockId]) -> Self {
+        let mut blocks = vec![Block::default()];
+        let mut states = HashMap::new();
+        for &block_id in block_ids {
+            assert_ne!(block_id, BlockId::default());
+            let state = blocks.len() as u32;
+            assert!(
+                states.insert(block_id, state).is_none(),
+                "duplicate block in palette"
+            );
+            blocks.push(Block::new(block_id, BlockLighting::SKY));
+        }
+        Self {
+            blocks: blocks,
+            states: states,
+        }
+    }
+    pub fn block_id(&self, state: u32) -> BlockId {
+        self.blocks[state as usize].id()
+    }
+}
+
+impl StateEncoding for PaletteStateEncoding {
+    type Block = Block;
+    fn state_count(&self) -> u32 {
+        self.blocks.len() as u32
+    }
+    fn decode(&self, block: Block) -> u32 {
+        self.states.get(&block.id()).cloned().unwrap_or(0)
+    }
+    fn encode(&self, state: u32) -> Block {
+        self.blocks[state as usize]
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct GenerationsRule<E: StateEncoding> {
+    rule: OuterTotalisticRule,
+    state_count: u32,
+    encoding: E,
+}
+
+impl<E: StateEncoding> GenerationsRule<E> {
+    pub fn new(
+        rule: OuterTotalisticRule,
+        state_count: u32,
+        encoding: E,
+    ) -> Result<Self, ParseRuleError> {
+        if state_count < 2 {
+            return Err(ParseRuleError("state count must be at least 2"));
+        }
+        if state_count > encoding.state_count() {
+            return Err(ParseRuleError("state count is too big for the encoding"));
+        }
+        if encoding.encode(0) != E::Block::default() {
+            // empty space must stay empty or the world would fill up
+            return Err(ParseRuleError(
+                "encoding must map the dead state to empty space",
+            ));
+        }
+        Ok(Self {
+            rule: rule,
+            state_count: state_count,
+            encoding: encoding,
+        })
+    }
+    pub fn parse(text: &str, encoding: E) -> Result<Self, ParseRuleError> {
+        let (rule, state_count) = parse_rule_string(text)?;
+        Self::new(rule, state_count.unwrap_or(2), encoding)
+    }
+    pub fn rule(&self) -> OuterTotalisticRule {
+        self.rule
+    }
+    pub fn state_count(&self) -> u32 {
+        self.state_count
+    }
+    pub fn encoding(&self) -> &E {
+        &self.encoding
+    }
+    pub fn next_state(&self, state: u32, live_neighbor_count: u32) -> u32 {
+        match state {
+            0 if self.rule.birth.contains(live_neighbor_count) => 1,
+            0 => 0,
+            1 if self.rule.survival.contains(live_neighbor_count) => 1,
+            _ => (state + 1) % self.state_count,
+        }
+    }
+}
+
+impl<E: StateEncoding> StepFn<E::Block> for GenerationsRule<E> {
+    fn step(&self, neighborhood: &[[[E::Block; 3]; 3]; 3]) -> E::Block {
+        let state = self.encoding.decode(neighborhood[1][1][1]);
+        let live_neighbor_count = self
+            .rule
+            .neighborhood
+            .count_matching(neighborhood, |v| self.encoding.decode(v) == 1);
+        self.encoding
+            .encode(self.next_state(state, live_neighbor_count))
+    }
+}
+
+impl<E: StateEncoding> fmt::Display for GenerationsRule<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "B{}/S{}/C{}",
+            self.rule.birth, self.rule.survival, self.state_count
+        )?;
+        match self.rule.neighborhood {
+            Neighborhood::Moore => Ok(()),
+            Neighborhood::VonNeumann => f.write_str("/V"),
+        }
+    }
+}
+
+impl<E: StateEncoding + Default> FromStr for GenerationsRule<E> {
+    type Err = ParseRuleError;
+    fn from_str(text: &str) -> Result<Self, ParseRuleError> {
+        Self::parse(text, E::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hashtable::DefaultBuildHasher;
+    use math::{self, Mappable};
+    use world3d::{State, World};
+
+    fn get<Block: BlockType>(
+        state: &State<Block, DefaultBuildHasher>,
+        position: math::Vec3<i32>,
+    ) -> Block {
+        let substate = state.get_substate(position.map(|v| v & !1), 2);
+        substate.get(position.map(|v| (v & 1) as u32))
+    }
+
+    fn create_state<Step: StepFn<bool>>(
+        world: &mut World<bool, Step, DefaultBuildHasher>,
+        cells: &[(i32, i32, i32)],
+    ) -> State<bool, DefaultBuildHasher> {
+        let mut state = State::create_empty(world);
+        for &(x, y, z) in cells {
+            state.set(world, math::Vec3::new(x, y, z), true);
+        }
+        state
+    }
+
+    #[test]
+    fn test_parse() {
+        let rule: OuterTotalisticRule = "B4/S5,6".parse().unwrap();
+        assert_eq!(rule.birth(), NeighborCounts::with_range(4, 4));
+        assert_eq!(rule.survival(), NeighborCounts::with_range(5, 6));
+        assert_eq!(rule.neighborhood(), Neighborhood::Moore);
+        assert_eq!(rule.to_string(), "B4/S5,6");
+        let rule: OuterTotalisticRule = "5766".parse().unwrap();
+        assert_eq!(rule, "B6/S5-7".parse().unwrap());
+        assert_eq!(rule.to_string(), "B6/S5-7");
+        let rule: OuterTotalisticRule = "s2,4-5/b1/v".parse().unwrap();
+        assert_eq!(rule.neighborhood(), Neighborhood::VonNeumann);
+        assert_eq!(rule.to_string(), "B1/S2,4,5/V");
+        assert_eq!(rule, rule.to_string().parse().unwrap());
+        assert!("B3/S".parse::<OuterTotalisticRule>().is_ok());
+        assert!("B0/S1".parse::<OuterTotalisticRule>().is_err());
+        assert!("B27/S1".parse::<OuterTotalisticRule>().is_err());
+        assert!("B7/S1/V".parse::<OuterTotalisticRule>().is_err());
+        assert!("B3".parse::<OuterTotalisticRule>().is_err());
+        assert!("B3/S2/S3".parse::<OuterTotalisticRule>().is_err());
+        assert!("B3-2/S2".parse::<OuterTotalisticRule>().is_err());
+        assert!("B3/S2,".parse::<OuterTotalisticRule>().is_err());
+        assert!("5476".parse::<OuterTotalisticRule>().is_err());
+    }
+
+    #[test]
+    fn test_still_life() {
+        let rule: OuterTotalisticRule = "5766".parse().unwrap();
+        let mut world = World::new(rule, DefaultBuildHasher::new());
+        let mut cells = Vec::new();
+        for &x in &[0, 1] {
+            for &y in &[0, 1] {
+                for &z in &[0, 1] {
+                    cells.push((x, y, z));
+                }
+            }
+        }
+        let block = create_state(&mut world, &cells);
+        let mut state = block.clone();
+        state.step(&mut world, 0);
+        assert_eq!(state, block);
+        state.step(&mut world, 5);
+        assert_eq!(state, block);
+    }
+
+    #[test]
+    fn test_oscillators() {
+        let oscillators: &[(&str, &[(i32, i32, i32)], &[(i32, i32, i32)])] = &[
+            (
+                "5766",
+                &[
+                    (0, 0, 0),
+                    (0, 0, 1),
+                    (0, 1, 0),
+                    (0, 1, 1),
+                    (0, 2, 0),
+                    (0, 2, 1),
+                ],
+                &[
+                    (-1, 1, 0),
+                    (-1, 1, 1),
+                    (0, 1, 0),
+                    (0, 1, 1),
+                    (1, 1, 0),
+                    (1, 1, 1),
+                ],
+            ),
+            (
+                "B4/S",
+                &[(0, 0, 0), (0, 0, 1), (0, 1, 0), (1, 0, 0)],
+                &[(0, 1, 1), (1, 0, 1), (1, 1, 0), (1, 1, 1)],
+            ),
+        ];
+        for &(rule, phase0, phase1) in oscillators {
+            let rule: OuterTotalisticRule = rule.parse().unwrap();
+            let mut world = World::new(rule, DefaultBuildHasher::new());
+            let phase0 = create_state(&mut world, phase0);
+            let phase1 = create_state(&mut world, phase1);
+            assert_ne!(phase0, phase1);
+            let mut state = phase0.clone();
+            state.step(&mut world, 0);
+            assert_eq!(state, phase1, "rule = {}", rule);
+            state.step(&mut world, 0);
+            assert_eq!(state, phase0, "rule = {}", rule);
+            state.step(&mut world, 4);
+            assert_eq!(state, phase0, "rule = {}", rule);
+            state.step(&mut world, 0);
+            state.step(&mut world, 3);
+            assert_eq!(state, phase1, "rule = {}", rule);
+        }
+    }
+
+    #[test]
+    fn test_von_neumann() {
+        let rule: OuterTotalisticRule = "B1/S/V".parse().unwrap();
+        let mut world = World::new(rule, DefaultBuildHasher::new());
+        let mut state = create_state(&mut world, &[(0, 0, 0)]);
+        state.step(&mut world, 0);
+        for x in -2..=2 {
+            for y in -2..=2 {
+                for z in -2..=2 {
+                    let expected = x * x + y * y + z * z == 1;
+                    assert_eq!(get(&state, math::Vec3::new(x, y, z)), expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_generations() {
+        let rule: GenerationsRule<DirectStateEncoding> = "B2/S/C3".parse().unwrap();
+        assert_eq!(rule.rule(), "B2/S".parse().unwrap());
+        assert_eq!(rule.state_count(), 3);
+        assert_eq!(rule.to_string(), "B2/S/C3");
+        let rule: GenerationsRule<DirectStateEncoding> = "c10/s4/b1/v".parse().unwrap();
+        assert_eq!(rule.state_count(), 10);
+        assert_eq!(rule.to_string(), "B1/S4/C10/V");
+        let rule: GenerationsRule<DirectStateEncoding> = "5766".parse().unwrap();
+        assert_eq!(rule.state_count(), 2);
+        assert!("B2/S/C3".parse::<OuterTotalisticRule>().is_err());
+        assert!("B2/S/C1"
+            .parse::<GenerationsRule<DirectStateEncoding>>()
+            .is_err());
+        assert!("B2/S/C257"
+            .parse::<GenerationsRule<DirectStateEncoding>>()
+            .is_err());
+        assert!("B2/S/Cx"
+            .parse::<GenerationsRule<DirectStateEncoding>>()
+            .is_err());
+        let encoding = PaletteStateEncoding::new(&[BlockId::new(1), BlockId::new(2)]);
+        assert!(GenerationsRule::parse("B2/S/C3", encoding.clone()).is_ok());
+        assert!(GenerationsRule::parse("B2/S/C4", encoding).is_err());
+    }
+
+    #[test]
+    fn test_generations_decay() {
+        let rule: GenerationsRule<DirectStateEncoding> = "B/S/C4".parse().unwrap();
+        let mut world = World::new(rule, DefaultBuildHasher::new());
+        let mut state = State::create_empty(&mut world);
+        state.set(&mut world, math::Vec3::new(0, 0, 0), 1);
+        for &expected in &[2, 3, 0] {
+            state.step(&mut world, 0);
+            assert_eq!(get(&state, math::Vec3::new(0, 0, 0)), expected);
+        }
+        assert_eq!(state, State::create_empty(&mut world));
+    }
+
+    #[test]
+    fn test_generations_palette() {
+        let block_ids = [BlockId::new(5), BlockId::new(6), BlockId::new(7)];
+        let encoding = PaletteStateEncoding::new(&block_ids);
+        assert_eq!(encoding.block_id(0), BlockId::default());
+        assert_eq!(encoding.decode(Block::default()), 0);
+        assert_eq!(
+            encoding.decode(Block::new(BlockId::new(9), BlockLighting::SKY)),
+            0
+        );
+        for state in 1..4 {
+            assert_eq!(encoding.block_id(state), block_ids[state as usize - 1]);
+            assert_eq!(encoding.decode(encoding.encode(state)), state);
+        }
+        let rule = GenerationsRule::parse("B1/S1/C4/V", encoding).unwrap();
+        let mut world = World::new(rule, DefaultBuildHasher::new());
+        let mut state = State::create_empty(&mut world);
+        state.set(
+            &mut world,
+            math::Vec3::new(0, 0, 0),
+            Block::new(block_ids[0], BlockLighting::SKY),
+        );
+        state.step(&mut world, 0);
+        for x in -2..=2 {
+            for y in -2..=2 {
+                for z in -2..=2 {
+                    let expected = match x * x + y * y + z * z {
+                        0 => block_ids[1],
+                        1 => block_ids[0],
+                        _ => BlockId::default(),
+                    };
+                    assert_eq!(get(&state, math::Vec3::new(x, y, z)).id(), expected);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,698 @@
+// This file is part of Hashlife3d.
+//
+// Hashlife3d is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Hashlife3d is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with Hashlife3d.  If not, see <https://www.gnu.org/licenses/>
+
+// save file layout, all integers are LEB128 varints:
+// magic, version, flags (1 byte), then the body, deflated if FLAG_DEFLATE is set:
+// root level, then the nodes:
+// palette length, then each block id string as its length and UTF-8 bytes,
+// node count, then each node as a tag byte followed by either
+// 8 leaf blocks (palette index, state bits) or
+// 8 children (distance back from this node's index minus 1)
+// delta files use DELTA_MAGIC, have the base state's fingerprint as 8 little
+// endian bytes after the flags, and their body is the base state's node count,
+// the nodes, numbered starting after the base state's nodes, then the root index
+use block::{Block, BlockId, BlockLighting, LightLevel};
+use deflate::deflate_bytes;
+use inflate::inflate_bytes;
+use registry::Registry;
+use std::cmp;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use world3d::{
+    BlockType, SerializedDelta, SerializedNode, SerializedNodeIndex, SerializedState,
+    MAX_SUPPORTED_LEVEL,
+};
+
+pub const MAGIC: &[u8; 8] = b"HL3DSAVE";
+pub const DELTA_MAGIC: &[u8; 8] = b"HL3DDLTA";
+pub const VERSION: u32 = 1;
+const FLAG_DEFLATE: u8 = 1;
+const KNOWN_FLAGS: u8 = FLAG_DEFLATE;
+const NODE_TAG_LEAF: u8 = 0;
+const NODE_TAG_NONLEAF: u8 = 1;
+const MAX_ID_STRING_LENGTH: u64 = 0x1000;
+
+// blocks are saved as the id string of their block id and the rest of their state
+pub trait SaveBlock: BlockType {
+    fn block_id(self) -> BlockId;
+    fn state_bits(self) -> u32;
+    // None if state_bits isn't valid
+    fn from_saved(block_id: BlockId, state_bits: u32) -> Option<Self>;
+}
+
+impl SaveBlock for Block {
+    fn block_id(self) -> BlockId {
+        self.id()
+    }
+    fn state_bits(self) -> u32 {
+        self.artificial_diffuse_light_level().get()
+            | (self.natural_diffuse_light_level().get() << 4)
+            | (self.natural_direct_light_level().get() << 8)
+    }
+    fn from_saved(block_id: BlockId, state_bits: u32) -> Option<Self> {
+        if state_bits >> 12 != 0 {
+            return None;
+        }
+        Some(Block::new(
+            block_id,
+            BlockLighting::new(
+                LightLevel::new(state_bits & 0xF),
+                LightLevel::new((state_bits >> 4) & 0xF),
+                LightLevel::new((state_bits >> 8) & 0xF),
+            ),
+        ))
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum Compression {
+    None,
+    Deflate,
+}
+
+// what to do with blocks whose id strings aren't in the registry
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum MissingBlocks {
+    Reject,
+    ReplaceWith(BlockId),
+}
+
+#[derive(Clone, Debug)]
+pub struct LoadedState<Block: BlockType> {
+    pub state: SerializedState<Block>,
+    // id strings that were replaced, in the order they are in the save file
+    pub missing_block_names: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct LoadedDelta<Block: BlockType> {
+    pub delta: SerializedDelta<Block>,
+    pub missing_block_names: Vec<String>,
+}
+
+fn invalid_data<T>(message: &'static str) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+fn write_varint<W: Write>(writer: &mut W, mut value: u64) -> io::Result<()> {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            return writer.write_all(&[byte]);
+        }
+        writer.write_all(&[byte | 0x80])?;
+    }
+}
+
+fn read_byte<R: Read>(reader: &mut R) -> io::Result<u8> {
+    let mut byte = [0];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn read_varint<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut retval = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = read_byte(reader)?;
+        let bits = (byte & 0x7F) as u64;
+        if bits << shift >> shift != bits {
+            return invalid_data("varint too big");
+        }
+        retval |= bits << shift;
+        if byte & 0x80 == 0 {
+            return Ok(retval);
+        }
+    }
+    invalid_data("varint too long")
+}
+
+fn read_varint_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let value = read_varint(reader)?;
+    if value > u32::max_value() as u64 {
+        invalid_data("varint too big")
+    } else {
+        Ok(value as u32)
+    }
+}
+
+// children are written as the distance back from the node's index, since they
+// are usually just before their parent
+fn write_nodes<Block: SaveBlock, W: Write>(
+    writer: &mut W,
+    nodes: &[SerializedNode<Block>],
+    first_index: u64,
+    registry: &Registry,
+) -> io::Result<()> {
+    let mut palette = Vec::new();
+    let mut palette_indexes = HashMap::new();
+    for node in nodes {
+        if let SerializedNode::Leaf(blocks) = node {
+            for block in blocks.iter().flat_map(|v| v.iter()).flat_map(|v| v.iter()) {
+                let block_id = block.block_id();
+                let palette_len = palette.len();
+                palette_indexes.entry(block_id).or_insert_with(|| {
+                    palette.push(block_id);
+                    palette_len as u64
+                });
+            }
+        }
+    }
+    write_varint(writer, palette.len() as u64)?;
+    for block_id in palette {
+        let id_string = registry.get_block(block_id).id_string;
+        write_varint(writer, id_string.len() as u64)?;
+        writer.write_all(id_string.as_bytes())?;
+    }
+    write_varint(writer, nodes.len() as u64)?;
+    for (index, node) in nodes.iter().enumerate() {
+        let index = first_index + index as u64;
+        match node {
+            SerializedNode::Leaf(blocks) => {
+                writer.write_all(&[NODE_TAG_LEAF])?;
+                for block in blocks.iter().flat_map(|v| v.iter()).flat_map(|v| v.iter()) {
+                    write_varint(writer, palette_indexes[&block.block_id()])?;
+                    write_varint(writer, block.state_bits() as u64)?;
+                }
+            }
+            SerializedNode::Nonleaf(children) => {
+                writer.write_all(&[NODE_TAG_NONLEAF])?;
+                for child in children.iter().flat_map(|v| v.iter()).flat_map(|v| v.iter()) {
+                    write_varint(writer, index - 1 - child.0 as u64)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_nodes<Block: SaveBlock, R: Read>(
+    reader: &mut R,
+    first_index: u64,
+    registry: &Registry,
+    missing_blocks: MissingBlocks,
+    missing_block_names: &mut Vec<String>,
+) -> io::Result<Vec<SerializedNode<Block>>> {
+    let palette_len = read_varint(reader)?;
+    // the ids in the save file are from the registry it was saved with, so
+    // remap them by id string
+    let mut palette = Vec::new();
+    for _ in 0..palette_len {
+        let id_string_len = read_varint(reader)?;
+        if id_string_len > MAX_ID_STRING_LENGTH {
+            return invalid_data("block id string too long");
+        }
+        let mut id_string = vec![0; id_string_len as usize];
+        reader.read_exact(&mut id_string)?;
+        let id_string = match String::from_utf8(id_string) {
+            Ok(id_string) => id_string,
+            Err(_) => return invalid_data("block id string is not valid UTF-8"),
+        };
+        match (registry.find_block_by_name(&id_string), missing_blocks) {
+            (Some(block_id), _) => palette.push(block_id),
+            (None, MissingBlocks::Reject) => return invalid_data("unknown block id string"),
+            (None, MissingBlocks::ReplaceWith(placeholder)) => {
+                palette.push(placeholder);
+                missing_block_names.push(id_string);
+            }
+        }
+    }
+    let node_count = read_varint(reader)?;
+    let end_index = match first_index.checked_add(node_count) {
+        Some(end_index) if end_index <= SerializedNodeIndex::MAX.0 as u64 => end_index,
+        _ => return invalid_data("too many nodes"),
+    };
+    // don't trust the count for preallocating
+    let mut nodes = Vec::with_capacity(cmp::min(node_count, 0x10000) as usize);
+    for index in first_index..end_index {
+        match read_byte(reader)? {
+            NODE_TAG_LEAF => {
+                let mut blocks = [[[Block::default(); 2]; 2]; 2];
+                for block in blocks
+                    .iter_mut()
+                    .flat_map(|v| v.iter_mut())
+                    .flat_map(|v| v.iter_mut())
+                {
+                    let palette_index = read_varint(reader)?;
+                    let state_bits = read_varint_u32(reader)?;
+                    if palette_index >= palette.len() as u64 {
+                        return invalid_data("palette index out of range");
+                    }
+                    let block_id = palette[palette_index as usize];
+                    *block = match Block::from_saved(block_id, state_bits) {
+                        Some(block) => block,
+                        None => return invalid_data("invalid block state"),
+                    };
+                }
+                nodes.push(SerializedNode::Leaf(blocks));
+            }
+            NODE_TAG_NONLEAF => {
+                let mut children = [[[SerializedNodeIndex(0); 2]; 2]; 2];
+                for child in children
+                    .iter_mut()
+                    .flat_map(|v| v.iter_mut())
+                    .flat_map(|v| v.iter_mut())
+                {
+                    let distance = read_varint(reader)?;
+                    if distance >= index {
+                        return invalid_data("node index out of range");
+                    }
+                    *child = SerializedNodeIndex((index - 1 - distance) as u32);
+                }
+                nodes.push(SerializedNode::Nonleaf(children));
+            }
+            _ => return invalid_data("invalid node tag"),
+        }
+    }
+    Ok(nodes)
+}
+
+// header is the uncompressed part after the flags
+fn write_file<W: Write>(
+    mut writer: W,
+    magic: &[u8; 8],
+    header: &[u8],
+    body: &[u8],
+    compression: Compression,
+) -> io::Result<()> {
+    writer.write_all(magic)?;
+    write_varint(&mut writer, VERSION as u64)?;
+    match compression {
+        Compression::None => {
+            writer.write_all(&[0])?;
+            writer.write_all(header)?;
+            writer.write_all(body)?;
+        }
+        Compression::Deflate => {
+            writer.write_all(&[FLAG_DEFLATE])?;
+            writer.write_all(header)?;
+            writer.write_all(&deflate_bytes(body))?;
+        }
+    }
+    writer.flush()
+}
+
+// checks the header, fills in the rest of it and returns the decompressed body
+fn read_file<R: Read>(mut reader: R, magic: &[u8; 8], header: &mut [u8]) -> io::Result<Vec<u8>> {
+    let mut read_magic = [0; 8];
+    reader.read_exact(&mut read_magic)?;
+    if read_magic != *magic {
+        return invalid_data("not a save file of the right kind");
+    }
+    if read_varint(&mut reader)? != VERSION as u64 {
+        return invalid_data("unsupported save file version");
+    }
+    let flags = read_byte(&mut reader)?;
+    if flags & !KNOWN_FLAGS != 0 {
+        return invalid_data("unknown save file flags");
+    }
+    reader.read_exact(header)?;
+    let mut body = Vec::new();
+    reader.read_to_end(&mut body)?;
+    if flags & FLAG_DEFLATE != 0 {
+        body = match inflate_bytes(&body) {
+            Ok(body) => body,
+            Err(_) => return invalid_data("invalid compressed data"),
+        };
+    }
+    Ok(body)
+}
+
+fn check_at_end(body_reader: &[u8]) -> io::Result<()> {
+    if body_reader.is_empty() {
+        Ok(())
+    } else {
+        invalid_data("trailing data after save file")
+    }
+}
+
+pub fn write_state<Block: SaveBlock, W: Write>(
+    writer: W,
+    state: &SerializedState<Block>,
+    registry: &Registry,
+    compression: Compression,
+) -> io::Result<()> {
+    let mut body = Vec::new();
+    write_varint(&mut body, state.level() as u64)?;
+    write_nodes(&mut body, state.nodes(), 0, registry)?;
+    write_file(writer, MAGIC, &[], &body, compression)
+}
+
+// fails if any blocks aren't in the registry
+pub fn read_state<Block: SaveBlock, R: Read>(
+    reader: R,
+    registry: &Registry,
+) -> io::Result<SerializedState<Block>> {
+    Ok(load_state(reader, registry, MissingBlocks::Reject)?.state)
+}
+
+pub fn load_state<Block: SaveBlock, R: Read>(
+    reader: R,
+    registry: &Registry,
+    missing_blocks: MissingBlocks,
+) -> io::Result<LoadedState<Block>> {
+    let body = read_file(reader, MAGIC, &mut [])?;
+    let mut body_reader = &body[..];
+    let level = read_varint(&mut body_reader)?;
+    if level > MAX_SUPPORTED_LEVEL as u64 {
+        return invalid_data("world too big");
+    }
+    let mut missing_block_names = Vec::new();
+    let nodes = read_nodes(
+        &mut body_reader,
+        0,
+        registry,
+        missing_blocks,
+        &mut missing_block_names,
+    )?;
+    check_at_end(body_reader)?;
+    let state = match SerializedState::from_nodes(nodes) {
+        Ok(state) => state,
+        Err(message) => return invalid_data(message),
+    };
+    if state.level() as u64 != level {
+        return invalid_data("root level doesn't match header");
+    }
+    Ok(LoadedState {
+        state: state,
+        missing_block_names: missing_block_names,
+    })
+}
+
+pub fn write_delta<Block: SaveBlock, W: Write>(
+    writer: W,
+    delta: &SerializedDelta<Block>,
+    registry: &Registry,
+    compression: Compression,
+) -> io::Result<()> {
+    let mut body = Vec::new();
+    let base_node_count = delta.base_node_count() as u64;
+    write_varint(&mut body, base_node_count)?;
+    write_nodes(&mut body, delta.nodes(), base_node_count, registry)?;
+    write_varint(&mut body, delta.root().0 as u64)?;
+    let fingerprint = delta.base_fingerprint();
+    let mut header = [0; 8];
+    for (i, byte) in header.iter_mut().enumerate() {
+        *byte = (fingerprint >> (i * 8)) as u8;
+    }
+    write_file(writer, DELTA_MAGIC, &header, &body, compression)
+}
+
+// the result still needs to be checked against the base state by State::from_delta
+pub fn load_delta<Block: SaveBlock, R: Read>(
+    reader: R,
+    registry: &Registry,
+    missing_blocks: MissingBlocks,
+) -> io::Result<LoadedDelta<Block>> {
+    let mut header = [0u8; 8];
+    let body = read_file(reader, DELTA_MAGIC, &mut header)?;
+    let base_fingerprint = header
+        .iter()
+        .enumerate()
+        .fold(0, |fingerprint, (i, &byte)| fingerprint | (byte as u64) << (i * 8));
+    let mut body_reader = &body[..];
+    let base_node_count = read_varint_u32(&mut body_reader)?;
+    let mut missing_block_names = Vec::new();
+    let nodes = read_nodes(
+        &mut body_reader,
+        base_node_count as u64,
+        registry,
+        missing_blocks,
+        &mut missing_block_names,
+    )?;
+    let root = SerializedNodeIndex(read_varint_u32(&mut body_reader)?);
+    check_at_end(body_reader)?;
+    match SerializedDelta::from_parts(base_node_count, base_fingerprint, nodes, root) {
+        Ok(delta) => Ok(LoadedDelta {
+            delta: delta,
+            missing_block_names: missing_block_names,
+        }),
+        Err(message) => invalid_data(message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use block;
+    use hashtable::DefaultBuildHasher;
+    use math;
+    use registry::RegistryBuilder;
+    use world3d::{State, World};
+
+    fn create_registry() -> Registry {
+        let mut registry_builder = RegistryBuilder::new();
+        block::register_blocks(&mut registry_builder);
+        registry_builder.finish_startup()
+    }
+
+    fn create_test_state(registry: &Registry) -> SerializedState<Block> {
+        let stone = registry.find_block_by_name("voxels:stone").unwrap();
+        let air = registry.find_block_by_name("voxels:air").unwrap();
+        let mut world = World::new(
+            |neighborhood: &[[[Block; 3]; 3]; 3]| neighborhood[1][1][1],
+            DefaultBuildHasher::new(),
+        );
+        let mut state = State::create_empty(&mut world);
+        state.set_cube_pow2(&mut world, math::Vec3::splat(-32), 32, |position, _| {
+            let lighting = BlockLighting::new(
+                LightLevel::new(position.x % 16),
+                LightLevel::new(position.y % 16),
+                LightLevel::MAX,
+            );
+            if (position.x ^ position.y ^ position.z) % 5 == 0 {
+                Block::new(stone, lighting)
+            } else if position.z < 8 {
+                Block::new(air, lighting)
+            } else {
+                Block::default()
+            }
+        });
+        SerializedState::from(&state)
+    }
+
+    fn write_to_vec(
+        state: &SerializedState<Block>,
+        registry: &Registry,
+        compression: Compression,
+    ) -> Vec<u8> {
+        let mut data = Vec::new();
+        write_state(&mut data, state, registry, compression).unwrap();
+        data
+    }
+
+    fn assert_invalid(data: &[u8], registry: &Registry) {
+        match read_state::<Block, _>(data, registry) {
+            Ok(_) => panic!("corrupt save file was accepted"),
+            Err(error) => assert!(
+                error.kind() == io::ErrorKind::InvalidData
+                    || error.kind() == io::ErrorKind::UnexpectedEof,
+                "{:?}",
+                error
+            ),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let registry = create_registry();
+        let state = create_test_state(&registry);
+        let uncompressed = write_to_vec(&state, &registry, Compression::None);
+        let compressed = write_to_vec(&state, &registry, Compression::Deflate);
+        assert!(compressed.len() < uncompressed.len());
+        assert!(read_state::<Block, _>(&uncompressed[..], &registry).unwrap() == state);
+        assert!(read_state::<Block, _>(&compressed[..], &registry).unwrap() == state);
+        let mut varint = Vec::new();
+        let values = [0, 1, 0x7F, 0x80, 0x3FFF, 0x4000, u32::max_value() as u64, u64::max_value()];
+        for &value in &values {
+            varint.clear();
+            write_varint(&mut varint, value).unwrap();
+            assert_eq!(read_varint(&mut &varint[..]).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn test_corrupt_input() {
+        let registry = create_registry();
+        let state = create_test_state(&registry);
+        let data = write_to_vec(&state, &registry, Compression::None);
+        let header_len = MAGIC.len() + 2;
+        // bad magic
+        let mut corrupt = data.clone();
+        corrupt[0] ^= 1;
+        assert_invalid(&corrupt, &registry);
+        // unsupported version
+        let mut corrupt = data.clone();
+        corrupt[MAGIC.len()] = VERSION as u8 + 1;
+        assert_invalid(&corrupt, &registry);
+        // unknown flags
+        let mut corrupt = data.clone();
+        corrupt[MAGIC.len() + 1] = 0x80;
+        assert_invalid(&corrupt, &registry);
+        // truncated and trailing data
+        for len in (0..data.len()).step_by(13).chain(Some(data.len() - 1)) {
+            assert_invalid(&data[..len], &registry);
+        }
+        let mut corrupt = data.clone();
+        corrupt.push(0);
+        assert_invalid(&corrupt, &registry);
+        // bad compressed data
+        let mut corrupt = write_to_vec(&state, &registry, Compression::Deflate);
+        corrupt.truncate(header_len + 4);
+        assert_invalid(&corrupt, &registry);
+        // unknown block
+        let mut corrupt = Vec::new();
+        corrupt.extend_from_slice(MAGIC);
+        corrupt.extend_from_slice(&[VERSION as u8, 0, 0, 1, 4]);
+        corrupt.extend_from_slice(b"nope");
+        assert_invalid(&corrupt, &registry);
+        let build_file = |nodes: &[&[u8]]| {
+            let mut data = Vec::new();
+            data.extend_from_slice(MAGIC);
+            // level 1, a palette with just air
+            data.extend_from_slice(&[VERSION as u8, 0, 1, 1, 10]);
+            data.extend_from_slice(b"voxels:air");
+            data.push(nodes.len() as u8);
+            for node in nodes {
+                data.extend_from_slice(node);
+            }
+            data
+        };
+        let leaf: &[u8] = &[NODE_TAG_LEAF, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let nonleaf: &[u8] = &[NODE_TAG_NONLEAF, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert!(read_state::<Block, _>(&build_file(&[leaf, nonleaf])[..], &registry).is_ok());
+        // the root level doesn't match the header
+        assert_invalid(&build_file(&[leaf]), &registry);
+        // child after its parent
+        assert_invalid(&build_file(&[nonleaf, leaf]), &registry);
+        // unused node
+        assert_invalid(&build_file(&[leaf, leaf, nonleaf]), &registry);
+        // invalid node tag
+        assert_invalid(&build_file(&[leaf, &[2]]), &registry);
+        // palette index out of range
+        let mut bad_leaf = leaf.to_vec();
+        bad_leaf[1] = 1;
+        assert_invalid(&build_file(&[&bad_leaf, nonleaf]), &registry);
+        // invalid block state
+        let mut bad_leaf = leaf.to_vec();
+        bad_leaf[2] = 0x80;
+        bad_leaf.insert(3, 0x20);
+        assert_invalid(&build_file(&[&bad_leaf, nonleaf]), &registry);
+        // children at different levels
+        let mut bad_nonleaf = nonleaf.to_vec();
+        bad_nonleaf[1] = 1;
+        assert_invalid(&build_file(&[leaf, leaf, nonleaf, &bad_nonleaf]), &registry);
+    }
+
+    #[test]
+    fn test_palette_remapping() {
+        let registry = create_registry();
+        let stone = registry.find_block_by_name("voxels:stone").unwrap();
+        let air = registry.find_block_by_name("voxels:air").unwrap();
+        let mut data = Vec::new();
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&[VERSION as u8, 0, 1, 3]);
+        // saved with a different registration order and a block that is gone
+        for id_string in &["mod:gone", "voxels:stone", "voxels:air"] {
+            data.push(id_string.len() as u8);
+            data.extend_from_slice(id_string.as_bytes());
+        }
+        data.extend_from_slice(&[2, NODE_TAG_LEAF]);
+        for palette_index in 0..8 {
+            data.extend_from_slice(&[palette_index % 3, palette_index]);
+        }
+        data.extend_from_slice(&[NODE_TAG_NONLEAF, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_invalid(&data, &registry);
+        let loaded_state: LoadedState<Block> =
+            load_state(&data[..], &registry, MissingBlocks::ReplaceWith(stone)).unwrap();
+        assert_eq!(loaded_state.missing_block_names, vec!["mod:gone".to_string()]);
+        let expected_ids = [stone, stone, air];
+        match loaded_state.state.nodes()[0] {
+            SerializedNode::Leaf(blocks) => for x in 0..2 {
+                for y in 0..2 {
+                    for z in 0..2 {
+                        let index = x * 4 + y * 2 + z;
+                        let block = blocks[x][y][z];
+                        assert_eq!(block.id(), expected_ids[index % 3]);
+                        assert_eq!(block.state_bits(), index as u32);
+                    }
+                }
+            },
+            SerializedNode::Nonleaf(_) => unreachable!(),
+        }
+        let state = create_test_state(&registry);
+        let data = write_to_vec(&state, &registry, Compression::Deflate);
+        let loaded_state =
+            load_state(&data[..], &registry, MissingBlocks::ReplaceWith(air)).unwrap();
+        assert!(loaded_state.missing_block_names.is_empty());
+        assert!(loaded_state.state == state);
+    }
+
+    #[test]
+    fn test_delta() {
+        let registry = create_registry();
+        let stone = registry.find_block_by_name("voxels:stone").unwrap();
+        let mut world = World::new(
+            |neighborhood: &[[[Block; 3]; 3]; 3]| neighborhood[1][1][1],
+            DefaultBuildHasher::new(),
+        );
+        let base = State::from(&create_test_state(&registry), &mut world).unwrap();
+        let mut state = base.clone();
+        state.set(
+            &mut world,
+            math::Vec3::new(-3, 4, 5),
+            Block::new(stone, BlockLighting::default()),
+        );
+        let delta = SerializedDelta::new(&base, &state);
+        for &compression in &[Compression::None, Compression::Deflate] {
+            let mut data = Vec::new();
+            write_delta(&mut data, &delta, &registry, compression).unwrap();
+            let loaded_delta: LoadedDelta<Block> =
+                load_delta(&data[..], &registry, MissingBlocks::Reject).unwrap();
+            assert!(loaded_delta.delta == delta);
+            assert!(State::from_delta(&base, &loaded_delta.delta, &mut world).unwrap() == state);
+            // not a full save file
+            assert_invalid(&data, &registry);
+        }
+        // root index out of range
+        let mut corrupt = Vec::new();
+        write_delta(&mut corrupt, &delta, &registry, Compression::None).unwrap();
+        let mut root = Vec::new();
+        write_varint(&mut root, delta.root().0 as u64).unwrap();
+        let len = corrupt.len() - root.len();
+        corrupt.truncate(len);
+        let node_count = delta.base_node_count() as u64 + delta.nodes().len() as u64;
+        write_varint(&mut corrupt, node_count).unwrap();
+        assert!(load_delta::<Block, _>(&corrupt[..], &registry, MissingBlocks::Reject).is_err());
+        // a node count that overflows when added to the base node count
+        let mut body = Vec::new();
+        write_varint(&mut body, delta.base_node_count() as u64).unwrap();
+        write_varint(&mut body, 0).unwrap();
+        write_varint(&mut body, u64::max_value()).unwrap();
+        let mut corrupt = Vec::new();
+        write_file(&mut corrupt, DELTA_MAGIC, &[0; 8], &body, Compression::None).unwrap();
+        let error = load_delta::<Block, _>(&corrupt[..], &registry, MissingBlocks::Reject)
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        // the fingerprint is checked against the base
+        let mut corrupt = Vec::new();
+        write_delta(&mut corrupt, &delta, &registry, Compression::Deflate).unwrap();
+        corrupt[MAGIC.len() + 2] ^= 1;
+        let loaded_delta: LoadedDelta<Block> =
+            load_delta(&corrupt[..], &registry, MissingBlocks::Reject).unwrap();
+        assert!(State::from_delta(&base, &loaded_delta.delta, &mut world).is_err());
+    }
+}
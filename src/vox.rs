@@ -0,0 +1,526 @@
+// This file is part of Hashlife3d.
+//
+// Hashlife3d is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Lesser General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// Hashlife3d is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License
+// along with Hashlife3d.  If not, see <https://www.gnu.org/licenses/>
+
+// MagicaVoxel .vox files, all integers are little endian u32:
+// "VOX ", version, then a MAIN chunk whose children are SIZE, XYZI and RGBA.
+// chunks are an id, content size, children size, content, then children.
+// unknown chunks like the scene graph and materials are skipped, and only files
+// with a single model are supported.
+//
+// .vox files are z up, so the .vox position (x, y, z) is at
+// (x, z, size.y - 1 - y) in the world, which keeps the handedness.
+use block::{Block, BlockId, BlockLighting};
+use math;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::io::{self, Read, Write};
+use world3d::{Region, State, StepFn, World};
+
+pub const MAGIC: &[u8; 4] = b"VOX ";
+pub const VERSION: u32 = 150;
+pub const MAX_MODEL_SIZE: u32 = 256;
+const PALETTE_SIZE: usize = 256;
+
+// maps .vox color indexes to blocks, color index 0 is always empty
+#[derive(Clone, Debug, Default)]
+pub struct VoxMapping {
+    blocks: HashMap<u8, BlockId>,
+    color_indexes: HashMap<BlockId, u8>,
+}
+
+impl VoxMapping {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    // when a block has several color indexes, the first one is used for writing
+    pub fn insert(&mut self, color_index: u8, block_id: BlockId) -> Result<(), &'static str> {
+        if color_index == 0 {
+            return Err("color index 0 is empty space");
+        }
+        if self.blocks.contains_key(&color_index) {
+            return Err("duplicate color index in mapping");
+        }
+        self.blocks.insert(color_index, block_id);
+        self.color_indexes.entry(block_id).or_insert(color_index);
+        Ok(())
+    }
+    pub fn block_id(&self, color_index: u8) -> Option<BlockId> {
+        self.blocks.get(&color_index).cloned()
+    }
+    pub fn color_index(&self, block_id: BlockId) -> Option<u8> {
+        self.color_indexes.get(&block_id).cloned()
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VoxModel {
+    // in .vox coordinates
+    pub size: math::Vec3<u32>,
+    pub voxels: Vec<(math::Vec3<u8>, u8)>,
+    // RGBA colors indexed by color index, None uses MagicaVoxel's default palette
+    pub palette: Option<Vec<[u8; 4]>>,
+}
+
+fn invalid_data<T>(message: &'static str) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+fn invalid_input<T>(message: &'static str) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidInput, message))
+}
+
+fn write_u32<W: Write>(writer: &mut W, value: u32) -> io::Result<()> {
+    writer.write_all(&[
+        value as u8,
+        (value >> 8) as u8,
+        (value >> 16) as u8,
+        (value >> 24) as u8,
+    ])
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(
+        bytes[0] as u32
+            | (bytes[1] as u32) << 8
+            | (bytes[2] as u32) << 16
+            | (bytes[3] as u32) << 24,
+    )
+}
+
+fn write_chunk<W: Write>(writer: &mut W, id: &[u8; 4], content: &[u8]) -> io::Result<()> {
+    writer.write_all(id)?;
+    write_u32(writer, content.len() as u32)?;
+    write_u32(writer, 0)?;
+    writer.write_all(content)
+}
+
+// don't trust the size for preallocating
+fn read_exact_vec<R: Read>(reader: &mut R, size: u32) -> io::Result<Vec<u8>> {
+    let mut retval = Vec::new();
+    reader.take(size as u64).read_to_end(&mut retval)?;
+    if retval.len() != size as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "unexpected end of .vox file",
+        ));
+    }
+    Ok(retval)
+}
+
+pub fn write_vox<W: Write>(mut writer: W, model: &VoxModel) -> io::Result<()> {
+    if model.size.x > MAX_MODEL_SIZE
+        || model.size.y > MAX_MODEL_SIZE
+        || model.size.z > MAX_MODEL_SIZE
+    {
+        return invalid_input("model too big for a .vox file");
+    }
+    if model
+        .palette
+        .as_ref()
+        .map_or(false, |palette| palette.len() != PALETTE_SIZE)
+    {
+        return invalid_input("palette doesn't have 256 colors");
+    }
+    let mut children = Vec::new();
+    let mut size = Vec::new();
+    write_u32(&mut size, model.size.x)?;
+    write_u32(&mut size, model.size.y)?;
+    write_u32(&mut size, model.size.z)?;
+    write_chunk(&mut children, b"SIZE", &size)?;
+    let mut voxels = Vec::new();
+    write_u32(&mut voxels, model.voxels.len() as u32)?;
+    for &(position, color_index) in &model.voxels {
+        voxels.extend_from_slice(&[position.x, position.y, position.z, color_index]);
+    }
+    write_chunk(&mut children, b"XYZI", &voxels)?;
+    if let Some(palette) = &model.palette {
+        // the RGBA chunk starts at color index 1
+        let mut colors = Vec::new();
+        for color in palette[1..].iter().chain(Some(&[0; 4])) {
+            colors.extend_from_slice(color);
+        }
+        write_chunk(&mut children, b"RGBA", &colors)?;
+    }
+    writer.write_all(MAGIC)?;
+    write_u32(&mut writer, VERSION)?;
+    writer.write_all(b"MAIN")?;
+    write_u32(&mut writer, 0)?;
+    write_u32(&mut writer, children.len() as u32)?;
+    writer.write_all(&children)?;
+    writer.flush()
+}
+
+pub fn read_vox<R: Read>(mut reader: R) -> io::Result<VoxModel> {
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if magic != *MAGIC {
+        return invalid_data("not a .vox file");
+    }
+    read_u32(&mut reader)?;
+    let mut id = [0; 4];
+    reader.read_exact(&mut id)?;
+    if id != *b"MAIN" {
+        return invalid_data("missing MAIN chunk");
+    }
+    let content_size = read_u32(&mut reader)?;
+    let children_size = read_u32(&mut reader)?;
+    read_exact_vec(&mut reader, content_size)?;
+    let children = read_exact_vec(&mut reader, children_size)?;
+    let mut children = &children[..];
+    let mut size = None;
+    let mut voxels = None;
+    let mut palette = None;
+    while !children.is_empty() {
+        children.read_exact(&mut id)?;
+        let content_size = read_u32(&mut children)?;
+        let children_size = read_u32(&mut children)?;
+        let content = read_exact_vec(&mut children, content_size)?;
+        read_exact_vec(&mut children, children_size)?;
+        let mut content = &content[..];
+        match &id {
+            b"PACK" => {
+                if read_u32(&mut content)? != 1 {
+                    return invalid_data("multiple models are not supported");
+                }
+            }
+            b"SIZE" => {
+                if size.is_some() {
+                    return invalid_data("multiple models are not supported");
+                }
+                let x = read_u32(&mut content)?;
+                let y = read_u32(&mut content)?;
+                let z = read_u32(&mut content)?;
+                if x > MAX_MODEL_SIZE || y > MAX_MODEL_SIZE || z > MAX_MODEL_SIZE {
+                    return invalid_data("model too big");
+                }
+                size = Some(math::Vec3::new(x, y, z));
+            }
+            b"XYZI" => {
+                let size = match (size, &voxels) {
+                    (Some(size), None) => size,
+                    _ => return invalid_data("XYZI chunk without a SIZE chunk"),
+                };
+                let voxel_count = read_u32(&mut content)?;
+                if voxel_count as u64 * 4 > content.len() as u64 {
+                    return invalid_data("XYZI chunk too short");
+                }
+                let mut model_voxels = Vec::with_capacity(voxel_count as usize);
+                for _ in 0..voxel_count {
+                    let mut voxel = [0; 4];
+                    content.read_exact(&mut voxel)?;
+                    if voxel[0] as u32 >= size.x
+                        || voxel[1] as u32 >= size.y
+                        || voxel[2] as u32 >= size.z
+                    {
+                        return invalid_data("voxel outside of model");
+                    }
+                    if voxel[3] == 0 {
+                        return invalid_data("voxel with empty color index");
+                    }
+                    model_voxels.push((math::Vec3::new(voxel[0], voxel[1], voxel[2]), voxel[3]));
+                }
+                voxels = Some(model_voxels);
+            }
+            b"RGBA" => {
+                let mut colors = vec![[0; 4]; PALETTE_SIZE];
+                for color in &mut colors[1..] {
+                    content.read_exact(color)?;
+                }
+                palette = Some(colors);
+            }
+            _ => {}
+        }
+    }
+    match (size, voxels) {
+        (Some(size), Some(voxels)) => Ok(VoxModel {
+            size: size,
+            voxels: voxels,
+            palette: palette,
+        }),
+        _ => invalid_data("missing model"),
+    }
+}
+
+impl VoxModel {
+    // blocks that aren't in the mapping, like air, are left out
+    pub fn from_state<H: BuildHasher>(
+        state: &State<Block, H>,
+        region: Region<i32>,
+        mapping: &VoxMapping,
+    ) -> Result<Self, &'static str> {
+        let world_size = math::Vec3::new(
+            region.max.x.wrapping_sub(region.min.x) as u32,
+            region.max.y.wrapping_sub(region.min.y) as u32,
+            region.max.z.wrapping_sub(region.min.z) as u32,
+        );
+        if world_size.x > MAX_MODEL_SIZE
+            || world_size.y > MAX_MODEL_SIZE
+            || world_size.z > MAX_MODEL_SIZE
+        {
+            return Err("region too big for a .vox model");
+        }
+        let mut voxels = Vec::new();
+        for (position, block) in state.iter_non_default(region) {
+            if let Some(color_index) = mapping.color_index(block.id()) {
+                let x = position.x.wrapping_sub(region.min.x) as u32;
+                let y = position.y.wrapping_sub(region.min.y) as u32;
+                let z = position.z.wrapping_sub(region.min.z) as u32;
+                voxels.push((
+                    math::Vec3::new(x as u8, (world_size.z - 1 - z) as u8, y as u8),
+                    color_index,
+                ));
+            }
+        }
+        Ok(Self {
+            size: math::Vec3::new(world_size.x, world_size.z, world_size.y),
+            voxels: voxels,
+            palette: None,
+        })
+    }
+    // the region the model covers when its minimum corner is at position
+    pub fn region(&self, position: math::Vec3<i32>) -> Region<i32> {
+        Region::new(
+            position,
+            math::Vec3::new(
+                position.x.wrapping_add(self.size.x as i32),
+                position.y.wrapping_add(self.size.z as i32),
+                position.z.wrapping_add(self.size.y as i32),
+            ),
+        )
+    }
+    // empty voxels leave the blocks already in the state alone
+    pub fn write_to_state<Step: StepFn<Block>, H: BuildHasher>(
+        &self,
+        state: &mut State<Block, H>,
+        world: &mut World<Block, Step, H>,
+        position: math::Vec3<i32>,
+        mapping: &VoxMapping,
+    ) -> Result<(), &'static str> {
+        let mut blocks = Vec::with_capacity(self.voxels.len());
+        for &(voxel_position, color_index) in &self.voxels {
+            let block_id = match mapping.block_id(color_index) {
+                Some(block_id) => block_id,
+                None => return Err("color index not in the mapping"),
+            };
+            let offset = math::Vec3::new(
+                voxel_position.x as i32,
+                voxel_position.z as i32,
+                self.size.y as i32 - 1 - voxel_position.y as i32,
+            );
+            let block_position = match (
+                position.x.checked_add(offset.x),
+                position.y.checked_add(offset.y),
+                position.z.checked_add(offset.z),
+            ) {
+                (Some(x), Some(y), Some(z)) => math::Vec3::new(x, y, z),
+                _ => return Err("model doesn't fit in the world"),
+            };
+            if !world.contains(block_position) {
+                return Err("model doesn't fit in the world");
+            }
+            blocks.push((block_position, Block::new(block_id, BlockLighting::SKY)));
+        }
+        for (block_position, block) in blocks {
+            state.set(world, block_position, block);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use block;
+    use hashtable::DefaultBuildHasher;
+    use registry::RegistryBuilder;
+
+    fn chunk(id: &[u8; 4], content: &[u8], children: &[u8]) -> Vec<u8> {
+        let mut retval = id.to_vec();
+        write_u32(&mut retval, content.len() as u32).unwrap();
+        write_u32(&mut retval, children.len() as u32).unwrap();
+        retval.extend_from_slice(content);
+        retval.extend_from_slice(children);
+        retval
+    }
+
+    fn vox_file(children: &[Vec<u8>]) -> Vec<u8> {
+        let mut retval = MAGIC.to_vec();
+        write_u32(&mut retval, 200).unwrap();
+        retval.extend(chunk(b"MAIN", &[], &children.concat()));
+        retval
+    }
+
+    fn assert_invalid(data: &[u8]) {
+        match read_vox(data) {
+            Ok(_) => panic!("corrupt .vox file was accepted"),
+            Err(error) => assert!(
+                error.kind() == io::ErrorKind::InvalidData
+                    || error.kind() == io::ErrorKind::UnexpectedEof,
+                "{:?}",
+                error
+            ),
+        }
+    }
+
+    #[test]
+    fn test_read_write() {
+        let size = chunk(b"SIZE", &[2, 0, 0, 0, 3, 0, 0, 0, 4, 0, 0, 0], &[]);
+        let voxels = chunk(b"XYZI", &[2, 0, 0, 0, 0, 0, 0, 1, 1, 2, 3, 7], &[]);
+        // scene graph chunks are skipped
+        let transform = chunk(b"nTRN", &[1, 2, 3], &chunk(b"abcd", &[4], &[]));
+        let data = vox_file(&[size.clone(), voxels.clone(), transform.clone()]);
+        let model = read_vox(&data[..]).unwrap();
+        assert_eq!(
+            model,
+            VoxModel {
+                size: math::Vec3::new(2, 3, 4),
+                voxels: vec![(math::Vec3::new(0, 0, 0), 1), (math::Vec3::new(1, 2, 3), 7),],
+                palette: None,
+            }
+        );
+        let mut written = Vec::new();
+        write_vox(&mut written, &model).unwrap();
+        assert_eq!(read_vox(&written[..]).unwrap(), model);
+        let mut palette = vec![[0; 4]; PALETTE_SIZE];
+        for (index, color) in palette.iter_mut().enumerate().skip(1) {
+            *color = [index as u8, 0x12, 0x34, 0xFF];
+        }
+        let model = VoxModel {
+            palette: Some(palette),
+            ..model
+        };
+        written.clear();
+        write_vox(&mut written, &model).unwrap();
+        assert_eq!(read_vox(&written[..]).unwrap(), model);
+        let short_palette = VoxModel {
+            palette: Some(vec![[0; 4]; 16]),
+            ..model.clone()
+        };
+        assert!(write_vox(Vec::new(), &short_palette).is_err());
+        let big_model = VoxModel {
+            size: math::Vec3::new(1, 257, 1),
+            ..model.clone()
+        };
+        assert!(write_vox(Vec::new(), &big_model).is_err());
+        let pack = chunk(b"PACK", &[1, 0, 0, 0], &[]);
+        assert!(read_vox(&vox_file(&[pack, size.clone(), voxels.clone()])[..]).is_ok());
+        let mut corrupt = data.clone();
+        corrupt[0] = b'X';
+        assert_invalid(&corrupt);
+        for len in 0..data.len() {
+            assert_invalid(&data[..len]);
+        }
+        assert_invalid(&vox_file(&[size.clone()]));
+        assert_invalid(&vox_file(&[voxels.clone(), size.clone()]));
+        assert_invalid(&vox_file(&[
+            size.clone(),
+            voxels.clone(),
+            size.clone(),
+            voxels.clone(),
+        ]));
+        let pack = chunk(b"PACK", &[2, 0, 0, 0], &[]);
+        assert_invalid(&vox_file(&[pack, size.clone(), voxels.clone()]));
+        let big_size = chunk(b"SIZE", &[1, 1, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0], &[]);
+        assert_invalid(&vox_file(&[big_size, voxels.clone()]));
+        let outside = chunk(b"XYZI", &[1, 0, 0, 0, 2, 0, 0, 1], &[]);
+        assert_invalid(&vox_file(&[size.clone(), outside]));
+        let empty_color = chunk(b"XYZI", &[1, 0, 0, 0, 0, 0, 0, 0], &[]);
+        assert_invalid(&vox_file(&[size.clone(), empty_color]));
+        let too_short = chunk(b"XYZI", &[2, 0, 0, 0, 0, 0, 0, 1], &[]);
+        assert_invalid(&vox_file(&[size.clone(), too_short]));
+    }
+
+    #[test]
+    fn test_state() {
+        let mut registry_builder = RegistryBuilder::new();
+        block::register_blocks(&mut registry_builder);
+        let registry = registry_builder.finish_startup();
+        let stone = registry.find_block_by_name("voxels:stone").unwrap();
+        let air = registry.find_block_by_name("voxels:air").unwrap();
+        let mut mapping = VoxMapping::new();
+        mapping.insert(5, stone).unwrap();
+        mapping.insert(9, stone).unwrap();
+        assert!(mapping.insert(9, air).is_err());
+        assert!(mapping.insert(0, air).is_err());
+        assert_eq!(mapping.block_id(9), Some(stone));
+        assert_eq!(mapping.color_index(stone), Some(5));
+        assert_eq!(mapping.block_id(9), Some(stone));
+        let mut world = World::new(
+            |neighborhood: &[[[Block; 3]; 3]; 3]| neighborhood[1][1][1],
+            DefaultBuildHasher::new(),
+        );
+        let mut state = State::create_empty(&mut world);
+        let stone_block = Block::new(stone, BlockLighting::SKY);
+        let positions = [(-3, 4, 5), (0, 4, 5), (-3, 6, 7), (-1, 5, 5)];
+        for &(x, y, z) in &positions {
+            state.set(&mut world, math::Vec3::new(x, y, z), stone_block);
+        }
+        // air isn't in the mapping, so it's left out
+        state.set(
+            &mut world,
+            math::Vec3::new(-2, 4, 5),
+            Block::new(air, BlockLighting::SKY),
+        );
+        let region = Region::new(math::Vec3::new(-3, 4, 5), math::Vec3::new(1, 7, 8));
+        let model = VoxModel::from_state(&state, region, &mapping).unwrap();
+        assert_eq!(model.size, math::Vec3::new(4, 3, 3));
+        assert_eq!(model.region(region.min), region);
+        assert_eq!(model.voxels.len(), positions.len());
+        assert!(model.voxels.contains(&(math::Vec3::new(0, 0, 2), 5)));
+        let mut data = Vec::new();
+        write_vox(&mut data, &model).unwrap();
+        let model = read_vox(&data[..]).unwrap();
+        let mut loaded_state = State::create_empty(&mut world);
+        model
+            .write_to_state(&mut loaded_state, &mut world, region.min, &mapping)
+            .unwrap();
+        let mut expected_state = State::create_empty(&mut world);
+        for &(x, y, z) in &positions {
+            expected_state.set(&mut world, math::Vec3::new(x, y, z), stone_block);
+        }
+        assert!(loaded_state == expected_state);
+        // unmapped color index
+        let mut other_mapping = VoxMapping::new();
+        other_mapping.insert(9, stone).unwrap();
+        assert!(model
+            .write_to_state(&mut loaded_state, &mut world, region.min, &other_mapping)
+            .is_err());
+        let big_region = Region::new(math::Vec3::splat(0), math::Vec3::new(1, 1, 257));
+        assert!(VoxModel::from_state(&state, big_region, &mapping).is_err());
+        // models that don't fit are rejected before anything is written
+        let mut small_world = World::with_max_level(
+            |neighborhood: &[[[Block; 3]; 3]; 3]| neighborhood[1][1][1],
+            DefaultBuildHasher::new(),
+            3,
+        );
+        let mut small_state = State::create_empty(&mut small_world);
+        for &position in &[math::Vec3::new(5, 0, 0), math::Vec3::new(100, 0, 0)] {
+            assert!(model
+                .write_to_state(&mut small_state, &mut small_world, position, &mapping)
+                .is_err());
+        }
+        let position = math::Vec3::new(i32::max_value() - 1, 0, 0);
+        assert!(model
+            .write_to_state(&mut loaded_state, &mut world, position, &mapping)
+            .is_err());
+        assert_eq!(small_state.population(), 0);
+        model
+            .write_to_state(&mut small_state, &mut small_world, region.min, &mapping)
+            .unwrap();
+        assert_eq!(small_state.population(), positions.len() as u128);
+    }
+}
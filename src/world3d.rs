@@ -14,16 +14,20 @@
 // along with Hashlife3d.  If not, see <https://www.gnu.org/licenses/>
 use hashtable::*;
 use math::{self, Dot, Mappable, Reducible};
+use rayon::prelude::*;
 use serde::de::Error;
 use serde::{Deserialize, Deserializer};
 use std::cell::UnsafeCell;
+use std::cmp;
 use std::collections::{HashMap, VecDeque};
 use std::fmt;
 use std::hash::BuildHasher;
 use std::hash::{Hash, Hasher};
 use std::mem;
 use std::ptr::{self, NonNull};
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time;
 use std::u32;
 
 pub trait BlockType: Copy + Default + Eq + PartialEq + Hash + fmt::Debug {}
@@ -41,17 +45,79 @@ impl<Block: BlockType, T: Fn(&[[[Block; 3]; 3]; 3]) -> Block> StepFn<Block> for
 }
 
 #[derive(Eq, PartialEq, Hash, Copy, Clone, Debug)]
-struct NodeKeyNonleaf<Block: BlockType> {
-    children: [[[NonNull<Node<Block>>; 2]; 2]; 2],
+struct NodeKeyNonleaf {
+    // indexes into the world's node table, which are followed without locking
+    // the table's shards so parallel stepping isn't serialized
+    children: [[[ValueIndex; 2]; 2]; 2],
     children_level: u8,
 }
 
-type NodeKeyLeaf<Block> = [[[Block; 2]; 2]; 2];
+// boundary blocks are outside of a bounded world's region and never change,
+// which can't be decided by the block alone since the same block may also be
+// inside of the region
+#[derive(Eq, PartialEq, Hash, Copy, Clone, Debug, Default)]
+struct LeafBlock<Block: BlockType> {
+    block: Block,
+    is_boundary: bool,
+}
+
+impl<Block: BlockType> LeafBlock<Block> {
+    fn new(block: Block) -> Self {
+        Self {
+            block: block,
+            is_boundary: false,
+        }
+    }
+    fn boundary(block: Block) -> Self {
+        Self {
+            block: block,
+            is_boundary: true,
+        }
+    }
+}
+
+#[derive(Eq, PartialEq, Hash, Copy, Clone, Debug, Default)]
+struct NodeKeyLeaf<Block: BlockType> {
+    blocks: [[[Block; 2]; 2]; 2],
+    // bit x * 4 + y * 2 + z is set for boundary blocks, packed so leaf keys
+    // don't grow by a padded flag per block
+    boundary_mask: u8,
+}
+
+impl<Block: BlockType> NodeKeyLeaf<Block> {
+    fn new(blocks: [[[Block; 2]; 2]; 2]) -> Self {
+        Self {
+            blocks: blocks,
+            boundary_mask: 0,
+        }
+    }
+    fn filled(block: LeafBlock<Block>) -> Self {
+        Self {
+            blocks: [[[block.block; 2]; 2]; 2],
+            boundary_mask: if block.is_boundary { 0xFF } else { 0 },
+        }
+    }
+    fn get(&self, x: usize, y: usize, z: usize) -> LeafBlock<Block> {
+        LeafBlock {
+            block: self.blocks[x][y][z],
+            is_boundary: self.boundary_mask & 1 << (x * 4 + y * 2 + z) != 0,
+        }
+    }
+    fn set(&mut self, x: usize, y: usize, z: usize, block: LeafBlock<Block>) {
+        let bit = 1 << (x * 4 + y * 2 + z);
+        self.blocks[x][y][z] = block.block;
+        if block.is_boundary {
+            self.boundary_mask |= bit;
+        } else {
+            self.boundary_mask &= !bit;
+        }
+    }
+}
 
 #[derive(Eq, PartialEq, Hash, Copy, Clone, Debug)]
 enum NodeKey<Block: BlockType> {
     Leaf(NodeKeyLeaf<Block>),
-    Nonleaf(NodeKeyNonleaf<Block>),
+    Nonleaf(NodeKeyNonleaf),
 }
 
 impl<Block: BlockType> NodeKey<Block> {
@@ -61,7 +127,7 @@ impl<Block: BlockType> NodeKey<Block> {
             NodeKey::Nonleaf(NodeKeyNonleaf { children_level, .. }) => *children_level as u32 + 1,
         }
     }
-    fn is_valid(&self) -> bool {
+    fn is_valid<H: BuildHasher>(&self, shared_world_state: &SharedWorldState<Block, H>) -> bool {
         match self {
             NodeKey::Leaf(_) => true,
             NodeKey::Nonleaf(NodeKeyNonleaf {
@@ -70,8 +136,8 @@ impl<Block: BlockType> NodeKey<Block> {
             }) => {
                 for child in children {
                     for child in child {
-                        for child in child {
-                            let child = unsafe { &*child.as_ptr() };
+                        for &child in child {
+                            let child = unsafe { shared_world_state.get_node(child).as_ref() };
                             if child.key.level() != *children_level as u32 {
                                 return false;
                             }
@@ -88,7 +154,7 @@ impl<Block: BlockType> NodeKey<Block> {
             NodeKey::Nonleaf(_) => panic!("as_leaf called on Nonleaf"),
         }
     }
-    fn as_nonleaf(&self) -> &NodeKeyNonleaf<Block> {
+    fn as_nonleaf(&self) -> &NodeKeyNonleaf {
         match self {
             NodeKey::Nonleaf(retval) => retval,
             NodeKey::Leaf(_) => panic!("as_nonleaf called on Leaf"),
@@ -102,13 +168,39 @@ enum GcState {
     Reachable,
 }
 
-#[derive(Copy, Clone, Debug)]
+// nodes start out young and become old once they survive a collection
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum GcGeneration {
+    Young,
+    Old,
+}
+
+#[derive(Debug)]
 struct Node<Block: BlockType> {
     key: NodeKey<Block>,
-    next: [Option<NonNull<Node<Block>>>; 2],
+    // null until computed
+    next: [AtomicPtr<Node<Block>>; 2],
+    // single steps depend on the generation count, unlike double steps
+    single_step_log2_generation_count: AtomicUsize,
+    // the World's memo_clock when next was last used, for evicting results
+    next_last_used: AtomicUsize,
     gc_state: GcState,
+    gc_generation: GcGeneration,
+    // where the node is in the world's node table, set once it's inserted
+    index: ValueIndex,
 }
 
+unsafe impl<Block: BlockType + Send + Sync> Send for Node<Block> {}
+unsafe impl<Block: BlockType + Send + Sync> Sync for Node<Block> {}
+
+#[derive(Copy, Clone)]
+struct SendableNode<Block: BlockType>(NonNull<Node<Block>>);
+
+unsafe impl<Block: BlockType + Send + Sync> Send for SendableNode<Block> {}
+
+// smaller nodes aren't worth the overhead of splitting across threads
+const PARALLEL_MIN_LEVEL: u32 = 4;
+
 macro_rules! get_size_from_level {
     ($level:expr) => {
         2u32 << $level
@@ -116,25 +208,28 @@ macro_rules! get_size_from_level {
 }
 
 impl<Block: BlockType> Node<Block> {
+    fn get_index(node: NonNull<Node<Block>>) -> ValueIndex {
+        unsafe { node.as_ref() }.index
+    }
     fn get_filled_node<Step: StepFn<Block>, H: BuildHasher>(
-        block: Block,
+        block: LeafBlock<Block>,
         level: u8,
-        world: &mut World<Block, Step, H>,
+        world: &World<Block, Step, H>,
     ) -> NonNull<Node<Block>> {
         if level == 0 {
-            world.get(NodeKey::Leaf([[[block; 2]; 2]; 2])).into()
+            world.get(NodeKey::Leaf(NodeKeyLeaf::filled(block))).into()
         } else {
             let child = Node::get_filled_node(block, level - 1, world);
             world
                 .get(NodeKey::Nonleaf(NodeKeyNonleaf {
-                    children: [[[child; 2]; 2]; 2],
+                    children: [[[Node::get_index(child); 2]; 2]; 2],
                     children_level: level - 1,
                 })).into()
         }
     }
     fn get_empty_node<Step: StepFn<Block>, H: BuildHasher>(
         level: u8,
-        world: &mut World<Block, Step, H>,
+        world: &World<Block, Step, H>,
     ) -> NonNull<Node<Block>> {
         Node::get_filled_node(Default::default(), level, world)
     }
@@ -151,33 +246,120 @@ impl<Block: BlockType> Node<Block> {
     fn is_double_step(&self, log2_generation_count: u32) -> bool {
         self.get_log2_of_max_generation_step().unwrap() <= log2_generation_count
     }
+    fn get_next(&self, log2_generation_count: u32) -> Option<NonNull<Node<Block>>> {
+        if self.is_double_step(log2_generation_count) {
+            NonNull::new(self.next[1].load(Ordering::Acquire))
+        } else {
+            // only one generation count is ever stored concurrently, so if the
+            // pointer didn't change around reading the generation count, it
+            // belongs to that generation count
+            let retval = self.next[0].load(Ordering::SeqCst);
+            if self.single_step_log2_generation_count.load(Ordering::SeqCst)
+                != log2_generation_count as usize
+            {
+                return None;
+            }
+            if self.next[0].load(Ordering::SeqCst) != retval {
+                return None;
+            }
+            NonNull::new(retval)
+        }
+    }
+    fn set_next(&self, log2_generation_count: u32, next: NonNull<Node<Block>>) {
+        if self.is_double_step(log2_generation_count) {
+            self.next[1].store(next.as_ptr(), Ordering::Release);
+        } else {
+            self.next[0].store(ptr::null_mut(), Ordering::SeqCst);
+            self.single_step_log2_generation_count
+                .store(log2_generation_count as usize, Ordering::SeqCst);
+            self.next[0].store(next.as_ptr(), Ordering::SeqCst);
+        }
+    }
     fn compute_next<Step: StepFn<Block>, H: BuildHasher>(
-        mut node: NonNull<Node<Block>>,
+        node: NonNull<Node<Block>>,
         log2_generation_count: u32,
-        world: &mut World<Block, Step, H>,
+        world: &World<Block, Step, H>,
     ) -> NonNull<Node<Block>> {
-        let root = unsafe { node.as_mut() };
-        let is_double_step = root.is_double_step(log2_generation_count);
-        let next_index = is_double_step as usize;
-        if let Some(retval) = &mut root.next[next_index] {
-            return *retval;
+        Node::compute_next_with(node, log2_generation_count, world, Node::compute_nexts)
+    }
+    fn compute_nexts<Step: StepFn<Block>, H: BuildHasher>(
+        nodes: &mut [NonNull<Node<Block>>],
+        log2_generation_count: u32,
+        world: &World<Block, Step, H>,
+    ) {
+        for node in nodes {
+            *node = Node::compute_next(*node, log2_generation_count, world);
+        }
+    }
+    fn compute_next_parallel<Step: StepFn<Block> + Sync, H: BuildHasher + Sync>(
+        node: NonNull<Node<Block>>,
+        log2_generation_count: u32,
+        world: &World<Block, Step, H>,
+    ) -> NonNull<Node<Block>>
+    where
+        Block: Send + Sync,
+    {
+        Node::compute_next_with(
+            node,
+            log2_generation_count,
+            world,
+            Node::compute_nexts_parallel,
+        )
+    }
+    fn compute_nexts_parallel<Step: StepFn<Block> + Sync, H: BuildHasher + Sync>(
+        nodes: &mut [NonNull<Node<Block>>],
+        log2_generation_count: u32,
+        world: &World<Block, Step, H>,
+    ) where
+        Block: Send + Sync,
+    {
+        if unsafe { nodes[0].as_ref() }.key.level() < PARALLEL_MIN_LEVEL {
+            Node::compute_nexts(nodes, log2_generation_count, world);
+            return;
+        }
+        let mut sendable_nodes: Vec<_> = nodes.iter().map(|node| SendableNode(*node)).collect();
+        sendable_nodes.par_iter_mut().for_each(|node| {
+            node.0 = Node::compute_next_parallel(node.0, log2_generation_count, world);
+        });
+        for (node, sendable_node) in nodes.iter_mut().zip(sendable_nodes) {
+            *node = sendable_node.0;
+        }
+    }
+    fn compute_next_with<
+        Step: StepFn<Block>,
+        H: BuildHasher,
+        F: Fn(&mut [NonNull<Node<Block>>], u32, &World<Block, Step, H>),
+    >(
+        node: NonNull<Node<Block>>,
+        log2_generation_count: u32,
+        world: &World<Block, Step, H>,
+        compute_nexts: F,
+    ) -> NonNull<Node<Block>> {
+        let root = unsafe { node.as_ref() };
+        if let Some(retval) = root.get_next(log2_generation_count) {
+            root.next_last_used
+                .store(world.memo_clock, Ordering::Relaxed);
+            world.memo_hit_count.fetch_add(1, Ordering::Relaxed);
+            return retval;
         }
+        world.memo_miss_count.fetch_add(1, Ordering::Relaxed);
         let retval = match root.key.as_nonleaf() {
             NodeKeyNonleaf {
                 children,
                 children_level: 0,
             } => {
-                let mut input: [[[Block; 4]; 4]; 4] = Default::default();
+                let mut input: [[[LeafBlock<Block>; 4]; 4]; 4] = Default::default();
                 for outer_x in 0..2 {
                     for outer_y in 0..2 {
                         for outer_z in 0..2 {
-                            let inner = unsafe { children[outer_x][outer_y][outer_z].as_ref() };
+                            let inner = children[outer_x][outer_y][outer_z];
+                            let inner = unsafe { world.get_node(inner).as_ref() };
                             for inner_x in 0..2 {
                                 for inner_y in 0..2 {
                                     for inner_z in 0..2 {
                                         input[outer_x * 2 + inner_x][outer_y * 2 + inner_y]
                                             [outer_z * 2 + inner_z] =
-                                            inner.key.as_leaf()[inner_x][inner_y][inner_z];
+                                            inner.key.as_leaf().get(inner_x, inner_y, inner_z);
                                     }
                                 }
                             }
@@ -188,76 +370,82 @@ impl<Block: BlockType> Node<Block> {
                 for dx in 0..2 {
                     for dy in 0..2 {
                         for dz in 0..2 {
+                            // boundary blocks never change, so the outside stays
+                            // the same no matter how far ahead we compute
+                            let center = input[dx + 1][dy + 1][dz + 1];
+                            if center.is_boundary {
+                                next_key.set(dx, dy, dz, center);
+                                continue;
+                            }
                             let mut step_input: [[[Block; 3]; 3]; 3] = Default::default();
                             for x in 0..3 {
                                 for y in 0..3 {
                                     for z in 0..3 {
-                                        step_input[x][y][z] = input[x + dx][y + dy][z + dz];
+                                        step_input[x][y][z] = input[x + dx][y + dy][z + dz].block;
                                     }
                                 }
                             }
-                            next_key[dx][dy][dz] = world.step.step(&step_input);
+                            next_key.set(dx, dy, dz, LeafBlock::new(world.step.step(&step_input)));
                         }
                     }
                 }
-                let retval = world.get(NodeKey::Leaf(next_key)).into();
-                root.next[next_index] = Some(retval);
-                retval
+                world.get(NodeKey::Leaf(next_key)).into()
             }
             NodeKeyNonleaf {
                 children,
                 children_level,
             } => {
-                if is_double_step {
-                    let mut intermediate_state = [[[None; 3]; 3]; 3];
-                    for x in 0..3 {
-                        for y in 0..3 {
-                            for z in 0..3 {
-                                let is_x_at_edge = x == 0 || x == 2;
-                                let is_y_at_edge = y == 0 || y == 2;
-                                let is_z_at_edge = z == 0 || z == 2;
-                                let is_at_corner = is_x_at_edge && is_y_at_edge && is_z_at_edge;
-                                let initial_state_node;
-                                if is_at_corner {
-                                    initial_state_node = children[x / 2][y / 2][z / 2];
-                                } else {
-                                    let mut key = NodeKeyNonleaf {
-                                        children: [[[NonNull::dangling(); 2]; 2]; 2],
-                                        children_level: *children_level - 1,
-                                    };
-                                    for kx in 0..2 {
-                                        for ky in 0..2 {
-                                            for kz in 0..2 {
-                                                let x = x + kx;
-                                                let y = y + ky;
-                                                let z = z + kz;
-                                                key.children[kx][ky][kz] = unsafe {
-                                                    children[x / 2][y / 2][z / 2].as_ref()
-                                                }.key
-                                                .as_nonleaf()
-                                                .children[x % 2][y % 2][z % 2];
-                                            }
+                // indexed by x * 9 + y * 3 + z
+                let mut next_states = [NonNull::dangling(); 27];
+                for x in 0..3 {
+                    for y in 0..3 {
+                        for z in 0..3 {
+                            let is_x_at_edge = x == 0 || x == 2;
+                            let is_y_at_edge = y == 0 || y == 2;
+                            let is_z_at_edge = z == 0 || z == 2;
+                            let is_at_corner = is_x_at_edge && is_y_at_edge && is_z_at_edge;
+                            let initial_state_node;
+                            if is_at_corner {
+                                initial_state_node = world.get_node(children[x / 2][y / 2][z / 2]);
+                            } else {
+                                let mut key = NodeKeyNonleaf {
+                                    children: [[[Default::default(); 2]; 2]; 2],
+                                    children_level: *children_level - 1,
+                                };
+                                for kx in 0..2 {
+                                    for ky in 0..2 {
+                                        for kz in 0..2 {
+                                            let x = x + kx;
+                                            let y = y + ky;
+                                            let z = z + kz;
+                                            let child = children[x / 2][y / 2][z / 2];
+                                            key.children[kx][ky][kz] =
+                                                unsafe { world.get_node(child).as_ref() }
+                                                    .key
+                                                    .as_nonleaf()
+                                                    .children[x % 2][y % 2][z % 2];
                                         }
                                     }
-                                    initial_state_node = world.get(NodeKey::Nonleaf(key)).into();
-                                };
-                                intermediate_state[x][y][z] = Some(Node::compute_next(
-                                    initial_state_node,
-                                    log2_generation_count,
-                                    world,
-                                ));
-                            }
+                                }
+                                initial_state_node = world.get(NodeKey::Nonleaf(key)).into();
+                            };
+                            next_states[x * 9 + y * 3 + z] = initial_state_node;
                         }
                     }
-                    let mut final_key = NodeKeyNonleaf {
-                        children: [[[NonNull::dangling(); 2]; 2]; 2],
-                        children_level: *children_level - 1,
-                    };
+                }
+                compute_nexts(&mut next_states, log2_generation_count, world);
+                let mut final_key = NodeKeyNonleaf {
+                    children: [[[Default::default(); 2]; 2]; 2],
+                    children_level: *children_level - 1,
+                };
+                if root.is_double_step(log2_generation_count) {
+                    // indexed by x * 4 + y * 2 + z
+                    let mut final_states = [NonNull::dangling(); 8];
                     for x in 0..2 {
                         for y in 0..2 {
                             for z in 0..2 {
                                 let mut key = NodeKeyNonleaf {
-                                    children: [[[NonNull::dangling(); 2]; 2]; 2],
+                                    children: [[[Default::default(); 2]; 2]; 2],
                                     children_level: *children_level - 1,
                                 };
                                 for kx in 0..2 {
@@ -267,68 +455,25 @@ impl<Block: BlockType> Node<Block> {
                                             let y = y + ky;
                                             let z = z + kz;
                                             key.children[kx][ky][kz] =
-                                                intermediate_state[x][y][z].unwrap();
+                                                Node::get_index(next_states[x * 9 + y * 3 + z]);
                                         }
                                     }
                                 }
-                                let intermediate_state_node =
+                                final_states[x * 4 + y * 2 + z] =
                                     world.get(NodeKey::Nonleaf(key)).into();
-                                final_key.children[x][y][z] = Node::compute_next(
-                                    intermediate_state_node,
-                                    log2_generation_count,
-                                    world,
-                                );
                             }
                         }
                     }
-                    let retval = world.get(NodeKey::Nonleaf(final_key)).into();
-                    root.next[next_index] = Some(retval);
-                    retval
-                } else {
-                    let mut final_state = [[[None; 3]; 3]; 3];
-                    for x in 0..3 {
-                        for y in 0..3 {
-                            for z in 0..3 {
-                                let is_x_at_edge = x == 0 || x == 2;
-                                let is_y_at_edge = y == 0 || y == 2;
-                                let is_z_at_edge = z == 0 || z == 2;
-                                let is_at_corner = is_x_at_edge && is_y_at_edge && is_z_at_edge;
-                                let initial_state_node;
-                                if is_at_corner {
-                                    initial_state_node = children[x / 2][y / 2][z / 2];
-                                } else {
-                                    let mut key = NodeKeyNonleaf {
-                                        children: [[[NonNull::dangling(); 2]; 2]; 2],
-                                        children_level: *children_level - 1,
-                                    };
-                                    for kx in 0..2 {
-                                        for ky in 0..2 {
-                                            for kz in 0..2 {
-                                                let x = x + kx;
-                                                let y = y + ky;
-                                                let z = z + kz;
-                                                key.children[kx][ky][kz] = unsafe {
-                                                    children[x / 2][y / 2][z / 2].as_ref()
-                                                }.key
-                                                .as_nonleaf()
-                                                .children[x % 2][y % 2][z % 2];
-                                            }
-                                        }
-                                    }
-                                    initial_state_node = world.get(NodeKey::Nonleaf(key)).into();
-                                };
-                                final_state[x][y][z] = Some(Node::compute_next(
-                                    initial_state_node,
-                                    log2_generation_count,
-                                    world,
-                                ));
+                    compute_nexts(&mut final_states, log2_generation_count, world);
+                    for x in 0..2 {
+                        for y in 0..2 {
+                            for z in 0..2 {
+                                final_key.children[x][y][z] =
+                                    Node::get_index(final_states[x * 4 + y * 2 + z]);
                             }
                         }
                     }
-                    let mut final_key = NodeKeyNonleaf {
-                        children: [[[NonNull::dangling(); 2]; 2]; 2],
-                        children_level: *children_level - 1,
-                    };
+                } else {
                     for x in 0..2 {
                         for y in 0..2 {
                             for z in 0..2 {
@@ -342,20 +487,21 @@ impl<Block: BlockType> Node<Block> {
                                                 let x = 1 + x * 2 + kx;
                                                 let y = 1 + y * 2 + ky;
                                                 let z = 1 + z * 2 + kz;
-                                                key[kx][ky][kz] = unsafe {
-                                                    final_state[x / 2][y / 2][z / 2]
-                                                        .unwrap()
+                                                let block = unsafe {
+                                                    next_states[x / 2 * 9 + y / 2 * 3 + z / 2]
                                                         .as_ref()
                                                 }.key
-                                                .as_leaf()[x % 2][y % 2][z % 2];
+                                                .as_leaf()
+                                                .get(x % 2, y % 2, z % 2);
+                                                key.set(kx, ky, kz, block);
                                             }
                                         }
                                     }
                                     final_key.children[x][y][z] =
-                                        world.get(NodeKey::Leaf(key)).into();
+                                        world.get(NodeKey::Leaf(key)).index;
                                 } else {
                                     let mut key = NodeKeyNonleaf {
-                                        children: [[[NonNull::dangling(); 2]; 2]; 2],
+                                        children: [[[Default::default(); 2]; 2]; 2],
                                         children_level: *children_level - 2,
                                     };
                                     for kx in 0..2 {
@@ -365,8 +511,7 @@ impl<Block: BlockType> Node<Block> {
                                                 let y = 1 + y * 2 + ky;
                                                 let z = 1 + z * 2 + kz;
                                                 key.children[kx][ky][kz] = unsafe {
-                                                    final_state[x / 2][y / 2][z / 2]
-                                                        .unwrap()
+                                                    next_states[x / 2 * 9 + y / 2 * 3 + z / 2]
                                                         .as_ref()
                                                 }.key
                                                 .as_nonleaf()
@@ -375,28 +520,47 @@ impl<Block: BlockType> Node<Block> {
                                         }
                                     }
                                     final_key.children[x][y][z] =
-                                        world.get(NodeKey::Nonleaf(key)).into();
+                                        world.get(NodeKey::Nonleaf(key)).index;
                                 }
                             }
                         }
                     }
-                    let retval = world.get(NodeKey::Nonleaf(final_key)).into();
-                    root.next[next_index] = Some(retval);
-                    retval
                 }
+                world.get(NodeKey::Nonleaf(final_key)).into()
             }
         };
+        root.set_next(log2_generation_count, retval);
+        root.next_last_used
+            .store(world.memo_clock, Ordering::Relaxed);
+        if root.gc_generation == GcGeneration::Old
+            && unsafe { retval.as_ref() }.gc_generation == GcGeneration::Young
+        {
+            // minor collections only trace young nodes, so keep track of
+            // old nodes that point to young nodes
+            let nodes = unsafe { &*world.shared_world_state.nodes.get() };
+            world.shared_world_state.remembered_nodes[nodes.get_shard_index(root)]
+                .lock()
+                .unwrap()
+                .push(node);
+        }
         retval
     }
     fn expand_root<Step: StepFn<Block>, H: BuildHasher>(
         root: NonNull<Node<Block>>,
-        world: &mut World<Block, Step, H>,
+        world: &World<Block, Step, H>,
+    ) -> NonNull<Node<Block>> {
+        Node::expand_root_with(root, world.shared_world_state.topology.outside_block(), world)
+    }
+    fn expand_root_with<Step: StepFn<Block>, H: BuildHasher>(
+        root: NonNull<Node<Block>>,
+        fill_block: LeafBlock<Block>,
+        world: &World<Block, Step, H>,
     ) -> NonNull<Node<Block>> {
         let root_key = unsafe { root.as_ref() }.key;
         let root_key_level = root_key.level();
         assert!(root_key_level <= u8::max_value() as u32);
         let mut retval_key = NodeKeyNonleaf {
-            children: [[[NonNull::dangling(); 2]; 2]; 2],
+            children: [[[Default::default(); 2]; 2]; 2],
             children_level: root_key.level() as u8,
         };
         match root_key {
@@ -404,9 +568,9 @@ impl<Block: BlockType> Node<Block> {
                 for x in 0..2 {
                     for y in 0..2 {
                         for z in 0..2 {
-                            let mut key: NodeKeyLeaf<Block> = Default::default();
-                            key[1 - x][1 - y][1 - z] = children[x][y][z];
-                            retval_key.children[x][y][z] = world.get(NodeKey::Leaf(key)).into();
+                            let mut key = NodeKeyLeaf::filled(fill_block);
+                            key.set(1 - x, 1 - y, 1 - z, children.get(x, y, z));
+                            retval_key.children[x][y][z] = world.get(NodeKey::Leaf(key)).index;
                         }
                     }
                 }
@@ -415,16 +579,16 @@ impl<Block: BlockType> Node<Block> {
                 children,
                 children_level,
             }) => {
-                let empty_node = Node::get_empty_node(children_level, world);
+                let fill_node = Node::get_filled_node(fill_block, children_level, world);
                 for x in 0..2 {
                     for y in 0..2 {
                         for z in 0..2 {
                             let mut key = NodeKeyNonleaf {
-                                children: [[[empty_node; 2]; 2]; 2],
+                                children: [[[Node::get_index(fill_node); 2]; 2]; 2],
                                 children_level: children_level,
                             };
                             key.children[1 - x][1 - y][1 - z] = children[x][y][z];
-                            retval_key.children[x][y][z] = world.get(NodeKey::Nonleaf(key)).into();
+                            retval_key.children[x][y][z] = world.get(NodeKey::Nonleaf(key)).index;
                         }
                     }
                 }
@@ -434,7 +598,7 @@ impl<Block: BlockType> Node<Block> {
     }
     fn truncate_root<Step: StepFn<Block>, H: BuildHasher>(
         root: NonNull<Node<Block>>,
-        world: &mut World<Block, Step, H>,
+        world: &World<Block, Step, H>,
     ) -> NonNull<Node<Block>> {
         match unsafe { &root.as_ref().key } {
             NodeKey::Leaf(_) => panic!("can't truncate leaf node"),
@@ -446,9 +610,11 @@ impl<Block: BlockType> Node<Block> {
                 for x in 0..2 {
                     for y in 0..2 {
                         for z in 0..2 {
-                            retval_key[x][y][z] = unsafe { children[x][y][z].as_ref() }
+                            let block = unsafe { world.get_node(children[x][y][z]).as_ref() }
                                 .key
-                                .as_leaf()[1 - x][1 - y][1 - z];
+                                .as_leaf()
+                                .get(1 - x, 1 - y, 1 - z);
+                            retval_key.set(x, y, z, block);
                         }
                     }
                 }
@@ -459,16 +625,15 @@ impl<Block: BlockType> Node<Block> {
                 children_level,
             }) => {
                 let mut retval_key = NodeKeyNonleaf {
-                    children: [[[NonNull::dangling(); 2]; 2]; 2],
+                    children: [[[Default::default(); 2]; 2]; 2],
                     children_level: children_level - 1,
                 };
                 for x in 0..2 {
                     for y in 0..2 {
                         for z in 0..2 {
-                            retval_key.children[x][y][z] = unsafe { children[x][y][z].as_ref() }
-                                .key
-                                .as_nonleaf()
-                                .children[1 - x][1 - y][1 - z];
+                            let child = unsafe { world.get_node(children[x][y][z]).as_ref() };
+                            retval_key.children[x][y][z] =
+                                child.key.as_nonleaf().children[1 - x][1 - y][1 - z];
                         }
                     }
                 }
@@ -476,10 +641,90 @@ impl<Block: BlockType> Node<Block> {
             }
         }
     }
+    fn tile_root_to<Step: StepFn<Block>, H: BuildHasher>(
+        level: u32,
+        mut root: NonNull<Node<Block>>,
+        world: &World<Block, Step, H>,
+    ) -> NonNull<Node<Block>> {
+        assert!(level <= u8::max_value() as u32 + 1);
+        while unsafe { root.as_ref() }.key.level() < level {
+            let children_level = unsafe { root.as_ref() }.key.level() as u8;
+            root = world
+                .get(NodeKey::Nonleaf(NodeKeyNonleaf {
+                    children: [[[Node::get_index(root); 2]; 2]; 2],
+                    children_level: children_level,
+                })).into();
+        }
+        root
+    }
+    fn get_region_outside<H: BuildHasher>(
+        node: NonNull<Node<Block>>,
+        origin: math::Vec3<i64>,
+        excluded_region: &Region<i64>,
+        empty_nodes: &[NonNull<Node<Block>>],
+        retval: &mut Option<Region<i64>>,
+        shared_world_state: &SharedWorldState<Block, H>,
+    ) {
+        let level = unsafe { node.as_ref() }.key.level();
+        if node == empty_nodes[level as usize] {
+            return;
+        }
+        // saturate instead of overflowing for absurdly big steps
+        let size = if level < 62 { 2i64 << level } else { i64::max_value() };
+        let region = Region::new(origin, origin.map(|v| v.saturating_add(size)));
+        if excluded_region.contains_region(&region) {
+            return;
+        }
+        if let Some(retval) = retval {
+            if retval.contains_region(&region) {
+                return;
+            }
+        }
+        match unsafe { &node.as_ref().key } {
+            NodeKey::Leaf(key) => {
+                for x in 0..2 {
+                    for y in 0..2 {
+                        for z in 0..2 {
+                            let position = origin + math::Vec3::new(x as i64, y as i64, z as i64);
+                            let block_region = Region::new(position, position + math::Vec3::splat(1));
+                            if key.blocks[x][y][z] == Default::default()
+                                || excluded_region.contains_region(&block_region)
+                            {
+                                continue;
+                            }
+                            *retval = Some(match *retval {
+                                Some(retval) => retval.union(block_region),
+                                None => block_region,
+                            });
+                        }
+                    }
+                }
+            }
+            NodeKey::Nonleaf(key) => {
+                for x in 0..2 {
+                    for y in 0..2 {
+                        for z in 0..2 {
+                            let child_origin = math::Vec3::new(x as i64, y as i64, z as i64)
+                                .zip(origin)
+                                .map(|(index, origin)| origin.saturating_add(index * (size / 2)));
+                            Node::get_region_outside(
+                                shared_world_state.get_node(key.children[x][y][z]),
+                                child_origin,
+                                excluded_region,
+                                empty_nodes,
+                                retval,
+                                shared_world_state,
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
     fn truncate_root_to<Step: StepFn<Block>, H: BuildHasher>(
         level: u32,
         mut root: NonNull<Node<Block>>,
-        world: &mut World<Block, Step, H>,
+        world: &World<Block, Step, H>,
     ) -> NonNull<Node<Block>> {
         assert!(level <= unsafe { root.as_ref() }.key.level());
         for _ in level..unsafe { root.as_ref() }.key.level() {
@@ -488,29 +733,196 @@ impl<Block: BlockType> Node<Block> {
         assert!(level == unsafe { root.as_ref() }.key.level());
         root
     }
-    fn get_block(root: NonNull<Node<Block>>, mut position: math::Vec3<u32>) -> Block {
+    fn expand_root_to<Step: StepFn<Block>, H: BuildHasher>(
+        level: u32,
+        fill_block: LeafBlock<Block>,
+        mut root: NonNull<Node<Block>>,
+        world: &World<Block, Step, H>,
+    ) -> NonNull<Node<Block>> {
+        while unsafe { root.as_ref() }.key.level() < level {
+            root = Node::expand_root_with(root, fill_block, world);
+        }
+        root
+    }
+    // undoes expand_root_with for as long as everything outside the center is fill_block
+    fn shrink_root<Step: StepFn<Block>, H: BuildHasher>(
+        min_level: u32,
+        fill_block: LeafBlock<Block>,
+        mut root: NonNull<Node<Block>>,
+        world: &World<Block, Step, H>,
+    ) -> NonNull<Node<Block>> {
+        loop {
+            let can_shrink = match unsafe { &root.as_ref().key } {
+                NodeKey::Leaf(_) => false,
+                _ if unsafe { root.as_ref() }.key.level() <= min_level => false,
+                NodeKey::Nonleaf(NodeKeyNonleaf {
+                    children,
+                    children_level: 0,
+                }) => (0..8).all(|i| {
+                    let (x, y, z) = (i >> 2, (i >> 1) & 1, i & 1);
+                    let child = unsafe { world.get_node(children[x][y][z]).as_ref() };
+                    let child = child.key.as_leaf();
+                    (0..8).all(|j| {
+                        let (cx, cy, cz) = (j >> 2, (j >> 1) & 1, j & 1);
+                        (cx, cy, cz) == (1 - x, 1 - y, 1 - z) || child.get(cx, cy, cz) == fill_block
+                    })
+                }),
+                NodeKey::Nonleaf(NodeKeyNonleaf {
+                    children,
+                    children_level,
+                }) => {
+                    let fill_node = Node::get_filled_node(fill_block, children_level - 1, world);
+                    let fill_node = Node::get_index(fill_node);
+                    (0..8).all(|i| {
+                        let (x, y, z) = (i >> 2, (i >> 1) & 1, i & 1);
+                        let child = unsafe { world.get_node(children[x][y][z]).as_ref() };
+                        let child = child.key.as_nonleaf();
+                        (0..8).all(|j| {
+                            let (cx, cy, cz) = (j >> 2, (j >> 1) & 1, j & 1);
+                            (cx, cy, cz) == (1 - x, 1 - y, 1 - z)
+                                || child.children[cx][cy][cz] == fill_node
+                        })
+                    })
+                }
+            };
+            if !can_shrink {
+                return root;
+            }
+            root = Node::truncate_root(root, world);
+        }
+    }
+    // identical subtrees are only counted once, so this is proportional to
+    // the number of distinct nodes rather than the number of blocks
+    fn count_blocks_if<F: Fn(Block) -> bool, H: BuildHasher>(
+        node: NonNull<Node<Block>>,
+        predicate: &F,
+        counts: &mut HashMap<NonNull<Node<Block>>, u128>,
+        shared_world_state: &SharedWorldState<Block, H>,
+    ) -> u128 {
+        if let Some(count) = counts.get(&node) {
+            return *count;
+        }
+        let mut count = 0;
+        match &unsafe { node.as_ref() }.key {
+            NodeKey::Leaf(key) => {
+                for block in &key.blocks {
+                    for block in block {
+                        for block in block {
+                            if predicate(*block) {
+                                count += 1;
+                            }
+                        }
+                    }
+                }
+            }
+            NodeKey::Nonleaf(NodeKeyNonleaf { children, .. }) => {
+                for child in children {
+                    for child in child {
+                        for &child in child {
+                            count += Node::count_blocks_if(
+                                shared_world_state.get_node(child),
+                                predicate,
+                                counts,
+                                shared_world_state,
+                            );
+                        }
+                    }
+                }
+            }
+        }
+        counts.insert(node, count);
+        count
+    }
+    // relative to the node's origin, None if all blocks are the default
+    fn get_bounding_box<H: BuildHasher>(
+        node: NonNull<Node<Block>>,
+        bounding_boxes: &mut HashMap<NonNull<Node<Block>>, Option<Region<u32>>>,
+        shared_world_state: &SharedWorldState<Block, H>,
+    ) -> Option<Region<u32>> {
+        if let Some(bounding_box) = bounding_boxes.get(&node) {
+            return *bounding_box;
+        }
+        let mut retval: Option<Region<u32>> = None;
+        match &unsafe { node.as_ref() }.key {
+            NodeKey::Leaf(key) => {
+                for x in 0..2 {
+                    for y in 0..2 {
+                        for z in 0..2 {
+                            if key.blocks[x][y][z] == Default::default() {
+                                continue;
+                            }
+                            let position = math::Vec3::new(x as u32, y as u32, z as u32);
+                            let block_region =
+                                Region::new(position, position + math::Vec3::splat(1));
+                            retval = Some(match retval {
+                                Some(retval) => retval.union(block_region),
+                                None => block_region,
+                            });
+                        }
+                    }
+                }
+            }
+            NodeKey::Nonleaf(key) => {
+                let child_size = get_size_from_level!(key.children_level);
+                for x in 0..2 {
+                    for y in 0..2 {
+                        for z in 0..2 {
+                            let child = shared_world_state.get_node(key.children[x][y][z]);
+                            let child_bounding_box = match Node::get_bounding_box(
+                                child,
+                                bounding_boxes,
+                                shared_world_state,
+                            ) {
+                                Some(child_bounding_box) => child_bounding_box,
+                                None => continue,
+                            };
+                            let child_origin = math::Vec3::new(x as u32, y as u32, z as u32)
+                                * math::Vec3::splat(child_size);
+                            let child_region = Region::new(
+                                child_bounding_box.min + child_origin,
+                                child_bounding_box.max + child_origin,
+                            );
+                            retval = Some(match retval {
+                                Some(retval) => retval.union(child_region),
+                                None => child_region,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+        bounding_boxes.insert(node, retval);
+        retval
+    }
+    fn get_block<H: BuildHasher>(
+        root: NonNull<Node<Block>>,
+        mut position: math::Vec3<u32>,
+        shared_world_state: &SharedWorldState<Block, H>,
+    ) -> Block {
         let mut root = unsafe { root.as_ref() };
         loop {
             let size = get_size_from_level!(root.key.level());
             assert!(position.x < size && position.y < size && position.z < size);
             match &root.key {
                 NodeKey::Leaf(key) => {
-                    break key[position.x as usize][position.y as usize][position.z as usize]
+                    break key.blocks[position.x as usize][position.y as usize][position.z as usize]
                 }
                 NodeKey::Nonleaf(key) => {
                     let index = position.map(|v| (v / (size / 2)) as usize);
                     position %= math::Vec3::splat(size / 2);
-                    root = unsafe { key.children[index.x][index.y][index.z].as_ref() };
+                    let child = key.children[index.x][index.y][index.z];
+                    root = unsafe { shared_world_state.get_node(child).as_ref() };
                 }
             }
         }
     }
-    fn get_cube_pow2(
+    fn get_cube_pow2<H: BuildHasher>(
         root: NonNull<Node<Block>>,
         position: math::Vec3<u32>,
         cube_size: u32,
         stride: math::Vec3<usize>,
         result: &mut [Block],
+        shared_world_state: &SharedWorldState<Block, H>,
     ) {
         let root = unsafe { root.as_ref() };
         let size = get_size_from_level!(root.key.level());
@@ -522,13 +934,14 @@ impl<Block: BlockType> Node<Block> {
         );
         match &root.key {
             NodeKey::Leaf(key) => if cube_size == 1 {
-                result[0] = key[position.x as usize][position.y as usize][position.z as usize]
+                result[0] =
+                    key.blocks[position.x as usize][position.y as usize][position.z as usize]
             } else {
                 assert_eq!(position, math::Vec3::splat(0));
                 for z in 0..2 {
                     for y in 0..2 {
                         for x in 0..2 {
-                            result[math::Vec3::new(x, y, z).dot(stride)] = key[x][y][z];
+                            result[math::Vec3::new(x, y, z).dot(stride)] = key.blocks[x][y][z];
                         }
                     }
                 }
@@ -542,11 +955,12 @@ impl<Block: BlockType> Node<Block> {
                                 math::Vec3::new(xi, yi, zi).map(|v| (v * (size / 2) as usize)),
                             );
                             Self::get_cube_pow2(
-                                key.children[xi][yi][zi],
+                                shared_world_state.get_node(key.children[xi][yi][zi]),
                                 math::Vec3::splat(0),
                                 size / 2,
                                 stride,
                                 &mut result[offset..],
+                                shared_world_state,
                             );
                         }
                     }
@@ -555,19 +969,21 @@ impl<Block: BlockType> Node<Block> {
                 assert!(cube_size <= size / 2);
                 let index = position.map(|v| (v / (size / 2)) as usize);
                 Self::get_cube_pow2(
-                    key.children[index.x][index.y][index.z],
+                    shared_world_state.get_node(key.children[index.x][index.y][index.z]),
                     position % math::Vec3::splat(size / 2),
                     cube_size,
                     stride,
                     result,
+                    shared_world_state,
                 );
             },
         }
     }
-    fn get_child_node(
+    fn get_child_node<H: BuildHasher>(
         mut root: NonNull<Node<Block>>,
         mut position: math::Vec3<u32>,
         child_size: u32,
+        shared_world_state: &SharedWorldState<Block, H>,
     ) -> NonNull<Node<Block>> {
         assert!(child_size >= 2);
         loop {
@@ -588,7 +1004,7 @@ impl<Block: BlockType> Node<Block> {
                     assert!(child_size <= size / 2);
                     let index = position.map(|v| (v / (size / 2)) as usize);
                     position %= math::Vec3::splat(size / 2);
-                    key.children[index.x][index.y][index.z]
+                    shared_world_state.get_node(key.children[index.x][index.y][index.z])
                 }
             }
         }
@@ -597,7 +1013,7 @@ impl<Block: BlockType> Node<Block> {
         root: NonNull<Node<Block>>,
         position: math::Vec3<u32>,
         block: Block,
-        world: &mut World<Block, Step, H>,
+        world: &World<Block, Step, H>,
     ) -> NonNull<Node<Block>> {
         let root = unsafe { root.as_ref() };
         let size = get_size_from_level!(root.key.level());
@@ -605,18 +1021,20 @@ impl<Block: BlockType> Node<Block> {
         match &root.key {
             NodeKey::Leaf(key) => {
                 let mut new_key = *key;
-                new_key[position.x as usize][position.y as usize][position.z as usize] = block;
+                let position = position.map(|v| v as usize);
+                new_key.set(position.x, position.y, position.z, LeafBlock::new(block));
                 world.get(NodeKey::Leaf(new_key)).into()
             }
             NodeKey::Nonleaf(key) => {
                 let mut new_key = *key;
                 let index = position.map(|v| (v / (size / 2)) as usize);
-                new_key.children[index.x][index.y][index.z] = Node::set_block_without_expanding(
-                    key.children[index.x][index.y][index.z],
+                let child = Node::set_block_without_expanding(
+                    world.get_node(key.children[index.x][index.y][index.z]),
                     position % math::Vec3::splat(size / 2),
                     block,
                     world,
                 );
+                new_key.children[index.x][index.y][index.z] = Node::get_index(child);
                 world.get(NodeKey::Nonleaf(new_key)).into()
             }
         }
@@ -624,13 +1042,13 @@ impl<Block: BlockType> Node<Block> {
     fn set_cube_pow2_without_expanding<
         Step: StepFn<Block>,
         H: BuildHasher,
-        F: FnMut(math::Vec3<u32>, Block) -> Block,
+        F: FnMut(math::Vec3<u32>, LeafBlock<Block>) -> LeafBlock<Block>,
     >(
         root: NonNull<Node<Block>>,
         position: math::Vec3<u32>,
         cube_size: u32,
         cube_offset: math::Vec3<u32>,
-        world: &mut World<Block, Step, H>,
+        world: &World<Block, Step, H>,
         f: &mut F,
     ) -> NonNull<Node<Block>> {
         let key = unsafe { root.as_ref().key };
@@ -643,20 +1061,19 @@ impl<Block: BlockType> Node<Block> {
         );
         match key {
             NodeKey::Leaf(mut key) => if cube_size == 1 {
-                key[position.x as usize][position.y as usize][position.z as usize] = f(
-                    cube_offset,
-                    key[position.x as usize][position.y as usize][position.z as usize],
-                );
+                let position = position.map(|v| v as usize);
+                let block = f(cube_offset, key.get(position.x, position.y, position.z));
+                key.set(position.x, position.y, position.z, block);
                 world.get(NodeKey::Leaf(key)).into()
             } else {
                 assert_eq!(position, math::Vec3::splat(0));
                 for z in 0..2 {
                     for y in 0..2 {
                         for x in 0..2 {
-                            key[x as usize][y as usize][z as usize] = f(
-                                cube_offset + math::Vec3::new(x, y, z),
-                                key[x as usize][y as usize][z as usize],
-                            );
+                            let (kx, ky, kz) = (x as usize, y as usize, z as usize);
+                            let offset = cube_offset + math::Vec3::new(x, y, z);
+                            let block = f(offset, key.get(kx, ky, kz));
+                            key.set(kx, ky, kz, block);
                         }
                     }
                 }
@@ -669,14 +1086,14 @@ impl<Block: BlockType> Node<Block> {
                         for xi in 0..2 {
                             let offset = math::Vec3::new(xi, yi, zi).map(|v| (v * (size / 2)));
                             let child = &mut key.children[xi as usize][yi as usize][zi as usize];
-                            *child = Self::set_cube_pow2_without_expanding(
-                                *child,
+                            *child = Node::get_index(Self::set_cube_pow2_without_expanding(
+                                world.get_node(*child),
                                 math::Vec3::splat(0),
                                 size / 2,
                                 cube_offset + offset,
                                 world,
                                 f,
-                            );
+                            ));
                         }
                     }
                 }
@@ -686,61 +1103,593 @@ impl<Block: BlockType> Node<Block> {
                 let index = position.map(|v| (v / (size / 2)) as usize);
                 {
                     let child = &mut key.children[index.x][index.y][index.z];
-                    *child = Self::set_cube_pow2_without_expanding(
-                        *child,
+                    *child = Node::get_index(Self::set_cube_pow2_without_expanding(
+                        world.get_node(*child),
                         position % math::Vec3::splat(size / 2),
                         cube_size,
                         cube_offset,
                         world,
                         f,
-                    );
+                    ));
                 }
                 world.get(NodeKey::Nonleaf(key)).into()
             },
         }
     }
-}
-
-impl<Block: BlockType> Default for NodeKey<Block> {
-    fn default() -> Self {
-        NodeKey::Leaf([[[Default::default(); 2]; 2]; 2])
-    }
-}
-
-impl Default for GcState {
-    fn default() -> Self {
-        GcState::Reachable
-    }
-}
-
-impl<Block: BlockType> Default for Node<Block> {
-    fn default() -> Self {
-        Self {
-            key: Default::default(),
-            next: [None; 2],
-            gc_state: Default::default(),
+    // the node at level with its origin at position relative to root, which may be outside root
+    fn get_aligned_node<Step: StepFn<Block>, H: BuildHasher>(
+        root: NonNull<Node<Block>>,
+        position: math::Vec3<i64>,
+        level: u32,
+        outside_block: LeafBlock<Block>,
+        world: &World<Block, Step, H>,
+    ) -> NonNull<Node<Block>> {
+        let root_size = get_size_from_level!(unsafe { root.as_ref() }.key.level()) as i64;
+        if position.map(|v| v >= 0 && v < root_size).reduce(|a, b| a && b) {
+            Node::get_child_node(
+                root,
+                position.map(|v| v as u32),
+                get_size_from_level!(level),
+                &world.shared_world_state,
+            )
+        } else {
+            Node::get_filled_node(outside_block, level as u8, world)
         }
     }
-}
-
-impl<Block: BlockType> Eq for Node<Block> {}
-
-impl<Block: BlockType> PartialEq for Node<Block> {
-    fn eq(&self, rhs: &Self) -> bool {
-        self.key == rhs.key
-    }
-}
-
-impl<Block: BlockType> Hash for Node<Block> {
-    fn hash<H: Hasher>(&self, h: &mut H) {
+    // the node at the same level as nodes, shifted by shift into the cube that nodes make up
+    fn get_shifted_node<Step: StepFn<Block>, H: BuildHasher>(
+        nodes: [[[NonNull<Node<Block>>; 2]; 2]; 2],
+        shift: math::Vec3<u32>,
+        world: &World<Block, Step, H>,
+        shifted_nodes: &mut ShiftedNodes<Block>,
+    ) -> NonNull<Node<Block>> {
+        if shift == math::Vec3::splat(0) {
+            return nodes[0][0][0];
+        }
+        if let Some(retval) = shifted_nodes.get(&(nodes, shift)) {
+            return *retval;
+        }
+        let level = unsafe { nodes[0][0][0].as_ref() }.key.level();
+        let retval = if level == 0 {
+            let mut key: NodeKeyLeaf<Block> = Default::default();
+            for x in 0..2 {
+                for y in 0..2 {
+                    for z in 0..2 {
+                        let position = shift + math::Vec3::new(x as u32, y as u32, z as u32);
+                        let index = position.map(|v| (v / 2) as usize);
+                        let child_position = position.map(|v| (v % 2) as usize);
+                        let block = unsafe { nodes[index.x][index.y][index.z].as_ref() }
+                            .key
+                            .as_leaf()
+                            .get(child_position.x, child_position.y, child_position.z);
+                        key.set(x, y, z, block);
+                    }
+                }
+            }
+            world.get(NodeKey::Leaf(key)).into()
+        } else {
+            let half_size = get_size_from_level!(level) / 2;
+            let mut key = NodeKeyNonleaf {
+                children: [[[Default::default(); 2]; 2]; 2],
+                children_level: level as u8 - 1,
+            };
+            for x in 0..2 {
+                for y in 0..2 {
+                    for z in 0..2 {
+                        let position = shift
+                            + math::Vec3::new(x as u32, y as u32, z as u32)
+                                * math::Vec3::splat(half_size);
+                        let mut children = [[[NonNull::dangling(); 2]; 2]; 2];
+                        for kx in 0..2 {
+                            for ky in 0..2 {
+                                for kz in 0..2 {
+                                    let index = position.map(|v| (v / half_size) as usize)
+                                        + math::Vec3::new(kx, ky, kz);
+                                    let child = unsafe {
+                                        nodes[index.x / 2][index.y / 2][index.z / 2].as_ref()
+                                    }.key
+                                    .as_nonleaf()
+                                    .children[index.x % 2][index.y % 2][index.z % 2];
+                                    children[kx][ky][kz] = world.get_node(child);
+                                }
+                            }
+                        }
+                        key.children[x][y][z] = Node::get_index(Node::get_shifted_node(
+                            children,
+                            position.map(|v| v % half_size),
+                            world,
+                            shifted_nodes,
+                        ));
+                    }
+                }
+            }
+            world.get(NodeKey::Nonleaf(key)).into()
+        };
+        shifted_nodes.insert((nodes, shift), retval);
+        retval
+    }
+    // nodes entirely inside region are shared with source when it is aligned
+    fn replace_region<Step: StepFn<Block>, H: BuildHasher>(
+        node: NonNull<Node<Block>>,
+        origin: math::Vec3<i64>,
+        region: &Region<i64>,
+        source: &RegionSource<Block>,
+        world: &World<Block, Step, H>,
+        shifted_nodes: &mut ShiftedNodes<Block>,
+        replaced_nodes: &mut ReplacedNodes<Block>,
+    ) -> NonNull<Node<Block>> {
+        let level = unsafe { node.as_ref() }.key.level();
+        let size = get_size_from_level!(level) as i64;
+        let node_region = Region::new(origin, origin + math::Vec3::splat(size));
+        let clipped_region = match region.intersection(node_region) {
+            Some(clipped_region) => clipped_region,
+            None => return node,
+        };
+        if clipped_region == node_region {
+            return source.get_node(origin, level, world, shifted_nodes);
+        }
+        // the edges of big regions cut through lots of identical nodes
+        let replaced_nodes_key = (
+            node,
+            Region::new(clipped_region.min - origin, clipped_region.max - origin),
+            source.get_source_position(origin),
+        );
+        if let Some(retval) = replaced_nodes.get(&replaced_nodes_key) {
+            return *retval;
+        }
+        let retval = match unsafe { node.as_ref() }.key {
+            NodeKey::Leaf(mut key) => {
+                for x in 0..2 {
+                    for y in 0..2 {
+                        for z in 0..2 {
+                            let position = origin + math::Vec3::new(x as i64, y as i64, z as i64);
+                            if region.contains_region(&Region::new(
+                                position,
+                                position + math::Vec3::splat(1),
+                            )) {
+                                let block = source.get_block(position, &world.shared_world_state);
+                                key.set(x, y, z, block);
+                            }
+                        }
+                    }
+                }
+                world.get(NodeKey::Leaf(key)).into()
+            }
+            NodeKey::Nonleaf(mut key) => {
+                for x in 0..2 {
+                    for y in 0..2 {
+                        for z in 0..2 {
+                            let child_origin = origin
+                                + math::Vec3::new(x as i64, y as i64, z as i64)
+                                    * math::Vec3::splat(size / 2);
+                            let child = Node::replace_region(
+                                world.get_node(key.children[x][y][z]),
+                                child_origin,
+                                region,
+                                source,
+                                world,
+                                shifted_nodes,
+                                replaced_nodes,
+                            );
+                            key.children[x][y][z] = Node::get_index(child);
+                        }
+                    }
+                }
+                world.get(NodeKey::Nonleaf(key)).into()
+            }
+        };
+        replaced_nodes.insert(replaced_nodes_key, retval);
+        retval
+    }
+    fn transform<Step: StepFn<Block>, H: BuildHasher>(
+        node: NonNull<Node<Block>>,
+        transform: Transform,
+        world: &World<Block, Step, H>,
+        transformed_nodes: &mut HashMap<NonNull<Node<Block>>, NonNull<Node<Block>>>,
+    ) -> NonNull<Node<Block>> {
+        if let Some(retval) = transformed_nodes.get(&node) {
+            return *retval;
+        }
+        let retval = match unsafe { node.as_ref() }.key {
+            NodeKey::Leaf(key) => {
+                let mut transformed_key = key;
+                for x in 0..2 {
+                    for y in 0..2 {
+                        for z in 0..2 {
+                            let index = transform
+                                .apply_in_cube(math::Vec3::new(x as u32, y as u32, z as u32), 2)
+                                .map(|v| v as usize);
+                            transformed_key.set(index.x, index.y, index.z, key.get(x, y, z));
+                        }
+                    }
+                }
+                world.get(NodeKey::Leaf(transformed_key)).into()
+            }
+            NodeKey::Nonleaf(key) => {
+                let mut transformed_key = key;
+                for x in 0..2 {
+                    for y in 0..2 {
+                        for z in 0..2 {
+                            let index = transform
+                                .apply_in_cube(math::Vec3::new(x as u32, y as u32, z as u32), 2)
+                                .map(|v| v as usize);
+                            let child = Node::transform(
+                                world.get_node(key.children[x][y][z]),
+                                transform,
+                                world,
+                                transformed_nodes,
+                            );
+                            transformed_key.children[index.x][index.y][index.z] =
+                                Node::get_index(child);
+                        }
+                    }
+                }
+                world.get(NodeKey::Nonleaf(transformed_key)).into()
+            }
+        };
+        transformed_nodes.insert(node, retval);
+        retval
+    }
+    // for blocks that end up inside of a bounded world's region
+    fn without_boundary<Step: StepFn<Block>, H: BuildHasher>(
+        node: NonNull<Node<Block>>,
+        world: &World<Block, Step, H>,
+        cleared_nodes: &mut HashMap<NonNull<Node<Block>>, NonNull<Node<Block>>>,
+    ) -> NonNull<Node<Block>> {
+        if let Some(retval) = cleared_nodes.get(&node) {
+            return *retval;
+        }
+        let retval = match unsafe { node.as_ref() }.key {
+            NodeKey::Leaf(mut key) => {
+                key.boundary_mask = 0;
+                world.get(NodeKey::Leaf(key)).into()
+            }
+            NodeKey::Nonleaf(mut key) => {
+                for child in key.children.iter_mut() {
+                    for child in child.iter_mut() {
+                        for child in child.iter_mut() {
+                            let child_node = world.get_node(*child);
+                            let cleared_child =
+                                Node::without_boundary(child_node, world, cleared_nodes);
+                            *child = Node::get_index(cleared_child);
+                        }
+                    }
+                }
+                world.get(NodeKey::Nonleaf(key)).into()
+            }
+        };
+        cleared_nodes.insert(node, retval);
+        retval
+    }
+}
+
+impl<Block: BlockType> Default for NodeKey<Block> {
+    fn default() -> Self {
+        NodeKey::Leaf(Default::default())
+    }
+}
+
+impl Default for GcState {
+    fn default() -> Self {
+        GcState::Reachable
+    }
+}
+
+impl<Block: BlockType> Default for Node<Block> {
+    fn default() -> Self {
+        Self {
+            key: Default::default(),
+            next: [
+                AtomicPtr::new(ptr::null_mut()),
+                AtomicPtr::new(ptr::null_mut()),
+            ],
+            single_step_log2_generation_count: AtomicUsize::new(0),
+            next_last_used: AtomicUsize::new(0),
+            gc_state: Default::default(),
+            gc_generation: GcGeneration::Young,
+            index: Default::default(),
+        }
+    }
+}
+
+impl<Block: BlockType> Eq for Node<Block> {}
+
+impl<Block: BlockType> PartialEq for Node<Block> {
+    fn eq(&self, rhs: &Self) -> bool {
+        self.key == rhs.key
+    }
+}
+
+impl<Block: BlockType> Hash for Node<Block> {
+    fn hash<H: Hasher>(&self, h: &mut H) {
         self.key.hash(h);
     }
 }
 
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct Region<T> {
+    // inclusive
+    pub min: math::Vec3<T>,
+    // exclusive
+    pub max: math::Vec3<T>,
+}
+
+impl<T: Copy + Ord> Region<T> {
+    pub fn new(min: math::Vec3<T>, max: math::Vec3<T>) -> Self {
+        Self { min: min, max: max }
+    }
+    pub fn contains_region(&self, rhs: &Self) -> bool {
+        self.min.zip(rhs.min).map(|(a, b)| a <= b).reduce(|a, b| a && b)
+            && self.max.zip(rhs.max).map(|(a, b)| a >= b).reduce(|a, b| a && b)
+    }
+    pub fn union(self, rhs: Self) -> Self {
+        Self {
+            min: self.min.zip(rhs.min).map(|(a, b)| cmp::min(a, b)),
+            max: self.max.zip(rhs.max).map(|(a, b)| cmp::max(a, b)),
+        }
+    }
+    // None if the regions don't overlap
+    pub fn intersection(self, rhs: Self) -> Option<Self> {
+        let retval = Self {
+            min: self.min.zip(rhs.min).map(|(a, b)| cmp::max(a, b)),
+            max: self.max.zip(rhs.max).map(|(a, b)| cmp::min(a, b)),
+        };
+        if retval.min.zip(retval.max).map(|(a, b)| a < b).reduce(|a, b| a && b) {
+            Some(retval)
+        } else {
+            None
+        }
+    }
+}
+
+type ShiftedNodes<Block> = HashMap<
+    (
+        [[[NonNull<Node<Block>>; 2]; 2]; 2],
+        math::Vec3<u32>,
+    ),
+    NonNull<Node<Block>>,
+>;
+
+type ReplacedNodes<Block> = HashMap<
+    (
+        NonNull<Node<Block>>,
+        Region<i64>,
+        Option<math::Vec3<i64>>,
+    ),
+    NonNull<Node<Block>>,
+>;
+
+// where the blocks written by State::fill, State::paste and State::stamp come from
+enum RegionSource<Block: BlockType> {
+    Filled(LeafBlock<Block>),
+    Nodes {
+        root: NonNull<Node<Block>>,
+        // of root, in the destination's coordinates
+        origin: math::Vec3<i64>,
+        outside_block: Block,
+    },
+}
+
+impl<Block: BlockType> RegionSource<Block> {
+    // what the blocks copied to origin depend on, besides the region
+    fn get_source_position(&self, origin: math::Vec3<i64>) -> Option<math::Vec3<i64>> {
+        match *self {
+            RegionSource::Filled(_) => None,
+            RegionSource::Nodes {
+                origin: root_origin,
+                ..
+            } => Some(origin - root_origin),
+        }
+    }
+    // the blocks copied into a bounded world's region aren't boundary blocks anymore
+    fn without_boundary<Step: StepFn<Block>, H: BuildHasher>(
+        self,
+        world: &World<Block, Step, H>,
+    ) -> Self {
+        match self {
+            RegionSource::Nodes {
+                root,
+                origin,
+                outside_block,
+            } => {
+                let root = match world.shared_world_state.topology {
+                    Topology::Bounded(..) => {
+                        Node::without_boundary(root, world, &mut HashMap::new())
+                    }
+                    Topology::Infinite | Topology::Toroidal => root,
+                };
+                RegionSource::Nodes {
+                    root: root,
+                    origin: origin,
+                    outside_block: outside_block,
+                }
+            }
+            RegionSource::Filled(_) => self,
+        }
+    }
+    fn get_node<Step: StepFn<Block>, H: BuildHasher>(
+        &self,
+        origin: math::Vec3<i64>,
+        level: u32,
+        world: &World<Block, Step, H>,
+        shifted_nodes: &mut ShiftedNodes<Block>,
+    ) -> NonNull<Node<Block>> {
+        match *self {
+            RegionSource::Filled(block) => Node::get_filled_node(block, level as u8, world),
+            RegionSource::Nodes {
+                root,
+                origin: root_origin,
+                outside_block,
+            } => {
+                let size = get_size_from_level!(level) as i64;
+                let position = origin - root_origin;
+                let shift = position.map(|v| ((v % size) + size) % size);
+                let aligned_position = position - shift;
+                let mut nodes = [[[NonNull::dangling(); 2]; 2]; 2];
+                for x in 0..2 {
+                    for y in 0..2 {
+                        for z in 0..2 {
+                            nodes[x][y][z] = Node::get_aligned_node(
+                                root,
+                                aligned_position
+                                    + math::Vec3::new(x as i64, y as i64, z as i64)
+                                        * math::Vec3::splat(size),
+                                level,
+                                LeafBlock::new(outside_block),
+                                world,
+                            );
+                        }
+                    }
+                }
+                Node::get_shifted_node(nodes, shift.map(|v| v as u32), world, shifted_nodes)
+            }
+        }
+    }
+    fn get_block<H: BuildHasher>(
+        &self,
+        position: math::Vec3<i64>,
+        shared_world_state: &SharedWorldState<Block, H>,
+    ) -> LeafBlock<Block> {
+        match *self {
+            RegionSource::Filled(block) => block,
+            RegionSource::Nodes {
+                root,
+                origin,
+                outside_block,
+            } => {
+                let position = position - origin;
+                let root_size = get_size_from_level!(unsafe { root.as_ref() }.key.level()) as i64;
+                LeafBlock::new(
+                    if position.map(|v| v >= 0 && v < root_size).reduce(|a, b| a && b) {
+                        Node::get_block(root, position.map(|v| v as u32), shared_world_state)
+                    } else {
+                        outside_block
+                    },
+                )
+            }
+        }
+    }
+}
+
+// one of the 48 symmetries of a cube: axis a of the result comes from axis
+// permutation[a] of the original, then is reflected if reflection[a] is set
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct Transform {
+    permutation: [usize; 3],
+    reflection: [bool; 3],
+}
+
+impl Transform {
+    #[allow(dead_code)]
+    pub fn new(permutation: [usize; 3], reflection: [bool; 3]) -> Self {
+        let mut used_axes = [false; 3];
+        for &axis in &permutation {
+            assert!(axis < 3 && !used_axes[axis], "invalid permutation");
+            used_axes[axis] = true;
+        }
+        Self {
+            permutation: permutation,
+            reflection: reflection,
+        }
+    }
+    #[allow(dead_code)]
+    pub fn identity() -> Self {
+        Self::new([0, 1, 2], [false; 3])
+    }
+    #[allow(dead_code)]
+    pub fn all() -> Vec<Self> {
+        let permutations = [
+            [0, 1, 2],
+            [0, 2, 1],
+            [1, 0, 2],
+            [1, 2, 0],
+            [2, 0, 1],
+            [2, 1, 0],
+        ];
+        let mut retval = Vec::with_capacity(48);
+        for permutation in &permutations {
+            for reflection in 0..8 {
+                retval.push(Self::new(
+                    *permutation,
+                    [reflection & 1 != 0, reflection & 2 != 0, reflection & 4 != 0],
+                ));
+            }
+        }
+        retval
+    }
+    // reflects around the world's center, so blocks go from x to -1 - x
+    #[allow(dead_code)]
+    pub fn apply(self, position: math::Vec3<i32>) -> math::Vec3<i32> {
+        let mut retval = math::Vec3::splat(0);
+        for axis in 0..3 {
+            let v = position[self.permutation[axis]];
+            retval[axis] = if self.reflection[axis] { -1 - v } else { v };
+        }
+        retval
+    }
+    // for positions in the cube from 0 to size
+    #[allow(dead_code)]
+    pub fn apply_in_cube(self, position: math::Vec3<u32>, size: u32) -> math::Vec3<u32> {
+        let mut retval = math::Vec3::splat(0);
+        for axis in 0..3 {
+            let v = position[self.permutation[axis]];
+            retval[axis] = if self.reflection[axis] { size - 1 - v } else { v };
+        }
+        retval
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub struct StepReport {
+    // bounds of the non-empty blocks that were cut off at the edge of the world
+    pub truncated_region: Option<Region<i64>>,
+}
+
+impl StepReport {
+    fn merge(self, rhs: Self) -> Self {
+        Self {
+            truncated_region: match (self.truncated_region, rhs.truncated_region) {
+                (Some(a), Some(b)) => Some(a.union(b)),
+                (a, b) => a.or(b),
+            },
+        }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum Topology<Block: BlockType> {
+    Infinite,
+    // wraps around on the world size in each axis
+    Toroidal,
+    // everything outside of the region is the boundary block
+    Bounded(Region<i32>, Block),
+}
+
+impl<Block: BlockType> Topology<Block> {
+    fn outside_block(self) -> LeafBlock<Block> {
+        match self {
+            Topology::Bounded(_, boundary_block) => LeafBlock::boundary(boundary_block),
+            Topology::Infinite | Topology::Toroidal => Default::default(),
+        }
+    }
+}
+
+impl<Block: BlockType> Default for Topology<Block> {
+    fn default() -> Self {
+        Topology::Infinite
+    }
+}
+
 #[derive(Debug)]
 struct SharedWorldState<Block: BlockType, H: BuildHasher> {
-    nodes: UnsafeCell<HashTable<Node<Block>, H>>,
+    nodes: UnsafeCell<ConcurrentHashTable<Node<Block>, H>>,
     snapshots: Mutex<HashMap<NonNull<Node<Block>>, Arc<NonNull<Node<Block>>>>>,
+    // nodes created since the last collection, kept per shard of nodes so
+    // threads only contend for them when they contend for the shard anyway
+    young_nodes: Vec<Mutex<Vec<NonNull<Node<Block>>>>>,
+    // old nodes with next pointing to young nodes, per shard like young_nodes
+    remembered_nodes: Vec<Mutex<Vec<NonNull<Node<Block>>>>>,
+    topology: Topology<Block>,
 }
 
 impl<Block: BlockType, H: BuildHasher> PartialEq for SharedWorldState<Block, H> {
@@ -749,6 +1698,13 @@ impl<Block: BlockType, H: BuildHasher> PartialEq for SharedWorldState<Block, H>
     }
 }
 
+impl<Block: BlockType, H: BuildHasher> SharedWorldState<Block, H> {
+    // nodes are only removed by gc once nothing that's still used refers to them
+    fn get_node(&self, index: ValueIndex) -> NonNull<Node<Block>> {
+        NonNull::from(unsafe { (*self.nodes.get()).get_at(index) })
+    }
+}
+
 #[derive(Debug)]
 pub struct Substate<Block: BlockType, H: BuildHasher> {
     referenced_root: Arc<NonNull<Node<Block>>>,
@@ -770,11 +1726,11 @@ impl<Block: BlockType, H: BuildHasher> Clone for Substate<Block, H> {
 }
 
 impl<Block: BlockType, H: BuildHasher> Substate<Block, H> {
-    fn create_empty<Step: StepFn<Block>>(world: &mut World<Block, Step, H>) -> Self {
-        let mut root: NonNull<Node<Block>> = world.get(Default::default()).into();
-        while unsafe { root.as_ref() }.key.level() < MAX_LEVEL {
-            root = Node::expand_root(root, world);
-        }
+    fn create_filled<Step: StepFn<Block>>(
+        world: &mut World<Block, Step, H>,
+        block: LeafBlock<Block>,
+    ) -> Self {
+        let root = Node::get_filled_node(block, world.max_level, world);
         Self::create_independent_reference(world.shared_world_state.clone(), root)
     }
     fn create_independent_reference(
@@ -812,7 +1768,7 @@ impl<Block: BlockType, H: BuildHasher> Substate<Block, H> {
         if position.x >= size || position.y >= size || position.z >= size {
             Default::default()
         } else {
-            Node::get_block(self.root, position)
+            Node::get_block(self.root, position, &self.shared_world_state)
         }
     }
     pub fn get_cube_pow2(
@@ -830,7 +1786,14 @@ impl<Block: BlockType, H: BuildHasher> Substate<Block, H> {
         let size = self.size();
         assert!(cube_size <= size);
         if position.map(|v| v < size).reduce(|a, b| a && b) {
-            Node::get_cube_pow2(self.root, position, cube_size, stride, result);
+            Node::get_cube_pow2(
+                self.root,
+                position,
+                cube_size,
+                stride,
+                result,
+                &self.shared_world_state,
+            );
         } else {
             for rz in 0..cube_size {
                 for ry in 0..cube_size {
@@ -842,12 +1805,23 @@ impl<Block: BlockType, H: BuildHasher> Substate<Block, H> {
             }
         }
     }
+    // transforms within the cube from 0 to size
+    #[allow(dead_code)]
+    pub fn transformed<Step: StepFn<Block>>(
+        &self,
+        world: &mut World<Block, Step, H>,
+        transform: Transform,
+    ) -> Self {
+        assert!(self.shared_world_state == world.shared_world_state);
+        let root = Node::transform(self.root, transform, world, &mut HashMap::new());
+        Self::create_independent_reference(world.shared_world_state.clone(), root)
+    }
     pub fn get_substate(self, position: math::Vec3<u32>, size: u32) -> Self {
         assert!(size >= 2);
         assert!(size.is_power_of_two());
         assert!(size <= self.size());
         assert_eq!(position.map(|v| v % size), math::Vec3::splat(0));
-        let root = Node::get_child_node(self.root, position, size);
+        let root = Node::get_child_node(self.root, position, size, &self.shared_world_state);
         Self::create_dependent_reference(self, root)
     }
 }
@@ -868,61 +1842,260 @@ impl<Block: BlockType, H: BuildHasher> Hash for Substate<Block, H> {
 
 #[derive(Debug, Clone)]
 pub struct State<Block: BlockType, H: BuildHasher> {
+    // only as big as it needs to be to hold everything that isn't empty
     state: Substate<Block, H>,
-    empty_state: Substate<Block, H>,
+    // state expanded to the whole world, so get_substate doesn't have to create
+    // nodes, since it's called without the world
+    expanded_state: Substate<Block, H>,
+    outside_state: Substate<Block, H>,
 }
 
-const MAX_LEVEL: u32 = 20;
-const MAX_LEVEL_SIZE: u32 = get_size_from_level!(MAX_LEVEL);
+pub const DEFAULT_MAX_LEVEL: u8 = 20;
+// positions are i32, so the world can't be bigger than 2^31 blocks across
+pub const MAX_SUPPORTED_LEVEL: u8 = 30;
 
 impl<Block: BlockType, H: BuildHasher> State<Block, H> {
-    const OFFSET: u32 = MAX_LEVEL_SIZE / 2;
     fn new(
         shared_world_state: Arc<SharedWorldState<Block, H>>,
         root: NonNull<Node<Block>>,
-        empty_state: Substate<Block, H>,
+        expanded_root: NonNull<Node<Block>>,
+        outside_state: Substate<Block, H>,
     ) -> Self {
-        assert_eq!(unsafe { root.as_ref() }.key.level(), MAX_LEVEL);
+        assert_eq!(
+            unsafe { expanded_root.as_ref() }.key.level(),
+            unsafe { outside_state.root.as_ref() }.key.level()
+        );
         Self {
-            state: Substate::create_independent_reference(shared_world_state, root),
-            empty_state: empty_state,
+            state: Substate::create_independent_reference(shared_world_state.clone(), root),
+            expanded_state: Substate::create_independent_reference(
+                shared_world_state,
+                expanded_root,
+            ),
+            outside_state: outside_state,
+        }
+    }
+    fn get_min_level<Step: StepFn<Block>>(world: &World<Block, Step, H>) -> u32 {
+        match world.shared_world_state.topology {
+            Topology::Infinite => 0,
+            // stepping wraps around at the edge of the root, so the root has
+            // to be the whole world
+            Topology::Toroidal => world.max_level as u32,
+            // the boundary blocks inside the root are marked in its leaves,
+            // so the root has to contain all of the region
+            Topology::Bounded(region, _) => {
+                let mut level = 0;
+                while region.min.map(|v| v < -(1i32 << level)).reduce(|a, b| a || b)
+                    || region.max.map(|v| v > 1i32 << level).reduce(|a, b| a || b)
+                {
+                    level += 1;
+                }
+                level
+            }
         }
     }
     fn new_from_world<Step: StepFn<Block>>(
         world: &mut World<Block, Step, H>,
-        mut root: NonNull<Node<Block>>,
+        root: NonNull<Node<Block>>,
     ) -> Self {
-        while unsafe { root.as_ref() }.key.level() < MAX_LEVEL {
+        let max_level = world.max_level as u32;
+        let min_level = Self::get_min_level(world);
+        assert!(unsafe { root.as_ref() }.key.level() <= max_level);
+        // always use the smallest root so equal states have the same root
+        let outside_block = world.shared_world_state.topology.outside_block();
+        let root = Node::expand_root_to(min_level, Default::default(), root, world);
+        let root = Node::shrink_root(min_level, outside_block, root, world);
+        let expanded_root = Node::expand_root_to(max_level, outside_block, root, world);
+        let outside_state = Substate::create_filled(world, outside_block);
+        Self::new(
+            world.shared_world_state.clone(),
+            root,
+            expanded_root,
+            outside_state,
+        )
+    }
+    pub fn create_empty<Step: StepFn<Block>>(world: &mut World<Block, Step, H>) -> Self {
+        let root = Node::get_empty_node(0, world);
+        let root = Self::fill_boundary(world, root);
+        Self::new_from_world(world, root)
+    }
+    // fills everything outside of a bounded world's region with the boundary block,
+    // and nothing inside of it, expanding the root to contain the region first
+    fn fill_boundary<Step: StepFn<Block>>(
+        world: &World<Block, Step, H>,
+        root: NonNull<Node<Block>>,
+    ) -> NonNull<Node<Block>> {
+        let (region, boundary_block) = match world.shared_world_state.topology {
+            Topology::Bounded(region, boundary_block) => (region, boundary_block),
+            Topology::Infinite | Topology::Toroidal => return root,
+        };
+        let min_level = Self::get_min_level(world);
+        let root = Node::without_boundary(root, world, &mut HashMap::new());
+        let root = Node::expand_root_to(min_level, Default::default(), root, world);
+        let half_size = 1i64 << unsafe { root.as_ref() }.key.level();
+        let min = region.min.map(|v| v as i64);
+        let max = region.max.map(|v| v as i64);
+        let mut outside_regions = Vec::new();
+        // split the outside into slabs, taking one axis at a time
+        let mut inner = Region::new(math::Vec3::splat(-half_size), math::Vec3::splat(half_size));
+        for axis in 0..3 {
+            let mut below = inner;
+            below.max[axis] = min[axis];
+            let mut above = inner;
+            above.min[axis] = max[axis];
+            outside_regions.push(below);
+            outside_regions.push(above);
+            inner.min[axis] = min[axis];
+            inner.max[axis] = max[axis];
+        }
+        outside_regions.into_iter().fold(root, |root, outside_region| {
+            Node::replace_region(
+                root,
+                math::Vec3::splat(-half_size),
+                &outside_region,
+                &RegionSource::Filled(LeafBlock::boundary(boundary_block)),
+                world,
+                &mut HashMap::new(),
+                &mut HashMap::new(),
+            )
+        })
+    }
+    // expands the root until it contains region, or until it's as big as the world
+    fn get_root_containing<Step: StepFn<Block>>(
+        &self,
+        world: &World<Block, Step, H>,
+        region: Region<i64>,
+    ) -> NonNull<Node<Block>> {
+        let max_level = world.max_level as u32;
+        let mut root = self.state.root;
+        loop {
+            let level = unsafe { root.as_ref() }.key.level();
+            let half_size = 1i64 << level;
+            let root_region =
+                Region::new(math::Vec3::splat(-half_size), math::Vec3::splat(half_size));
+            if level >= max_level || root_region.contains_region(&region) {
+                return root;
+            }
             root = Node::expand_root(root, world);
         }
-        let empty_state = Substate::create_empty(world);
-        Self::new(world.shared_world_state.clone(), root, empty_state)
     }
-    pub fn create_empty<Step: StepFn<Block>>(world: &mut World<Block, Step, H>) -> Self {
-        let empty_state = Substate::create_empty(world);
-        Self {
-            state: empty_state.clone(),
-            empty_state: empty_state,
+    fn size(&self) -> u32 {
+        self.state.size()
+    }
+    fn offset(&self) -> u32 {
+        self.size() / 2
+    }
+    fn get_root_position(
+        &self,
+        root: NonNull<Node<Block>>,
+        position: math::Vec3<i32>,
+    ) -> math::Vec3<u32> {
+        let size = get_size_from_level!(unsafe { root.as_ref() }.key.level());
+        let position = position.map(|v| (v as u32).wrapping_add(size / 2));
+        match self.state.shared_world_state.topology {
+            Topology::Infinite | Topology::Bounded(..) => position,
+            Topology::Toroidal => position.map(|v| v % size),
         }
     }
     #[allow(dead_code)]
+    pub fn level(&self) -> u32 {
+        unsafe { self.state.root.as_ref() }.key.level()
+    }
+    // the number of non-default blocks
+    #[allow(dead_code)]
+    pub fn population(&self) -> u128 {
+        self.count_if(|block| block != Default::default())
+    }
+    // counts over the whole world, not just the part the root covers
+    #[allow(dead_code)]
+    pub fn count_if<F: Fn(Block) -> bool>(&self, predicate: F) -> u128 {
+        Node::count_blocks_if(
+            self.expanded_state.root,
+            &predicate,
+            &mut HashMap::new(),
+            &self.state.shared_world_state,
+        )
+    }
+    // looks up the empty nodes without creating them, since they're hash-consed
+    // any empty subtree has to be one of them
+    fn find_empty_nodes(&self) -> Vec<Option<NonNull<Node<Block>>>> {
+        let nodes = unsafe { &*self.state.shared_world_state.nodes.get() };
+        let mut retval = Vec::new();
+        let mut key = NodeKey::Leaf(Default::default());
+        for level in 0..=self.level() {
+            let node = Node {
+                key: key,
+                ..Default::default()
+            };
+            let empty_node = nodes.lock_shard(&node).get(&node).map(NonNull::from);
+            retval.push(empty_node);
+            match empty_node {
+                Some(empty_node) => {
+                    key = NodeKey::Nonleaf(NodeKeyNonleaf {
+                        children: [[[Node::get_index(empty_node); 2]; 2]; 2],
+                        children_level: level as u8,
+                    })
+                }
+                None => break,
+            }
+        }
+        retval.resize(self.level() as usize + 1, None);
+        retval
+    }
+    // blocks outside of the world aren't included
+    #[allow(dead_code)]
+    pub fn iter_non_default(&self, region: Region<i32>) -> NonDefaultBlocks<Block, H> {
+        let offset = self.offset() as i64;
+        let root_region = Region::new(math::Vec3::splat(0), math::Vec3::splat(self.size() as i64));
+        let region = Region::new(
+            region.min.map(|v| v as i64 + offset),
+            region.max.map(|v| v as i64 + offset),
+        ).intersection(root_region)
+        .map(|region| Region::new(region.min.map(|v| v as u32), region.max.map(|v| v as u32)));
+        NonDefaultBlocks {
+            state: self,
+            region: region,
+            empty_nodes: self.find_empty_nodes(),
+            stack: vec![(self.state.root, math::Vec3::splat(0))],
+            leaf_blocks: VecDeque::new(),
+        }
+    }
+    // the smallest region containing all the non-default blocks, None if there aren't any
+    #[allow(dead_code)]
+    pub fn bounding_box(&self) -> Option<Region<i32>> {
+        let offset = self.offset();
+        let bounding_box = Node::get_bounding_box(
+            self.state.root,
+            &mut HashMap::new(),
+            &self.state.shared_world_state,
+        );
+        bounding_box.map(|bounding_box| {
+            Region::new(
+                bounding_box.min.map(|v| v.wrapping_sub(offset) as i32),
+                bounding_box.max.map(|v| v.wrapping_sub(offset) as i32),
+            )
+        })
+    }
+    #[allow(dead_code)]
     pub fn from<Step: StepFn<Block>>(
         state: &SerializedState<Block>,
         world: &mut World<Block, Step, H>,
-    ) -> Self {
+    ) -> Result<Self, &'static str> {
+        if state.level() > world.max_level as u32 {
+            return Err("serialized state is too big for the world");
+        }
         let mut nodes: Vec<NonNull<Node<Block>>> = Vec::with_capacity(state.0.len());
         for i in 0..state.0.len() {
             let key = match &state.0[i] {
-                &SerializedNode::Leaf(key) => NodeKey::Leaf(key),
+                &SerializedNode::Leaf(key) => NodeKey::Leaf(NodeKeyLeaf::new(key)),
                 SerializedNode::Nonleaf(key) => unsafe {
                     let mut retval_key = NodeKeyNonleaf {
-                        children: [[[NonNull::dangling(); 2]; 2]; 2],
+                        children: [[[Default::default(); 2]; 2]; 2],
                         children_level: nodes[key[0][0][0].0 as usize].as_ref().key.level() as u8,
                     };
                     for (child, key) in retval_key.children.iter_mut().zip(key.iter()) {
                         for (child, key) in child.iter_mut().zip(key.iter()) {
                             for (child, key) in child.iter_mut().zip(key.iter()) {
-                                *child = nodes[key.0 as usize];
+                                *child = Node::get_index(nodes[key.0 as usize]);
                             }
                         }
                     }
@@ -931,20 +2104,75 @@ impl<Block: BlockType, H: BuildHasher> State<Block, H> {
             };
             nodes.push(world.get(key).into());
         }
-        Self::new_from_world(world, *nodes.last().unwrap())
+        let root = Self::fill_boundary(world, *nodes.last().unwrap());
+        Ok(Self::new_from_world(world, root))
+    }
+    // base must be the state the delta was created from
+    #[allow(dead_code)]
+    pub fn from_delta<Step: StepFn<Block>>(
+        base: &State<Block, H>,
+        delta: &SerializedDelta<Block>,
+        world: &mut World<Block, Step, H>,
+    ) -> Result<Self, &'static str> {
+        assert!(base.state.shared_world_state == world.shared_world_state);
+        let (mut nodes, nodes_map) = list_nodes(base.state.root, &world.shared_world_state);
+        if nodes.len() as u64 != delta.base_node_count as u64
+            || fingerprint_nodes(&nodes, &nodes_map, &world.shared_world_state)
+                != delta.base_fingerprint
+        {
+            return Err("base state doesn't match delta");
+        }
+        for node in &delta.nodes {
+            let key = match node {
+                &SerializedNode::Leaf(key) => NodeKey::Leaf(NodeKeyLeaf::new(key)),
+                SerializedNode::Nonleaf(key) => {
+                    let mut retval_key = NodeKeyNonleaf {
+                        children: [[[Default::default(); 2]; 2]; 2],
+                        children_level: 0,
+                    };
+                    let mut children_level = None;
+                    for (child, key) in retval_key.children.iter_mut().zip(key.iter()) {
+                        for (child, key) in child.iter_mut().zip(key.iter()) {
+                            for (child, key) in child.iter_mut().zip(key.iter()) {
+                                let node = nodes[key.0 as usize];
+                                *child = Node::get_index(node);
+                                let level = unsafe { node.as_ref() }.key.level();
+                                if children_level.is_none() {
+                                    children_level = Some(level);
+                                } else if children_level != Some(level) {
+                                    return Err("node children at different levels");
+                                }
+                            }
+                        }
+                    }
+                    retval_key.children_level = children_level.unwrap() as u8;
+                    if retval_key.children_level >= MAX_SUPPORTED_LEVEL {
+                        return Err("node nested too deeply");
+                    }
+                    NodeKey::Nonleaf(retval_key)
+                }
+            };
+            nodes.push(world.get(key).into());
+        }
+        let root = nodes[delta.root.0 as usize];
+        if unsafe { root.as_ref() }.key.level() > world.max_level as u32 {
+            return Err("delta root is too big for the world");
+        }
+        let root = Self::fill_boundary(world, root);
+        Ok(Self::new_from_world(world, root))
     }
     pub fn get_substate(&self, position: math::Vec3<i32>, size: u32) -> Substate<Block, H> {
         assert!(size >= 2);
         assert!(size.is_power_of_two());
-        assert!(size <= MAX_LEVEL_SIZE);
-        let position = position.map(|v| (v as u32).wrapping_add(Self::OFFSET));
+        assert!(size <= self.expanded_state.size());
+        let position = self.get_root_position(self.expanded_state.root, position);
         assert_eq!(position.map(|v| v % size), math::Vec3::splat(0));
-        if position.map(|v| v >= MAX_LEVEL_SIZE).reduce(|a, b| a || b) {
-            self.empty_state
+        if position.map(|v| v >= self.expanded_state.size()).reduce(|a, b| a || b) {
+            self.outside_state
                 .clone()
                 .get_substate(math::Vec3::splat(0), size)
         } else {
-            self.state.clone().get_substate(position, size)
+            self.expanded_state.clone().get_substate(position, size)
         }
     }
     fn set_helper<Step: StepFn<Block>>(
@@ -953,12 +2181,19 @@ impl<Block: BlockType, H: BuildHasher> State<Block, H> {
         position: math::Vec3<i32>,
         block: Block,
     ) -> Self {
-        let position = position.map(|v| (v as u32).wrapping_add(Self::OFFSET));
-        assert_eq!(
-            position.map(|v| v < MAX_LEVEL_SIZE),
-            math::Vec3::splat(true)
-        );
-        let root = Node::set_block_without_expanding(self.state.root, position, block, world);
+        if world.shared_world_state.topology != Topology::Toroidal {
+            let region = Region::new(position, position + math::Vec3::splat(1));
+            assert!(
+                world.region().contains_region(&region),
+                "position is outside of the world"
+            );
+        }
+        let min = position.map(|v| v as i64);
+        let root = self.get_root_containing(world, Region::new(min, min + math::Vec3::splat(1)));
+        let size = get_size_from_level!(unsafe { root.as_ref() }.key.level());
+        let position = self.get_root_position(root, position);
+        assert_eq!(position.map(|v| v < size), math::Vec3::splat(true));
+        let root = Node::set_block_without_expanding(root, position, block, world);
         State::new_from_world(world, root)
     }
     pub fn set<Step: StepFn<Block>>(
@@ -970,6 +2205,35 @@ impl<Block: BlockType, H: BuildHasher> State<Block, H> {
         assert!(self.state.shared_world_state == world.shared_world_state);
         *self = self.set_helper(world, position, block);
     }
+    fn replace_region_helper<Step: StepFn<Block>>(
+        &self,
+        world: &mut World<Block, Step, H>,
+        region: Region<i64>,
+        source: RegionSource<Block>,
+    ) -> Self {
+        let world_region = world.region();
+        let world_region = Region::new(
+            world_region.min.map(|v| v as i64),
+            world_region.max.map(|v| v as i64),
+        );
+        // keeps the boundary of bounded worlds intact
+        let region = match region.intersection(world_region) {
+            Some(region) => region,
+            None => return State::new_from_world(world, self.state.root),
+        };
+        let root = self.get_root_containing(world, region);
+        let level = unsafe { root.as_ref() }.key.level();
+        let root = Node::replace_region(
+            root,
+            math::Vec3::splat(-(1i64 << level)),
+            &region,
+            &source.without_boundary(world),
+            world,
+            &mut HashMap::new(),
+            &mut HashMap::new(),
+        );
+        State::new_from_world(world, root)
+    }
     fn set_cube_pow2_helper<Step: StepFn<Block>, F: FnMut(math::Vec3<u32>, Block) -> Block>(
         &self,
         world: &mut World<Block, Step, H>,
@@ -978,11 +2242,36 @@ impl<Block: BlockType, H: BuildHasher> State<Block, H> {
         mut f: F,
     ) -> Self {
         assert!(cube_size.is_power_of_two());
-        assert!(cube_size <= MAX_LEVEL_SIZE);
-        let position = position.map(|v| (v as u32).wrapping_add(Self::OFFSET));
+        assert!(cube_size <= get_size_from_level!(world.max_level));
+        let min = position.map(|v| v as i64);
+        let region = Region::new(min, min + math::Vec3::splat(cube_size as i64));
+        let root = self.get_root_containing(world, region);
+        let position = self.get_root_position(root, position);
         assert_eq!(position.map(|v| v % cube_size), math::Vec3::splat(0));
+        let bounded_region = match world.shared_world_state.topology {
+            Topology::Bounded(region, _) => Some(region),
+            Topology::Infinite | Topology::Toroidal => None,
+        };
+        // leaves the boundary of bounded worlds alone
+        let mut f = |cube_position: math::Vec3<u32>, block: LeafBlock<Block>| match bounded_region {
+            Some(bounded_region) => {
+                let position = min + cube_position.map(|v| v as i64);
+                if bounded_region
+                    .min
+                    .zip(bounded_region.max)
+                    .zip(position)
+                    .map(|((min, max), v)| v >= min as i64 && v < max as i64)
+                    .reduce(|a, b| a && b)
+                {
+                    LeafBlock::new(f(cube_position, block.block))
+                } else {
+                    block
+                }
+            }
+            None => LeafBlock::new(f(cube_position, block.block)),
+        };
         let root = Node::set_cube_pow2_without_expanding(
-            self.state.root,
+            root,
             position,
             cube_size,
             math::Vec3::splat(0),
@@ -991,6 +2280,76 @@ impl<Block: BlockType, H: BuildHasher> State<Block, H> {
         );
         State::new_from_world(world, root)
     }
+    #[allow(dead_code)]
+    pub fn transformed<Step: StepFn<Block>>(
+        &self,
+        world: &mut World<Block, Step, H>,
+        transform: Transform,
+    ) -> Self {
+        assert!(self.state.shared_world_state == world.shared_world_state);
+        let root = Node::transform(self.state.root, transform, world, &mut HashMap::new());
+        // bounded regions aren't symmetric, so the boundary has to be put back,
+        // and taken out of the blocks that were moved inside of the region
+        let root = Self::fill_boundary(world, root);
+        State::new_from_world(world, root)
+    }
+    // regions don't wrap around toroidal worlds
+    #[allow(dead_code)]
+    pub fn fill<Step: StepFn<Block>>(
+        &mut self,
+        world: &mut World<Block, Step, H>,
+        region: Region<i32>,
+        block: Block,
+    ) {
+        assert!(self.state.shared_world_state == world.shared_world_state);
+        let region = Region::new(region.min.map(|v| v as i64), region.max.map(|v| v as i64));
+        *self = self.replace_region_helper(
+            world,
+            region,
+            RegionSource::Filled(LeafBlock::new(block)),
+        );
+    }
+    // copies source_region from source so that source_region.min ends up at position
+    #[allow(dead_code)]
+    pub fn paste<Step: StepFn<Block>>(
+        &mut self,
+        world: &mut World<Block, Step, H>,
+        source: &State<Block, H>,
+        source_region: Region<i32>,
+        position: math::Vec3<i32>,
+    ) {
+        assert!(self.state.shared_world_state == world.shared_world_state);
+        assert!(source.state.shared_world_state == world.shared_world_state);
+        let position = position.map(|v| v as i64);
+        let source_min = source_region.min.map(|v| v as i64);
+        let source_max = source_region.max.map(|v| v as i64);
+        let region = Region::new(position, position + source_max - source_min);
+        let source = RegionSource::Nodes {
+            root: source.state.root,
+            origin: position - source_min - math::Vec3::splat(source.offset() as i64),
+            outside_block: world.shared_world_state.topology.outside_block().block,
+        };
+        *self = self.replace_region_helper(world, region, source);
+    }
+    // replaces the cube starting at position with substate
+    #[allow(dead_code)]
+    pub fn stamp<Step: StepFn<Block>>(
+        &mut self,
+        world: &mut World<Block, Step, H>,
+        substate: &Substate<Block, H>,
+        position: math::Vec3<i32>,
+    ) {
+        assert!(self.state.shared_world_state == world.shared_world_state);
+        assert!(substate.shared_world_state == world.shared_world_state);
+        let position = position.map(|v| v as i64);
+        let region = Region::new(position, position + math::Vec3::splat(substate.size() as i64));
+        let source = RegionSource::Nodes {
+            root: substate.root,
+            origin: position,
+            outside_block: Default::default(),
+        };
+        *self = self.replace_region_helper(world, region, source);
+    }
     pub fn set_cube_pow2<Step: StepFn<Block>, F: FnMut(math::Vec3<u32>, Block) -> Block>(
         &mut self,
         world: &mut World<Block, Step, H>,
@@ -1001,12 +2360,32 @@ impl<Block: BlockType, H: BuildHasher> State<Block, H> {
         assert!(self.state.shared_world_state == world.shared_world_state);
         *self = self.set_cube_pow2_helper(world, position, cube_size, f);
     }
-    fn step_helper<Step: StepFn<Block>>(
-        &self,
+    fn step_root<
+        Step: StepFn<Block>,
+        F: Fn(NonNull<Node<Block>>, u32, &World<Block, Step, H>) -> NonNull<Node<Block>>,
+    >(
+        mut root: NonNull<Node<Block>>,
         world: &mut World<Block, Step, H>,
         log2_generation_count: u32,
-    ) -> Self {
-        let mut root = self.state.root;
+        compute_next: F,
+    ) -> (NonNull<Node<Block>>, StepReport) {
+        world.memo_clock = world.memo_clock.wrapping_add(1);
+        let topology = world.shared_world_state.topology;
+        if topology == Topology::Toroidal {
+            // tile the root so the center of the next generation starts on a
+            // period boundary, then pick out one period from the result
+            let level = unsafe { root.as_ref() }.key.level();
+            let tiled_level = cmp::max(level + 2, log2_generation_count + 1);
+            root = Node::tile_root_to(tiled_level, root, world);
+            root = compute_next(root, log2_generation_count, world);
+            while unsafe { root.as_ref() }.key.level() > level {
+                root = world.get_node(unsafe { root.as_ref() }.key.as_nonleaf().children[0][0][0]);
+            }
+            return (root, Default::default());
+        }
+        // expand at least once more than needed, so the result covers all
+        // of the blocks that can move outside the world
+        root = Node::expand_root_with(root, topology.outside_block(), world);
         loop {
             let log2_of_max_generation_step: Option<u32> =
                 unsafe { root.as_ref() }.get_log2_of_max_generation_step();
@@ -1016,22 +2395,192 @@ impl<Block: BlockType, H: BuildHasher> State<Block, H> {
             {
                 break;
             }
-            root = Node::expand_root(root, world);
+            root = Node::expand_root_with(root, topology.outside_block(), world);
         }
-        root = Node::expand_root(root, world);
-        root = Node::compute_next(root, log2_generation_count, world);
-        if unsafe { root.as_ref() }.key.level() > MAX_LEVEL {
-            root = Node::truncate_root_to(MAX_LEVEL, root, world);
+        root = Node::expand_root_with(root, topology.outside_block(), world);
+        root = compute_next(root, log2_generation_count, world);
+        let level = unsafe { root.as_ref() }.key.level();
+        let max_level = world.max_level as u32;
+        let mut report = StepReport::default();
+        if level <= max_level {
+            return (root, report);
         }
-        State::new_from_world(world, root)
+        if let Topology::Infinite = topology {
+            // the bounded topology only ever has boundary blocks outside
+            let empty_nodes: Vec<_> = (0..=level as u8)
+                .map(|level| Node::get_empty_node(level, world))
+                .collect();
+            let half_size = 1i64 << max_level;
+            Node::get_region_outside(
+                root,
+                math::Vec3::splat(if level < 63 { -1i64 << level } else { i64::min_value() }),
+                &Region::new(math::Vec3::splat(-half_size), math::Vec3::splat(half_size)),
+                &empty_nodes,
+                &mut report.truncated_region,
+                &world.shared_world_state,
+            );
+        }
+        root = Node::truncate_root_to(max_level, root, world);
+        (root, report)
+    }
+    fn step_helper<
+        Step: StepFn<Block>,
+        F: Fn(NonNull<Node<Block>>, u32, &World<Block, Step, H>) -> NonNull<Node<Block>>,
+    >(
+        &self,
+        world: &mut World<Block, Step, H>,
+        log2_generation_count: u32,
+        compute_next: F,
+    ) -> (Self, StepReport) {
+        let (root, report) =
+            Self::step_root(self.state.root, world, log2_generation_count, compute_next);
+        (State::new_from_world(world, root), report)
     }
     pub fn step<Step: StepFn<Block>>(
         &mut self,
         world: &mut World<Block, Step, H>,
         log2_generation_count: u32,
-    ) {
+    ) -> StepReport {
+        assert!(self.state.shared_world_state == world.shared_world_state);
+        let (state, report) = self.step_helper(world, log2_generation_count, Node::compute_next);
+        *self = state;
+        report
+    }
+    // same as step, except large nodes are evaluated on the rayon thread pool
+    pub fn step_parallel<Step: StepFn<Block> + Sync>(
+        &mut self,
+        world: &mut World<Block, Step, H>,
+        log2_generation_count: u32,
+    ) -> StepReport
+    where
+        Block: Send + Sync,
+        H: Sync,
+    {
+        assert!(self.state.shared_world_state == world.shared_world_state);
+        let (state, report) =
+            self.step_helper(world, log2_generation_count, Node::compute_next_parallel);
+        *self = state;
+        report
+    }
+    fn step_by_helper<
+        Step: StepFn<Block>,
+        F: Fn(NonNull<Node<Block>>, u32, &World<Block, Step, H>) -> NonNull<Node<Block>>,
+    >(
+        &self,
+        world: &mut World<Block, Step, H>,
+        mut generations: u64,
+        compute_next: F,
+    ) -> (Self, StepReport) {
+        let mut root = self.state.root;
+        let mut report = StepReport::default();
+        while generations != 0 {
+            let (next_root, step_report) =
+                Self::step_root(root, world, generations.trailing_zeros(), &compute_next);
+            root = next_root;
+            report = report.merge(step_report);
+            generations &= generations - 1;
+        }
+        (State::new_from_world(world, root), report)
+    }
+    #[allow(dead_code)]
+    pub fn step_by<Step: StepFn<Block>>(
+        &mut self,
+        world: &mut World<Block, Step, H>,
+        generations: u64,
+    ) -> StepReport {
+        assert!(self.state.shared_world_state == world.shared_world_state);
+        let (state, report) = self.step_by_helper(world, generations, Node::compute_next);
+        *self = state;
+        report
+    }
+    #[allow(dead_code)]
+    pub fn step_by_parallel<Step: StepFn<Block> + Sync>(
+        &mut self,
+        world: &mut World<Block, Step, H>,
+        generations: u64,
+    ) -> StepReport
+    where
+        Block: Send + Sync,
+        H: Sync,
+    {
         assert!(self.state.shared_world_state == world.shared_world_state);
-        *self = self.step_helper(world, log2_generation_count);
+        let (state, report) =
+            self.step_by_helper(world, generations, Node::compute_next_parallel);
+        *self = state;
+        report
+    }
+}
+
+pub struct NonDefaultBlocks<'a, Block: BlockType + 'a, H: BuildHasher + 'a> {
+    state: &'a State<Block, H>,
+    // in root coordinates
+    region: Option<Region<u32>>,
+    // indexed by level, None if there isn't an empty node at that level
+    empty_nodes: Vec<Option<NonNull<Node<Block>>>>,
+    // nodes left to visit, with their origins in root coordinates
+    stack: Vec<(NonNull<Node<Block>>, math::Vec3<u32>)>,
+    leaf_blocks: VecDeque<(math::Vec3<i32>, Block)>,
+}
+
+impl<'a, Block: BlockType + 'a, H: BuildHasher + 'a> Iterator for NonDefaultBlocks<'a, Block, H> {
+    type Item = (math::Vec3<i32>, Block);
+    fn next(&mut self) -> Option<(math::Vec3<i32>, Block)> {
+        let region = self.region?;
+        loop {
+            if let Some(retval) = self.leaf_blocks.pop_front() {
+                return Some(retval);
+            }
+            let (node, origin) = self.stack.pop()?;
+            let level = unsafe { node.as_ref() }.key.level();
+            if Some(node) == self.empty_nodes[level as usize] {
+                continue;
+            }
+            let size = get_size_from_level!(level);
+            let node_region = Region::new(origin, origin + math::Vec3::splat(size));
+            if region.intersection(node_region).is_none() {
+                continue;
+            }
+            match &unsafe { node.as_ref() }.key {
+                NodeKey::Leaf(key) => {
+                    for x in 0..2 {
+                        for y in 0..2 {
+                            for z in 0..2 {
+                                let block = key.blocks[x][y][z];
+                                let position =
+                                    origin + math::Vec3::new(x as u32, y as u32, z as u32);
+                                let block_region =
+                                    Region::new(position, position + math::Vec3::splat(1));
+                                if block == Default::default()
+                                    || !region.contains_region(&block_region)
+                                {
+                                    continue;
+                                }
+                                let offset = self.state.offset();
+                                self.leaf_blocks.push_back((
+                                    position.map(|v| v.wrapping_sub(offset) as i32),
+                                    block,
+                                ));
+                            }
+                        }
+                    }
+                }
+                NodeKey::Nonleaf(key) => {
+                    // push in reverse so the children are visited in order
+                    for x in (0..2).rev() {
+                        for y in (0..2).rev() {
+                            for z in (0..2).rev() {
+                                let child_origin = origin
+                                    + math::Vec3::new(x as u32, y as u32, z as u32)
+                                        * math::Vec3::splat(size / 2);
+                                let child = key.children[x][y][z];
+                                let child = self.state.state.shared_world_state.get_node(child);
+                                self.stack.push((child, child_origin));
+                            }
+                        }
+                    }
+                }
+            }
+        }
     }
 }
 
@@ -1053,11 +2602,47 @@ impl<Block: BlockType, H: BuildHasher> Hash for State<Block, H> {
 pub struct World<Block: BlockType, Step: StepFn<Block>, H: BuildHasher> {
     shared_world_state: Arc<SharedWorldState<Block, H>>,
     step: Step,
+    max_level: u8,
+    old_node_count: usize,
+    // do a full collection once there are this many old nodes
+    full_gc_node_count: usize,
+    memory_budget: Option<usize>,
+    // counts steps, used to find the least recently used results
+    memo_clock: usize,
+    memo_hit_count: AtomicUsize,
+    memo_miss_count: AtomicUsize,
+}
+
+const MIN_FULL_GC_NODE_COUNT: usize = 1 << 16;
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub struct GcStats {
+    // false for collections that only looked at nodes created since the last collection
+    pub full: bool,
+    pub initial_node_count: usize,
+    pub final_node_count: usize,
+    pub scanned_node_count: usize,
+    // cached step results dropped to get under the memory budget
+    pub evicted_result_count: usize,
+    pub duration: time::Duration,
+}
+
+#[derive(Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub struct WorldStats {
+    pub node_count: usize,
+    // indexed by level, so the first entry is the number of leaves
+    pub level_node_counts: Vec<usize>,
+    pub leaf_node_count: usize,
+    // cached step results used and computed since the world was created
+    pub memo_hit_count: usize,
+    pub memo_miss_count: usize,
+    pub snapshot_count: usize,
+    pub memory_usage: usize,
 }
 
 impl<Block: BlockType, Step: StepFn<Block>, H: BuildHasher> World<Block, Step, H> {
-    fn get(&mut self, key: NodeKey<Block>) -> &mut Node<Block> {
-        debug_assert!(if !key.is_valid() {
+    fn get(&self, key: NodeKey<Block>) -> &Node<Block> {
+        debug_assert!(if !key.is_valid(&self.shared_world_state) {
             let mut key = key;
             loop {
                 println!("{:#?}", key);
@@ -1066,12 +2651,12 @@ impl<Block: BlockType, Step: StepFn<Block>, H: BuildHasher> World<Block, Step, H
                     NodeKey::Nonleaf(NodeKeyNonleaf { children, .. }) => {
                         for v in &children {
                             for v in v {
-                                for v in v {
-                                    println!("{:?}", unsafe { v.as_ref() });
+                                for &v in v {
+                                    println!("{:?}", unsafe { self.get_node(v).as_ref() });
                                 }
                             }
                         }
-                        key = unsafe { children[0][0][0].as_ref().key };
+                        key = unsafe { self.get_node(children[0][0][0]).as_ref().key };
                     }
                 }
             }
@@ -1079,34 +2664,180 @@ impl<Block: BlockType, Step: StepFn<Block>, H: BuildHasher> World<Block, Step, H
         } else {
             true
         });
-        let nodes = unsafe { &mut *self.shared_world_state.nodes.get() };
-        let (_, retval) = nodes.insert(Node {
+        let node = Node {
             key: key,
             ..Default::default()
-        });
-        retval
+        };
+        let nodes = unsafe { &*self.shared_world_state.nodes.get() };
+        let shard_index = nodes.get_shard_index(&node);
+        let mut shard = nodes.lock_shard_at(shard_index);
+        let (inserted, index, retval) = nodes.insert_into_shard(&mut shard, shard_index, node);
+        if inserted {
+            retval.index = index;
+        }
+        let retval = NonNull::from(retval);
+        if inserted {
+            // only locked while holding the shard's lock or &mut self, so this never waits
+            self.shared_world_state.young_nodes[shard_index]
+                .lock()
+                .unwrap()
+                .push(retval);
+        }
+        // nodes don't move once they're in the table and are only removed
+        // by gc, which needs &mut self
+        unsafe { &*retval.as_ptr() }
+    }
+    fn get_node(&self, index: ValueIndex) -> NonNull<Node<Block>> {
+        self.shared_world_state.get_node(index)
     }
-    pub fn new(step: Step, build_hasher: H) -> World<Block, Step, H> {
+    pub fn new(step: Step, build_hasher: H) -> World<Block, Step, H>
+    where
+        H: Clone,
+    {
+        Self::with_max_level(step, build_hasher, DEFAULT_MAX_LEVEL)
+    }
+    pub fn with_max_level(step: Step, build_hasher: H, max_level: u8) -> World<Block, Step, H>
+    where
+        H: Clone,
+    {
+        Self::with_topology(step, build_hasher, max_level, Topology::Infinite)
+    }
+    pub fn with_topology(
+        step: Step,
+        build_hasher: H,
+        max_level: u8,
+        topology: Topology<Block>,
+    ) -> World<Block, Step, H>
+    where
+        H: Clone,
+    {
+        assert!(max_level <= MAX_SUPPORTED_LEVEL);
+        if let Topology::Bounded(region, _) = topology {
+            let half_size = 1i64 << max_level;
+            assert!(
+                region.min.zip(region.max).map(|(min, max)| -half_size <= min as i64
+                    && min < max
+                    && max as i64 <= half_size).reduce(|a, b| a && b),
+                "bounded region must be inside the world"
+            );
+        }
+        let nodes = ConcurrentHashTable::with_hasher(build_hasher);
+        let shard_count = nodes.shard_count();
         World {
             shared_world_state: Arc::new(SharedWorldState {
-                nodes: UnsafeCell::new(HashTable::with_hasher(build_hasher)),
+                nodes: UnsafeCell::new(nodes),
                 snapshots: Default::default(),
+                young_nodes: (0..shard_count).map(|_| Default::default()).collect(),
+                remembered_nodes: (0..shard_count).map(|_| Default::default()).collect(),
+                topology: topology,
             }),
             step: step,
+            max_level: max_level,
+            old_node_count: 0,
+            full_gc_node_count: MIN_FULL_GC_NODE_COUNT,
+            memory_budget: None,
+            memo_clock: 0,
+            memo_hit_count: AtomicUsize::new(0),
+            memo_miss_count: AtomicUsize::new(0),
+        }
+    }
+    #[allow(dead_code)]
+    pub fn max_level(&self) -> u8 {
+        self.max_level
+    }
+    #[allow(dead_code)]
+    pub fn topology(&self) -> Topology<Block> {
+        self.shared_world_state.topology
+    }
+    // where blocks can be set, for toroidal worlds positions outside wrap around
+    #[allow(dead_code)]
+    pub fn region(&self) -> Region<i32> {
+        match self.shared_world_state.topology {
+            Topology::Bounded(region, _) => region,
+            Topology::Infinite | Topology::Toroidal => {
+                let half_size = 1i32 << self.max_level;
+                Region::new(math::Vec3::splat(-half_size), math::Vec3::splat(half_size))
+            }
+        }
+    }
+    // whether State::set can be called with position without panicking
+    #[allow(dead_code)]
+    pub fn contains(&self, position: math::Vec3<i32>) -> bool {
+        let region = self.region();
+        self.shared_world_state.topology == Topology::Toroidal
+            || region
+                .min
+                .zip(region.max)
+                .zip(position)
+                .map(|((min, max), v)| min <= v && v < max)
+                .reduce(|a, b| a && b)
+    }
+    // the approximate number of bytes used by nodes
+    pub fn memory_usage(&self) -> usize {
+        let young_node_count: usize = self
+            .shared_world_state
+            .young_nodes
+            .iter()
+            .map(|young_nodes| young_nodes.lock().unwrap().len())
+            .sum();
+        let node_count = self.old_node_count + young_node_count;
+        ConcurrentHashTable::<Node<Block>, H>::get_memory_usage(node_count)
+    }
+    #[allow(dead_code)]
+    pub fn stats(&self) -> WorldStats {
+        let mut level_node_counts = vec![0; self.max_level as usize + 1];
+        let mut node_count = 0;
+        let nodes = unsafe { &*self.shared_world_state.nodes.get() };
+        nodes.for_each(|node| {
+            node_count += 1;
+            let level = node.key.level() as usize;
+            // stepping temporarily expands the root past max_level
+            if level >= level_node_counts.len() {
+                level_node_counts.resize(level + 1, 0);
+            }
+            level_node_counts[level] += 1;
+        });
+        WorldStats {
+            node_count: node_count,
+            leaf_node_count: level_node_counts[0],
+            level_node_counts: level_node_counts,
+            memo_hit_count: self.memo_hit_count.load(Ordering::Relaxed),
+            memo_miss_count: self.memo_miss_count.load(Ordering::Relaxed),
+            snapshot_count: self.shared_world_state.snapshots.lock().unwrap().len(),
+            memory_usage: self.memory_usage(),
+        }
+    }
+    #[allow(dead_code)]
+    pub fn memory_budget(&self) -> Option<usize> {
+        self.memory_budget
+    }
+    // gc drops cached step results to try to keep memory_usage within the budget
+    #[allow(dead_code)]
+    pub fn set_memory_budget(&mut self, memory_budget: Option<usize>) {
+        self.memory_budget = memory_budget;
+    }
+    fn is_over_memory_budget(&self) -> bool {
+        match self.memory_budget {
+            Some(memory_budget) => self.memory_usage() > memory_budget,
+            None => false,
         }
     }
-    fn mark_node<'a>(node: NonNull<Node<Block>>, work_queue: &mut VecDeque<&'a mut Node<Block>>) {
+    fn mark_node<'a>(
+        node: NonNull<Node<Block>>,
+        young_only: bool,
+        work_queue: &mut VecDeque<&'a mut Node<Block>>,
+    ) {
         let node = unsafe { &mut *node.as_ptr() };
+        if young_only && node.gc_generation == GcGeneration::Old {
+            return;
+        }
         if let GcState::Unreachable = node.gc_state {
             node.gc_state = GcState::Reachable;
             work_queue.push_back(node);
         }
     }
-    pub fn gc(&mut self) {
-        let nodes = unsafe { &mut *self.shared_world_state.nodes.get() };
-        for node in nodes.iter_mut() {
-            node.gc_state = GcState::Unreachable;
-        }
+    // returns the number of nodes marked
+    fn mark_reachable_nodes(&mut self, young_only: bool) -> usize {
         let mut work_queue = Default::default();
         self.shared_world_state
             .snapshots
@@ -1114,16 +2845,31 @@ impl<Block: BlockType, Step: StepFn<Block>, H: BuildHasher> World<Block, Step, H
             .unwrap()
             .retain(|k, v| {
                 if Arc::get_mut(v).is_none() {
-                    Self::mark_node(*k, &mut work_queue);
+                    Self::mark_node(*k, young_only, &mut work_queue);
                     true
                 } else {
                     false
                 }
             });
+        let remembered_nodes: Vec<_> = self
+            .shared_world_state
+            .remembered_nodes
+            .iter()
+            .flat_map(|nodes| mem::replace(&mut *nodes.lock().unwrap(), Vec::new()))
+            .collect();
+        for node in remembered_nodes {
+            for i in unsafe { &mut *node.as_ptr() }.next.iter_mut() {
+                if let Some(next) = NonNull::new(*i.get_mut()) {
+                    Self::mark_node(next, young_only, &mut work_queue);
+                }
+            }
+        }
+        let mut marked_node_count = 0;
         while let Some(node) = work_queue.pop_front() {
-            for i in node.next.iter() {
-                if let Some(next) = *i {
-                    Self::mark_node(next, &mut work_queue);
+            marked_node_count += 1;
+            for i in node.next.iter_mut() {
+                if let Some(next) = NonNull::new(*i.get_mut()) {
+                    Self::mark_node(next, young_only, &mut work_queue);
                 }
             }
             match &node.key {
@@ -1131,27 +2877,159 @@ impl<Block: BlockType, Step: StepFn<Block>, H: BuildHasher> World<Block, Step, H
                 NodeKey::Nonleaf(NodeKeyNonleaf { children, .. }) => {
                     for child in children {
                         for child in child {
-                            for child in child {
-                                Self::mark_node(*child, &mut work_queue);
+                            for &child in child {
+                                let child = self.shared_world_state.get_node(child);
+                                Self::mark_node(child, young_only, &mut work_queue);
                             }
                         }
                     }
                 }
             }
         }
+        marked_node_count
+    }
+    // only collects nodes created since the last collection, unless enough
+    // old nodes have built up since the last full collection.
+    // young collections take time proportional to the nodes created since the
+    // last collection, but full collections aren't incremental and pause for
+    // time proportional to every node in the world. they only happen once the
+    // old nodes double, so their cost is amortized over the young collections
+    pub fn gc(&mut self) -> GcStats {
+        let gc_stats = if self.old_node_count >= self.full_gc_node_count {
+            self.full_gc()
+        } else {
+            self.young_gc()
+        };
+        if self.is_over_memory_budget() {
+            self.evict_results(gc_stats)
+        } else {
+            gc_stats
+        }
+    }
+    // drops the least recently used half of the cached step results, then
+    // collects what was only reachable through them, until under budget
+    fn evict_results(&mut self, gc_stats: GcStats) -> GcStats {
+        let start_time = time::Instant::now();
+        let mut retval = gc_stats;
+        retval.full = true;
+        while self.is_over_memory_budget() {
+            let nodes = unsafe { &mut *self.shared_world_state.nodes.get() };
+            let memo_clock = self.memo_clock;
+            let mut ages: Vec<usize> = nodes
+                .iter_mut()
+                .filter(|node| node.next.iter().any(|v| !v.load(Ordering::Relaxed).is_null()))
+                .map(|node| memo_clock.wrapping_sub(*node.next_last_used.get_mut()))
+                .collect();
+            if ages.is_empty() {
+                break;
+            }
+            ages.sort_unstable();
+            let median_age = ages[ages.len() / 2];
+            // results from the same step all have the same age, so make sure
+            // something gets evicted when most results have the oldest age
+            let min_evicted_age = if median_age == *ages.last().unwrap() {
+                median_age
+            } else {
+                median_age + 1
+            };
+            for node in nodes.iter_mut() {
+                if memo_clock.wrapping_sub(*node.next_last_used.get_mut()) < min_evicted_age {
+                    continue;
+                }
+                for next in node.next.iter_mut() {
+                    if !next.get_mut().is_null() {
+                        *next.get_mut() = ptr::null_mut();
+                        retval.evicted_result_count += 1;
+                    }
+                }
+            }
+            let full_gc_stats = self.full_gc();
+            retval.scanned_node_count += full_gc_stats.scanned_node_count;
+            retval.final_node_count = full_gc_stats.final_node_count;
+        }
+        retval.duration += start_time.elapsed();
+        retval
+    }
+    fn young_gc(&mut self) -> GcStats {
+        let start_time = time::Instant::now();
+        let young_nodes: Vec<_> = self
+            .shared_world_state
+            .young_nodes
+            .iter()
+            .flat_map(|young_nodes| mem::replace(&mut *young_nodes.lock().unwrap(), Vec::new()))
+            .collect();
+        for node in &young_nodes {
+            unsafe { &mut *node.as_ptr() }.gc_state = GcState::Unreachable;
+        }
+        let scanned_node_count = self.mark_reachable_nodes(true);
+        let initial_node_count = self.old_node_count + young_nodes.len();
+        let nodes = unsafe { &*self.shared_world_state.nodes.get() };
+        for node in young_nodes {
+            let node = unsafe { &mut *node.as_ptr() };
+            match node.gc_state {
+                GcState::Reachable => {
+                    node.gc_generation = GcGeneration::Old;
+                    self.old_node_count += 1;
+                }
+                GcState::Unreachable => {
+                    let node = Node {
+                        key: node.key,
+                        ..Default::default()
+                    };
+                    nodes.lock_shard(&node).remove(&node);
+                }
+            }
+        }
+        GcStats {
+            full: false,
+            initial_node_count: initial_node_count,
+            final_node_count: self.old_node_count,
+            scanned_node_count: scanned_node_count,
+            evicted_result_count: 0,
+            duration: start_time.elapsed(),
+        }
+    }
+    // traces every node in one pause, see gc
+    pub fn full_gc(&mut self) -> GcStats {
+        let start_time = time::Instant::now();
+        {
+            let nodes = unsafe { &mut *self.shared_world_state.nodes.get() };
+            for node in nodes.iter_mut() {
+                node.gc_state = GcState::Unreachable;
+            }
+        }
+        for young_nodes in &self.shared_world_state.young_nodes {
+            young_nodes.lock().unwrap().clear();
+        }
+        // every node gets traced, so the remembered nodes aren't needed
+        for remembered_nodes in &self.shared_world_state.remembered_nodes {
+            remembered_nodes.lock().unwrap().clear();
+        }
+        let scanned_node_count = self.mark_reachable_nodes(false);
+        let nodes = unsafe { &mut *self.shared_world_state.nodes.get() };
         let mut initial_node_count = 0usize;
         let mut final_node_count = 0usize;
         nodes.retain(|node| {
             initial_node_count += 1;
             match node.gc_state {
                 GcState::Reachable => {
+                    node.gc_generation = GcGeneration::Old;
                     final_node_count += 1;
                     true
                 }
                 GcState::Unreachable => false,
             }
         });
-        println!("GC: {} -> {}", initial_node_count, final_node_count);
+        self.old_node_count = final_node_count;
+        self.full_gc_node_count = cmp::max(MIN_FULL_GC_NODE_COUNT, final_node_count * 2);
+        GcStats {
+            full: true,
+            initial_node_count: initial_node_count,
+            final_node_count: final_node_count,
+            scanned_node_count: scanned_node_count,
+            evicted_result_count: 0,
+            duration: start_time.elapsed(),
+        }
     }
 }
 
@@ -1159,6 +3037,10 @@ unsafe impl<Block: BlockType + Send + Sync, Step: StepFn<Block> + Send, H: Build
     for World<Block, Step, H>
 {}
 
+unsafe impl<Block: BlockType + Send + Sync, Step: StepFn<Block> + Sync, H: BuildHasher + Sync> Sync
+    for World<Block, Step, H>
+{}
+
 unsafe impl<Block: BlockType + Send + Sync, H: BuildHasher + Send> Send for State<Block, H> {}
 
 unsafe impl<Block: BlockType + Send + Sync, H: BuildHasher + Send> Sync for State<Block, H> {}
@@ -1185,11 +3067,19 @@ pub struct SerializedState<Block: BlockType>(Vec<SerializedNode<Block>>);
 impl<'de, Block: BlockType + Deserialize<'de>> Deserialize<'de> for SerializedState<Block> {
     fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
         let nodes: Vec<SerializedNode<Block>> = Deserialize::deserialize(deserializer)?;
+        SerializedState::from_nodes(nodes).map_err(D::Error::custom)
+    }
+}
+
+impl<Block: BlockType> SerializedState<Block> {
+    // nodes are in the order they are used, so children come before their parents
+    // and the root is last
+    pub fn from_nodes(nodes: Vec<SerializedNode<Block>>) -> Result<Self, &'static str> {
         if nodes.len() as u64 > SerializedNodeIndex::MAX.0 as u64 {
-            return Err(Error::custom("too many nodes"));
+            return Err("too many nodes");
         }
         if nodes.is_empty() {
-            return Err(Error::custom("no root node"));
+            return Err("no root node");
         }
         let mut levels = Vec::<u8>::with_capacity(nodes.len());
         for i in 0..nodes.len() {
@@ -1201,16 +3091,16 @@ impl<'de, Block: BlockType + Deserialize<'de>> Deserialize<'de> for SerializedSt
                         for key in key {
                             for key in key {
                                 if key.0 as u64 >= i as u64 {
-                                    return Err(Error::custom("node index out of range"));
+                                    return Err("node index out of range");
                                 }
                                 let computed_level = levels[key.0 as usize] + 1;
-                                if computed_level as u32 > MAX_LEVEL {
-                                    return Err(Error::custom("node nested too deeply"));
+                                if computed_level > MAX_SUPPORTED_LEVEL {
+                                    return Err("node nested too deeply");
                                 }
                                 if level == None {
                                     level = Some(computed_level);
                                 } else if level != Some(computed_level) {
-                                    return Err(Error::custom("node children at different levels"));
+                                    return Err("node children at different levels");
                                 }
                             }
                         }
@@ -1221,16 +3111,13 @@ impl<'de, Block: BlockType + Deserialize<'de>> Deserialize<'de> for SerializedSt
             levels.push(level);
         }
         assert_eq!(levels.len(), nodes.len());
-        if *levels.last().unwrap() != MAX_LEVEL as u8 {
-            return Err(Error::custom("root node not nested deeply enough"));
-        }
         mem::drop(levels);
         let mut used = Vec::new();
         used.resize(nodes.len(), false);
         *used.last_mut().unwrap() = true;
         for i in (0..nodes.len()).rev() {
             if !used[i] {
-                return Err(Error::custom("node is unused"));
+                return Err("node is unused");
             }
             if let SerializedNode::Nonleaf(key) = &nodes[i] {
                 for key in key {
@@ -1244,61 +3131,280 @@ impl<'de, Block: BlockType + Deserialize<'de>> Deserialize<'de> for SerializedSt
         }
         Ok(SerializedState(nodes))
     }
-}
-
-impl<Block: BlockType> SerializedState<Block> {
-    fn from_node(root: NonNull<Node<Block>>) -> Self {
-        let mut nodes = Vec::new();
-        let mut nodes_map = HashMap::new();
-        fn serialize_node<Block: BlockType>(
+    #[allow(dead_code)]
+    pub fn nodes(&self) -> &[SerializedNode<Block>] {
+        &self.0
+    }
+    pub fn level(&self) -> u32 {
+        let mut level = 0;
+        let mut node = self.0.last().unwrap();
+        while let SerializedNode::Nonleaf(key) = node {
+            level += 1;
+            node = &self.0[key[0][0][0].0 as usize];
+        }
+        level
+    }
+    fn from_node<H: BuildHasher>(
+        root: NonNull<Node<Block>>,
+        shared_world_state: &SharedWorldState<Block, H>,
+    ) -> Self {
+        let (nodes, nodes_map) = list_nodes(root, shared_world_state);
+        SerializedState(
+            nodes
+                .iter()
+                .map(|&node| {
+                    serialize_node(node, |child| nodes_map[&child], shared_world_state)
+                }).collect(),
+        )
+    }
+}
+
+// every node reachable from root, with children before their parents, in the
+// order they are serialized in
+fn list_nodes<Block: BlockType, H: BuildHasher>(
+    root: NonNull<Node<Block>>,
+    shared_world_state: &SharedWorldState<Block, H>,
+) -> (
+    Vec<NonNull<Node<Block>>>,
+    HashMap<NonNull<Node<Block>>, SerializedNodeIndex>,
+) {
+    fn add_node<Block: BlockType, H: BuildHasher>(
+        node: NonNull<Node<Block>>,
+        nodes_map: &mut HashMap<NonNull<Node<Block>>, SerializedNodeIndex>,
+        nodes: &mut Vec<NonNull<Node<Block>>>,
+        shared_world_state: &SharedWorldState<Block, H>,
+    ) {
+        if nodes_map.contains_key(&node) {
+            return;
+        }
+        if let NodeKey::Nonleaf(key) = unsafe { &node.as_ref().key } {
+            for child in &key.children {
+                for child in child {
+                    for &child in child {
+                        let child = shared_world_state.get_node(child);
+                        add_node(child, nodes_map, nodes, shared_world_state);
+                    }
+                }
+            }
+        }
+        let index = nodes.len();
+        assert!(index as u64 <= SerializedNodeIndex::MAX.0 as u64);
+        nodes_map.insert(node, SerializedNodeIndex(index as u32));
+        nodes.push(node);
+    }
+    let mut nodes = Vec::new();
+    let mut nodes_map = HashMap::new();
+    add_node(root, &mut nodes_map, &mut nodes, shared_world_state);
+    (nodes, nodes_map)
+}
+
+fn serialize_node<
+    Block: BlockType,
+    F: FnMut(NonNull<Node<Block>>) -> SerializedNodeIndex,
+    H: BuildHasher,
+>(
+    node: NonNull<Node<Block>>,
+    mut get_index: F,
+    shared_world_state: &SharedWorldState<Block, H>,
+) -> SerializedNode<Block> {
+    match unsafe { &node.as_ref().key } {
+        // the boundary is filled in again when loading
+        NodeKey::Leaf(key) => SerializedNode::Leaf(key.blocks),
+        NodeKey::Nonleaf(key) => {
+            let mut new_key = [[[SerializedNodeIndex(0); 2]; 2]; 2];
+            for (new_key, key) in new_key.iter_mut().zip(key.children.iter()) {
+                for (new_key, key) in new_key.iter_mut().zip(key.iter()) {
+                    for (new_key, &key) in new_key.iter_mut().zip(key.iter()) {
+                        *new_key = get_index(shared_world_state.get_node(key));
+                    }
+                }
+            }
+            SerializedNode::Nonleaf(new_key)
+        }
+    }
+}
+
+// FNV-1a with integers written as little endian, so fingerprints don't depend
+// on the platform or the rust version
+struct FingerprintHasher(u64);
+
+impl FingerprintHasher {
+    fn new() -> Self {
+        FingerprintHasher(0xCBF29CE484222325)
+    }
+}
+
+impl Hasher for FingerprintHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ byte as u64).wrapping_mul(0x100000001B3);
+        }
+    }
+    fn write_u16(&mut self, value: u16) {
+        self.write_u64(value as u64)
+    }
+    fn write_u32(&mut self, value: u32) {
+        self.write_u64(value as u64)
+    }
+    fn write_u64(&mut self, value: u64) {
+        for i in 0..8 {
+            self.write(&[(value >> (i * 8)) as u8]);
+        }
+    }
+    fn write_usize(&mut self, value: usize) {
+        self.write_u64(value as u64)
+    }
+    fn write_i16(&mut self, value: i16) {
+        self.write_u64(value as u64)
+    }
+    fn write_i32(&mut self, value: i32) {
+        self.write_u64(value as u64)
+    }
+    fn write_i64(&mut self, value: i64) {
+        self.write_u64(value as u64)
+    }
+    fn write_isize(&mut self, value: isize) {
+        self.write_u64(value as u64)
+    }
+}
+
+// a hash of the serialized form of the nodes, blocks are hashed as they are, so
+// the base has to use the same block ids as when the delta was created
+fn fingerprint_nodes<Block: BlockType, H: BuildHasher>(
+    nodes: &[NonNull<Node<Block>>],
+    nodes_map: &HashMap<NonNull<Node<Block>>, SerializedNodeIndex>,
+    shared_world_state: &SharedWorldState<Block, H>,
+) -> u64 {
+    let mut hasher = FingerprintHasher::new();
+    for &node in nodes {
+        serialize_node(node, |child| nodes_map[&child], shared_world_state).hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+// the nodes of a state that aren't in a base state. indexes below
+// base_node_count refer to the nodes of SerializedState::from(base), the rest
+// refer to the delta's own nodes
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub struct SerializedDelta<Block: BlockType> {
+    base_node_count: u32,
+    base_fingerprint: u64,
+    nodes: Vec<SerializedNode<Block>>,
+    root: SerializedNodeIndex,
+}
+
+impl<Block: BlockType> SerializedDelta<Block> {
+    #[allow(dead_code)]
+    pub fn new<H: BuildHasher>(base: &State<Block, H>, state: &State<Block, H>) -> Self {
+        assert!(base.state.shared_world_state == state.state.shared_world_state);
+        let shared_world_state = &*state.state.shared_world_state;
+        let (base_nodes, mut nodes_map) = list_nodes(base.state.root, shared_world_state);
+        let base_node_count = base_nodes.len() as u32;
+        let base_fingerprint = fingerprint_nodes(&base_nodes, &nodes_map, shared_world_state);
+        let mut nodes = Vec::new();
+        fn add_node<Block: BlockType, H: BuildHasher>(
             node: NonNull<Node<Block>>,
+            base_node_count: u32,
             nodes_map: &mut HashMap<NonNull<Node<Block>>, SerializedNodeIndex>,
             nodes: &mut Vec<SerializedNode<Block>>,
+            shared_world_state: &SharedWorldState<Block, H>,
         ) -> SerializedNodeIndex {
-            if let Some(&value) = nodes_map.get(&node) {
-                value
-            } else {
-                let key = match unsafe { &node.as_ref().key } {
-                    &NodeKey::Leaf(key) => SerializedNode::Leaf(key),
-                    NodeKey::Nonleaf(key) => {
-                        let mut new_key = [[[SerializedNodeIndex(0); 2]; 2]; 2];
-                        for (new_key, key) in new_key.iter_mut().zip(key.children.iter()) {
-                            for (new_key, key) in new_key.iter_mut().zip(key.iter()) {
-                                for (new_key, &key) in new_key.iter_mut().zip(key.iter()) {
-                                    *new_key = serialize_node(key, nodes_map, nodes);
-                                }
+            if let Some(&index) = nodes_map.get(&node) {
+                return index;
+            }
+            let serialized_node = serialize_node(
+                node,
+                |child| add_node(child, base_node_count, nodes_map, nodes, shared_world_state),
+                shared_world_state,
+            );
+            let index = base_node_count as u64 + nodes.len() as u64;
+            assert!(index <= SerializedNodeIndex::MAX.0 as u64);
+            let index = SerializedNodeIndex(index as u32);
+            nodes_map.insert(node, index);
+            nodes.push(serialized_node);
+            index
+        }
+        let root = add_node(
+            state.state.root,
+            base_node_count,
+            &mut nodes_map,
+            &mut nodes,
+            shared_world_state,
+        );
+        SerializedDelta {
+            base_node_count: base_node_count,
+            base_fingerprint: base_fingerprint,
+            nodes: nodes,
+            root: root,
+        }
+    }
+    // checks what can be checked without the base state
+    pub fn from_parts(
+        base_node_count: u32,
+        base_fingerprint: u64,
+        nodes: Vec<SerializedNode<Block>>,
+        root: SerializedNodeIndex,
+    ) -> Result<Self, &'static str> {
+        if base_node_count == 0 {
+            return Err("no base nodes");
+        }
+        if base_node_count as u64 + nodes.len() as u64 > SerializedNodeIndex::MAX.0 as u64 {
+            return Err("too many nodes");
+        }
+        for (i, node) in nodes.iter().enumerate() {
+            if let SerializedNode::Nonleaf(key) = node {
+                for key in key {
+                    for key in key {
+                        for key in key {
+                            if key.0 as u64 >= base_node_count as u64 + i as u64 {
+                                return Err("node index out of range");
                             }
                         }
-                        SerializedNode::Nonleaf(new_key)
                     }
-                };
-                let retval = nodes.len();
-                assert!(retval as u64 <= SerializedNodeIndex::MAX.0 as u64);
-                let retval = SerializedNodeIndex(retval as u32);
-                use std::collections::hash_map::Entry::Vacant;
-                if let Vacant(entry) = nodes_map.entry(node) {
-                    entry.insert(retval);
-                } else {
-                    unreachable!()
                 }
-                nodes.push(key);
-                retval
             }
         }
-        serialize_node(root, &mut nodes_map, &mut nodes);
-        SerializedState(nodes)
+        if root.0 as u64 >= base_node_count as u64 + nodes.len() as u64 {
+            return Err("root index out of range");
+        }
+        Ok(SerializedDelta {
+            base_node_count: base_node_count,
+            base_fingerprint: base_fingerprint,
+            nodes: nodes,
+            root: root,
+        })
+    }
+    #[allow(dead_code)]
+    pub fn base_node_count(&self) -> u32 {
+        self.base_node_count
+    }
+    #[allow(dead_code)]
+    pub fn base_fingerprint(&self) -> u64 {
+        self.base_fingerprint
+    }
+    #[allow(dead_code)]
+    pub fn nodes(&self) -> &[SerializedNode<Block>] {
+        &self.nodes
+    }
+    #[allow(dead_code)]
+    pub fn root(&self) -> SerializedNodeIndex {
+        self.root
     }
 }
 
 impl<'a, Block: BlockType, H: BuildHasher> From<&'a State<Block, H>> for SerializedState<Block> {
     fn from(state: &'a State<Block, H>) -> SerializedState<Block> {
-        Self::from_node(state.state.root)
+        Self::from_node(state.state.root, &state.state.shared_world_state)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rules::{DirectStateEncoding, GenerationsRule, OuterTotalisticRule};
     use serde_test::{assert_tokens, Token};
     type Block = u32;
 
@@ -1449,6 +3555,23 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_step_memo() {
+        let mut world = World::new(
+            |neighborhood: &[[[Block; 3]; 3]; 3]| neighborhood[0][1][1],
+            DefaultBuildHasher::new(),
+        );
+        let mut state = State::create_empty(&mut world);
+        state.set(&mut world, math::Vec3::new(0, 0, 0), 1 as Block);
+        let mut expected_state = State::create_empty(&mut world);
+        expected_state.set(&mut world, math::Vec3::new(2, 0, 0), 1 as Block);
+        // stepping by 1 generation first must not memoize the result for 2 generations
+        let mut single_step_state = state.clone();
+        single_step_state.step(&mut world, 0);
+        state.step(&mut world, 1);
+        assert_eq!(state, expected_state);
+    }
+
     #[test]
     fn test_serde() {
         let mut world = World::new(
@@ -1458,9 +3581,9 @@ mod tests {
         let mut state = State::create_empty(&mut world);
         state.set(&mut world, math::Vec3::new(1, 2, 3), 1 as Block);
         let serialized_state = SerializedState::from(&state);
-        assert_eq!(state, State::from(&serialized_state, &mut world));
+        assert_eq!(state, State::from(&serialized_state, &mut world).unwrap());
         let mut tokens = Vec::new();
-        tokens.push(Token::Seq { len: Some(41) });
+        tokens.push(Token::Seq { len: Some(5) });
         {
             let mut push_node = |node: SerializedNode<Block>| match node {
                 SerializedNode::Leaf(node) => tokens.extend_from_slice(&[
@@ -1520,28 +3643,1086 @@ mod tests {
                     Token::TupleEnd,
                 ]),
             };
+            // the root is only big enough to hold the block
             push_node(SerializedNode::Leaf([[[0; 2]; 2]; 2]));
             let sni = |v| SerializedNodeIndex(v);
-            for i in 0..19 {
-                push_node(SerializedNode::Nonleaf([[[sni(i); 2]; 2]; 2]));
-            }
+            push_node(SerializedNode::Nonleaf([[[sni(0); 2]; 2]; 2]));
             push_node(SerializedNode::Leaf([[[0, 0], [0, 0]], [[0, 1], [0, 0]]]));
             push_node(SerializedNode::Nonleaf([
-                [[sni(0), sni(0)], [sni(0), sni(20)]],
+                [[sni(0), sni(0)], [sni(0), sni(2)]],
                 [[sni(0), sni(0)], [sni(0), sni(0)]],
             ]));
-            for i in 1..19 {
-                push_node(SerializedNode::Nonleaf([
-                    [[sni(20 + i), sni(i)], [sni(i), sni(i)]],
-                    [[sni(i), sni(i)], [sni(i), sni(i)]],
-                ]));
-            }
             push_node(SerializedNode::Nonleaf([
-                [[sni(19), sni(19)], [sni(19), sni(19)]],
-                [[sni(19), sni(19)], [sni(19), sni(39)]],
+                [[sni(1), sni(1)], [sni(1), sni(1)]],
+                [[sni(1), sni(1)], [sni(1), sni(3)]],
             ]));
         }
         tokens.push(Token::SeqEnd);
         assert_tokens(&serialized_state, &tokens);
     }
+
+    #[test]
+    fn test_max_level() {
+        let step = |neighborhood: &[[[Block; 3]; 3]; 3]| neighborhood[1][1][1];
+        let mut small_world = World::with_max_level(step, DefaultBuildHasher::new(), 3);
+        let mut big_world =
+            World::with_max_level(step, DefaultBuildHasher::new(), MAX_SUPPORTED_LEVEL);
+        let offset = -math::Vec3::splat(TEST_SIZE as i32);
+        let small_state = create_test_state(&mut small_world, offset);
+        assert_eq!(small_state.level(), 3);
+        let serialized_state = SerializedState::from(&small_state);
+        assert_eq!(serialized_state.level(), 3);
+        let mut big_state = State::from(&serialized_state, &mut big_world).unwrap();
+        // the root only grows when something is set further out
+        assert_eq!(big_state.level(), 3);
+        verify_subslice(
+            &big_state.get_substate(offset, TEST_SIZE),
+            math::Vec3::splat(0),
+            TEST_SIZE,
+        );
+        let far_position = math::Vec3::new(-1 << 30, (1 << 30) - 1, 0);
+        big_state.set(&mut big_world, far_position, 1);
+        let serialized_state = SerializedState::from(&big_state);
+        assert_eq!(serialized_state.level(), MAX_SUPPORTED_LEVEL as u32);
+        assert!(State::from(&serialized_state, &mut small_world).is_err());
+        let state = State::from(&serialized_state, &mut big_world).unwrap();
+        assert_eq!(state, big_state);
+        assert_eq!(
+            state
+                .get_substate(far_position.map(|v| v & !1), 2)
+                .get(far_position.map(|v| (v & 1) as u32)),
+            1
+        );
+        // and shrinks back once it's cleared again
+        big_state.set(&mut big_world, far_position, 0);
+        assert_eq!(big_state.level(), 3);
+        assert_eq!(
+            SerializedState::from(&big_state),
+            SerializedState::from(&small_state)
+        );
+        let serialized_state = SerializedState(vec![SerializedNode::Leaf([[[5; 2]; 2]; 2])]);
+        assert_eq!(serialized_state.level(), 0);
+        let state = State::from(&serialized_state, &mut small_world).unwrap();
+        assert_eq!(state.level(), 0);
+        let substate = state.get_substate(math::Vec3::splat(-8), 16);
+        for x in 0..16 {
+            for y in 0..16 {
+                for z in 0..16 {
+                    let expected = if math::Vec3::new(x, y, z)
+                        .map(|v| v == 7 || v == 8)
+                        .reduce(|a, b| a && b)
+                    {
+                        5
+                    } else {
+                        0
+                    };
+                    assert_eq!(substate.get(math::Vec3::new(x, y, z)), expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_step_by() {
+        let mut world = World::new(
+            |neighborhood: &[[[Block; 3]; 3]; 3]| {
+                let mut retval = 0;
+                for v in neighborhood {
+                    for v in v {
+                        for v in v {
+                            retval ^= *v;
+                        }
+                    }
+                }
+                retval
+            },
+            DefaultBuildHasher::new(),
+        );
+        let mut initial_state = State::create_empty(&mut world);
+        for &(x, y, z) in &[(0, 0, 0), (1, 0, 0), (0, 2, -1), (-3, 1, 2)] {
+            initial_state.set(&mut world, math::Vec3::new(x, y, z), 1);
+        }
+        let mut expected_state = initial_state.clone();
+        let mut generation = 0;
+        for &generations in &[0, 1, 2, 3, 5, 8, 13, 21, 37, 64] {
+            while generation < generations {
+                expected_state.step(&mut world, 0);
+                generation += 1;
+            }
+            let mut state = initial_state.clone();
+            state.step_by(&mut world, generations);
+            assert_eq!(state, expected_state, "generations = {}", generations);
+        }
+        let mut state = initial_state.clone();
+        state.step(&mut world, 6);
+        assert_eq!(state, expected_state);
+    }
+
+    const BRUTE_FORCE_SIZE: usize = 32;
+
+    #[derive(Clone, Eq, PartialEq, Debug)]
+    struct BruteForceState(Vec<Block>);
+
+    impl BruteForceState {
+        const STRIDE: math::Vec3<usize> = math::Vec3 {
+            x: 1,
+            y: BRUTE_FORCE_SIZE,
+            z: BRUTE_FORCE_SIZE * BRUTE_FORCE_SIZE,
+        };
+        fn new() -> Self {
+            BruteForceState(vec![Default::default(); BRUTE_FORCE_SIZE.pow(3)])
+        }
+        fn from_state(state: &State<Block, DefaultBuildHasher>) -> Self {
+            let mut retval = Self::new();
+            state
+                .get_substate(math::Vec3::splat(0), BRUTE_FORCE_SIZE as u32)
+                .get_cube_pow2(
+                    math::Vec3::splat(0),
+                    BRUTE_FORCE_SIZE as u32,
+                    Self::STRIDE,
+                    &mut retval.0,
+                );
+            retval
+        }
+        fn get_index(position: math::Vec3<i32>) -> Option<usize> {
+            if position
+                .map(|v| v >= 0 && v < BRUTE_FORCE_SIZE as i32)
+                .reduce(|a, b| a && b)
+            {
+                Some(position.map(|v| v as usize).dot(Self::STRIDE))
+            } else {
+                None
+            }
+        }
+        fn set(&mut self, position: math::Vec3<i32>, block: Block) {
+            let index = Self::get_index(position).unwrap();
+            self.0[index] = block;
+        }
+        fn step<Step: StepFn<Block>>(&self, step: &Step) -> Self {
+            // blocks on the outside layer are left empty, so patterns need
+            // to stay away from the edges
+            let mut retval = Self::new();
+            for x in 1..BRUTE_FORCE_SIZE - 1 {
+                for y in 1..BRUTE_FORCE_SIZE - 1 {
+                    for z in 1..BRUTE_FORCE_SIZE - 1 {
+                        let mut neighborhood: [[[Block; 3]; 3]; 3] = Default::default();
+                        for dx in 0..3 {
+                            for dy in 0..3 {
+                                for dz in 0..3 {
+                                    neighborhood[dx][dy][dz] = self.0[x + dx - 1
+                                        + Self::STRIDE.y * (y + dy - 1)
+                                        + Self::STRIDE.z * (z + dz - 1)];
+                                }
+                            }
+                        }
+                        retval.0[x + Self::STRIDE.y * y + Self::STRIDE.z * z] =
+                            step.step(&neighborhood);
+                    }
+                }
+            }
+            retval
+        }
+    }
+
+    struct XorShiftRng(u32);
+
+    impl XorShiftRng {
+        fn next(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0
+        }
+    }
+
+    fn parity_rule(neighborhood: &[[[Block; 3]; 3]; 3]) -> Block {
+        let mut retval = 0;
+        for v in neighborhood {
+            for v in v {
+                for v in v {
+                    retval ^= *v;
+                }
+            }
+        }
+        retval
+    }
+
+    thread_local! {
+        // the reference rules are parsed by the rules module instead of
+        // being written out again here
+        static LIFE_RULE: OuterTotalisticRule = "B6/S5-7".parse().unwrap();
+        static GENERATIONS_RULE: GenerationsRule<DirectStateEncoding> =
+            "B3,4/S2-6/C4".parse().unwrap();
+    }
+
+    fn life_rule(neighborhood: &[[[Block; 3]; 3]; 3]) -> Block {
+        LIFE_RULE.with(|rule| {
+            let live_neighbor_count = rule.neighborhood().count_matching(neighborhood, |v| v == 1);
+            rule.is_alive_next(neighborhood[1][1][1] == 1, live_neighbor_count) as Block
+        })
+    }
+
+    fn generations_rule(neighborhood: &[[[Block; 3]; 3]; 3]) -> Block {
+        GENERATIONS_RULE.with(|rule| {
+            let live_neighbor_count = rule
+                .rule()
+                .neighborhood()
+                .count_matching(neighborhood, |v| v == 1);
+            rule.next_state(neighborhood[1][1][1], live_neighbor_count)
+        })
+    }
+
+    fn decay_rule(neighborhood: &[[[Block; 3]; 3]; 3]) -> Block {
+        let mut retval = 0;
+        for v in neighborhood {
+            for v in v {
+                for v in v {
+                    retval = retval.max(v.saturating_sub(1));
+                }
+            }
+        }
+        retval
+    }
+
+    #[test]
+    fn test_brute_force() {
+        let rules: &[(fn(&[[[Block; 3]; 3]; 3]) -> Block, Block)] = &[
+            (parity_rule, 2),
+            (life_rule, 2),
+            (generations_rule, 4),
+            (decay_rule, 6),
+        ];
+        let mut rng = XorShiftRng(0x12345678);
+        for (rule_index, &(rule, state_count)) in rules.iter().enumerate() {
+            for _ in 0..2 {
+                let mut world = World::new(rule, DefaultBuildHasher::new());
+                let mut initial_state = State::create_empty(&mut world);
+                let mut initial_brute_force_state = BruteForceState::new();
+                let center = BRUTE_FORCE_SIZE as i32 / 2;
+                for x in center - 2..center + 2 {
+                    for y in center - 2..center + 2 {
+                        for z in center - 2..center + 2 {
+                            let position = math::Vec3::new(x, y, z);
+                            let block = rng.next() % state_count;
+                            initial_state.set(&mut world, position, block);
+                            initial_brute_force_state.set(position, block);
+                        }
+                    }
+                }
+                assert_eq!(
+                    BruteForceState::from_state(&initial_state),
+                    initial_brute_force_state
+                );
+                // patterns grow by at most one block per generation, so
+                // these step sequences stay inside the brute-force region
+                for log2_generation_counts in &[&[0, 1, 0, 2, 1][..], &[3]] {
+                    let mut state = initial_state.clone();
+                    let mut brute_force_state = initial_brute_force_state.clone();
+                    for &log2_generation_count in log2_generation_counts.iter() {
+                        state.step(&mut world, log2_generation_count);
+                        for _ in 0..1 << log2_generation_count {
+                            brute_force_state = brute_force_state.step(&rule);
+                        }
+                        assert!(
+                            BruteForceState::from_state(&state) == brute_force_state,
+                            "rule = {}, log2_generation_counts = {:?}",
+                            rule_index,
+                            log2_generation_counts
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    const SMALL_WORLD_LEVEL: u8 = 2;
+    const SMALL_WORLD_SIZE: usize = 8;
+    const SMALL_WORLD_STRIDE: math::Vec3<usize> = math::Vec3 {
+        x: 1,
+        y: SMALL_WORLD_SIZE,
+        z: SMALL_WORLD_SIZE * SMALL_WORLD_SIZE,
+    };
+
+    fn get_small_world_blocks(state: &State<Block, DefaultBuildHasher>) -> Vec<Block> {
+        let mut retval = vec![0; SMALL_WORLD_SIZE.pow(3)];
+        state
+            .get_substate(
+                math::Vec3::splat(-(SMALL_WORLD_SIZE as i32) / 2),
+                SMALL_WORLD_SIZE as u32,
+            ).get_cube_pow2(
+                math::Vec3::splat(0),
+                SMALL_WORLD_SIZE as u32,
+                SMALL_WORLD_STRIDE,
+                &mut retval,
+            );
+        retval
+    }
+
+    fn small_world_brute_force_step(
+        blocks: &[Block],
+        rule: fn(&[[[Block; 3]; 3]; 3]) -> Block,
+        topology: Topology<Block>,
+    ) -> Vec<Block> {
+        let size = SMALL_WORLD_SIZE as i32;
+        let get = |position: math::Vec3<i32>| {
+            if position.map(|v| v >= 0 && v < size).reduce(|a, b| a && b) {
+                return blocks[position.map(|v| v as usize).dot(SMALL_WORLD_STRIDE)];
+            }
+            match topology {
+                Topology::Infinite => unreachable!(),
+                Topology::Toroidal => blocks[position
+                    .map(|v| ((v + size) % size) as usize)
+                    .dot(SMALL_WORLD_STRIDE)],
+                Topology::Bounded(_, boundary_block) => boundary_block,
+            }
+        };
+        let mut retval = vec![0; blocks.len()];
+        for x in 0..size {
+            for y in 0..size {
+                for z in 0..size {
+                    let mut neighborhood: [[[Block; 3]; 3]; 3] = Default::default();
+                    for dx in 0..3 {
+                        for dy in 0..3 {
+                            for dz in 0..3 {
+                                neighborhood[dx][dy][dz] = get(math::Vec3::new(
+                                    x + dx as i32 - 1,
+                                    y + dy as i32 - 1,
+                                    z + dz as i32 - 1,
+                                ));
+                            }
+                        }
+                    }
+                    // the boundary is wherever the region isn't, even if the
+                    // same block is also inside of the region
+                    let position = math::Vec3::new(x, y, z) - math::Vec3::splat(size / 2);
+                    let block_region = Region::new(position, position + math::Vec3::splat(1));
+                    let block = match topology {
+                        Topology::Bounded(region, _) if !region.contains_region(&block_region) => {
+                            neighborhood[1][1][1]
+                        }
+                        _ => rule(&neighborhood),
+                    };
+                    let index = math::Vec3::new(x, y, z).map(|v| v as usize);
+                    retval[index.dot(SMALL_WORLD_STRIDE)] = block;
+                }
+            }
+        }
+        retval
+    }
+
+    fn check_small_world(
+        rule: fn(&[[[Block; 3]; 3]; 3]) -> Block,
+        state_count: Block,
+        topology: Topology<Block>,
+        rng: &mut XorShiftRng,
+    ) {
+        let mut world = World::with_topology(
+            rule,
+            DefaultBuildHasher::new(),
+            SMALL_WORLD_LEVEL,
+            topology,
+        );
+        let mut state = State::create_empty(&mut world);
+        state.set_cube_pow2(
+            &mut world,
+            math::Vec3::splat(-(SMALL_WORLD_SIZE as i32) / 2),
+            SMALL_WORLD_SIZE as u32,
+            |_, _| rng.next() % state_count,
+        );
+        let mut blocks = get_small_world_blocks(&state);
+        for &log2_generation_count in &[0, 1, 0, 2, 3, 5] {
+            state.step(&mut world, log2_generation_count);
+            for _ in 0..1 << log2_generation_count {
+                blocks = small_world_brute_force_step(&blocks, rule, topology);
+            }
+            assert!(
+                get_small_world_blocks(&state) == blocks,
+                "topology = {:?}, log2_generation_count = {}",
+                topology,
+                log2_generation_count
+            );
+        }
+    }
+
+    #[test]
+    fn test_toroidal() {
+        let mut world = World::with_topology(
+            |neighborhood: &[[[Block; 3]; 3]; 3]| neighborhood[0][1][1],
+            DefaultBuildHasher::new(),
+            SMALL_WORLD_LEVEL,
+            Topology::Toroidal,
+        );
+        let mut state = State::create_empty(&mut world);
+        state.set(&mut world, math::Vec3::new(3, 1, 2), 1);
+        state.step(&mut world, 0);
+        let mut expected_state = State::create_empty(&mut world);
+        expected_state.set(&mut world, math::Vec3::new(-4, 1, 2), 1);
+        assert_eq!(state, expected_state);
+        state.step(&mut world, 3);
+        assert_eq!(state, expected_state);
+        state.set(&mut world, math::Vec3::new(4, 9, -6), 2);
+        expected_state.set(&mut world, math::Vec3::new(-4, 1, 2), 2);
+        assert_eq!(state, expected_state);
+        assert_eq!(
+            get_small_world_blocks(&state)[math::Vec3::new(0, 5, 6).dot(SMALL_WORLD_STRIDE)],
+            2
+        );
+        let mut rng = XorShiftRng(0x9E3779B9);
+        check_small_world(parity_rule, 2, Topology::Toroidal, &mut rng);
+        check_small_world(life_rule, 2, Topology::Toroidal, &mut rng);
+        check_small_world(generations_rule, 4, Topology::Toroidal, &mut rng);
+    }
+
+    fn small_world_region() -> Region<i32> {
+        let half_size = SMALL_WORLD_SIZE as i32 / 2;
+        Region::new(math::Vec3::splat(-half_size), math::Vec3::splat(half_size))
+    }
+
+    #[test]
+    fn test_bounded() {
+        let boundary_block = 9;
+        let mut world = World::with_topology(
+            |neighborhood: &[[[Block; 3]; 3]; 3]| neighborhood[0][1][1],
+            DefaultBuildHasher::new(),
+            SMALL_WORLD_LEVEL,
+            Topology::Bounded(small_world_region(), boundary_block),
+        );
+        let mut state = State::create_empty(&mut world);
+        assert_eq!(
+            state.get_substate(math::Vec3::splat(0), 2).get(math::Vec3::splat(0)),
+            0
+        );
+        assert_eq!(
+            state.get_substate(math::Vec3::splat(4), 2).get(math::Vec3::splat(0)),
+            boundary_block
+        );
+        state.set(&mut world, math::Vec3::new(3, 1, 2), 1);
+        state.step(&mut world, 0);
+        let mut expected_state = State::create_empty(&mut world);
+        for y in -4..4 {
+            for z in -4..4 {
+                expected_state.set(&mut world, math::Vec3::new(-4, y, z), boundary_block);
+            }
+        }
+        assert_eq!(state, expected_state);
+        // a region that isn't a cube or a power of two across
+        let region = Region::new(math::Vec3::new(-3, -2, -4), math::Vec3::new(2, 3, 1));
+        let mut world = World::with_topology(
+            |neighborhood: &[[[Block; 3]; 3]; 3]| neighborhood[0][1][1],
+            DefaultBuildHasher::new(),
+            SMALL_WORLD_LEVEL,
+            Topology::Bounded(region, boundary_block),
+        );
+        assert_eq!(world.region(), region);
+        let mut state = State::create_empty(&mut world);
+        assert_eq!(
+            state.get_substate(math::Vec3::new(2, 0, 0), 2).get(math::Vec3::splat(0)),
+            boundary_block
+        );
+        assert_eq!(
+            state.get_substate(math::Vec3::new(0, 2, 0), 2).get(math::Vec3::splat(1)),
+            boundary_block
+        );
+        assert_eq!(
+            state.get_substate(math::Vec3::new(0, 2, 0), 2).get(math::Vec3::splat(0)),
+            0
+        );
+        assert_eq!(state.count_if(|block| block == 0), 5 * 5 * 5);
+        state.set(&mut world, math::Vec3::new(1, 1, -4), 1);
+        state.step(&mut world, 0);
+        let mut expected_state = State::create_empty(&mut world);
+        for y in -2..3 {
+            for z in -4..1 {
+                expected_state.set(&mut world, math::Vec3::new(-3, y, z), boundary_block);
+            }
+        }
+        assert_eq!(state, expected_state);
+        // editing doesn't touch the boundary
+        state.fill(&mut world, small_world_region(), 3);
+        assert_eq!(state.count_if(|block| block == 3), 5 * 5 * 5);
+        let mut rng = XorShiftRng(0x7F4A7C15);
+        // the boundary block also shows up inside the world
+        let topology = |boundary_block| Topology::Bounded(small_world_region(), boundary_block);
+        check_small_world(life_rule, 3, topology(2), &mut rng);
+        check_small_world(generations_rule, 5, topology(4), &mut rng);
+        check_small_world(decay_rule, 8, topology(7), &mut rng);
+        check_small_world(life_rule, 3, Topology::Bounded(region, 2), &mut rng);
+        check_small_world(decay_rule, 8, Topology::Bounded(region, 7), &mut rng);
+    }
+
+    #[test]
+    fn test_bounded_boundary_position() {
+        // the boundary block inside of the region changes like any other block
+        let boundary_block = 9;
+        let mut world = World::with_topology(
+            |neighborhood: &[[[Block; 3]; 3]; 3]| neighborhood[0][1][1],
+            DefaultBuildHasher::new(),
+            SMALL_WORLD_LEVEL,
+            Topology::Bounded(small_world_region(), boundary_block),
+        );
+        let mut state = State::create_empty(&mut world);
+        state.set(&mut world, math::Vec3::new(0, 1, 2), boundary_block);
+        state.step(&mut world, 0);
+        let mut expected_state = State::create_empty(&mut world);
+        for y in -4..4 {
+            for z in -4..4 {
+                expected_state.set(&mut world, math::Vec3::new(-4, y, z), boundary_block);
+            }
+        }
+        expected_state.set(&mut world, math::Vec3::new(1, 1, 2), boundary_block);
+        assert_eq!(state, expected_state);
+        state.step(&mut world, 1);
+        assert_eq!(
+            get_small_world_blocks(&state)[math::Vec3::new(7, 5, 6).dot(SMALL_WORLD_STRIDE)],
+            boundary_block
+        );
+        assert_eq!(state.count_if(|block| block == boundary_block), 3 * 8 * 8 + 1);
+        // blocks are still born when the boundary is the default block
+        let boundary_block = Block::default();
+        let mut world = World::with_topology(
+            parity_rule,
+            DefaultBuildHasher::new(),
+            SMALL_WORLD_LEVEL,
+            Topology::Bounded(small_world_region(), boundary_block),
+        );
+        let mut infinite_world =
+            World::with_max_level(parity_rule, DefaultBuildHasher::new(), SMALL_WORLD_LEVEL);
+        let mut state = State::create_empty(&mut world);
+        let mut infinite_state = State::create_empty(&mut infinite_world);
+        state.set(&mut world, math::Vec3::new(0, 1, -1), 1);
+        infinite_state.set(&mut infinite_world, math::Vec3::new(0, 1, -1), 1);
+        state.step(&mut world, 0);
+        infinite_state.step(&mut infinite_world, 0);
+        assert_eq!(state.population(), 27);
+        assert_eq!(
+            get_small_world_blocks(&state),
+            get_small_world_blocks(&infinite_state)
+        );
+        let region = Region::new(math::Vec3::new(-3, -2, -4), math::Vec3::new(2, 3, 1));
+        let mut rng = XorShiftRng(0x2545F491);
+        let topology = |region| Topology::Bounded(region, boundary_block);
+        check_small_world(parity_rule, 2, topology(small_world_region()), &mut rng);
+        check_small_world(life_rule, 2, topology(small_world_region()), &mut rng);
+        check_small_world(parity_rule, 2, topology(region), &mut rng);
+        check_small_world(decay_rule, 8, topology(region), &mut rng);
+    }
+
+    #[test]
+    fn test_step_report() {
+        let mut world = World::with_max_level(
+            |neighborhood: &[[[Block; 3]; 3]; 3]| neighborhood[0][1][1],
+            DefaultBuildHasher::new(),
+            SMALL_WORLD_LEVEL,
+        );
+        let mut state = State::create_empty(&mut world);
+        state.set(&mut world, math::Vec3::new(2, 1, -1), 1);
+        state.set(&mut world, math::Vec3::new(-4, -4, 3), 1);
+        assert_eq!(state.step(&mut world, 0), StepReport::default());
+        let region = |min: (i64, i64, i64), max: (i64, i64, i64)| {
+            Some(Region::new(
+                math::Vec3::new(min.0, min.1, min.2),
+                math::Vec3::new(max.0, max.1, max.2),
+            ))
+        };
+        assert_eq!(
+            state.clone().step(&mut world, 0).truncated_region,
+            region((4, 1, -1), (5, 2, 0))
+        );
+        state.set(&mut world, math::Vec3::new(3, -4, 3), 1);
+        assert_eq!(
+            state.clone().step_by(&mut world, 3).truncated_region,
+            region((4, -4, -1), (5, 2, 4))
+        );
+        state.step(&mut world, 2);
+        let mut expected_state = State::create_empty(&mut world);
+        expected_state.set(&mut world, math::Vec3::new(1, -4, 3), 1);
+        assert_eq!(state, expected_state);
+        let mut world = World::with_topology(
+            |neighborhood: &[[[Block; 3]; 3]; 3]| neighborhood[0][1][1],
+            DefaultBuildHasher::new(),
+            SMALL_WORLD_LEVEL,
+            Topology::Bounded(small_world_region(), 5),
+        );
+        let mut state = State::create_empty(&mut world);
+        state.set(&mut world, math::Vec3::new(3, 0, 0), 1);
+        assert_eq!(state.step(&mut world, 4), StepReport::default());
+    }
+
+    #[test]
+    fn test_step_parallel() {
+        let rules: &[(fn(&[[[Block; 3]; 3]; 3]) -> Block, Block)] = &[
+            (parity_rule, 2),
+            (life_rule, 2),
+            (generations_rule, 4),
+            (decay_rule, 6),
+        ];
+        let mut rng = XorShiftRng(0x9E3779B9);
+        for &(rule, state_count) in rules {
+            let mut serial_world = World::new(rule, DefaultBuildHasher::new());
+            let mut parallel_world = World::new(rule, DefaultBuildHasher::new());
+            let mut serial_state = State::create_empty(&mut serial_world);
+            serial_state.set_cube_pow2(
+                &mut serial_world,
+                math::Vec3::splat(-4),
+                4,
+                |_, _| rng.next() % state_count,
+            );
+            let mut parallel_state =
+                State::from(&SerializedState::from(&serial_state), &mut parallel_world).unwrap();
+            for &log2_generation_count in &[0, 1, 0, 2, 3] {
+                serial_state.step(&mut serial_world, log2_generation_count);
+                parallel_state.step_parallel(&mut parallel_world, log2_generation_count);
+                assert!(
+                    SerializedState::from(&serial_state) == SerializedState::from(&parallel_state),
+                    "log2_generation_count = {}",
+                    log2_generation_count
+                );
+            }
+            assert_eq!(
+                serial_state.step_by(&mut serial_world, 11),
+                parallel_state.step_by_parallel(&mut parallel_world, 11)
+            );
+            assert!(SerializedState::from(&serial_state) == SerializedState::from(&parallel_state));
+        }
+    }
+
+    #[test]
+    fn test_gc() {
+        fn get_node_count<Step: StepFn<Block>>(
+            world: &World<Block, Step, DefaultBuildHasher>,
+        ) -> usize {
+            unsafe { &*world.shared_world_state.nodes.get() }.len()
+        }
+        let mut world = World::new(life_rule, DefaultBuildHasher::new());
+        let mut reference_world = World::new(life_rule, DefaultBuildHasher::new());
+        let mut state = State::create_empty(&mut world);
+        let mut rng = XorShiftRng(0x2468ACE1);
+        state.set_cube_pow2(&mut world, math::Vec3::splat(-8), 8, |_, _| {
+            rng.next() % 2
+        });
+        let mut reference_state =
+            State::from(&SerializedState::from(&state), &mut reference_world).unwrap();
+        for i in 0..12 {
+            {
+                // leaves next pointing to nodes that are only reachable
+                // through the memoized results
+                let mut discarded_state = state.clone();
+                discarded_state.step(&mut world, 1);
+            }
+            let gc_stats = if i % 4 == 3 {
+                world.full_gc()
+            } else {
+                world.gc()
+            };
+            assert!(gc_stats.final_node_count <= gc_stats.initial_node_count);
+            assert_eq!(gc_stats.final_node_count, get_node_count(&world));
+            state.step(&mut world, 1);
+            reference_state.step(&mut reference_world, 1);
+            assert!(SerializedState::from(&state) == SerializedState::from(&reference_state));
+        }
+        let gc_stats = world.gc();
+        assert!(!gc_stats.full);
+        assert_eq!(gc_stats.final_node_count, get_node_count(&world));
+        mem::drop(state);
+        let gc_stats = world.full_gc();
+        assert!(gc_stats.full);
+        assert_eq!(gc_stats.final_node_count, 0);
+        assert_eq!(get_node_count(&world), 0);
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let mut world = World::new(parity_rule, DefaultBuildHasher::new());
+        let mut reference_world = World::new(parity_rule, DefaultBuildHasher::new());
+        let mut state = State::create_empty(&mut world);
+        let mut rng = XorShiftRng(0x13579BDF);
+        state.set_cube_pow2(&mut world, math::Vec3::splat(-8), 8, |_, _| {
+            rng.next() % 2
+        });
+        let mut reference_state =
+            State::from(&SerializedState::from(&state), &mut reference_world).unwrap();
+        let mut history = Vec::new();
+        for _ in 0..8 {
+            history.push(state.clone());
+            state.step(&mut world, 1);
+            reference_state.step(&mut reference_world, 1);
+        }
+        mem::drop(history);
+        assert_eq!(world.gc().evicted_result_count, 0);
+        // the cached results keep nodes alive that the current state doesn't use
+        let memory_usage = world.memory_usage();
+        world.set_memory_budget(Some(memory_usage - 1));
+        let gc_stats = world.gc();
+        assert!(gc_stats.full);
+        assert!(gc_stats.evicted_result_count > 0);
+        assert!(world.memory_usage() < memory_usage);
+        world.set_memory_budget(Some(0));
+        assert!(world.gc().evicted_result_count > 0);
+        assert_eq!(world.gc().evicted_result_count, 0);
+        let state_memory_usage = world.memory_usage();
+        assert!(state_memory_usage > 0);
+        world.set_memory_budget(Some(state_memory_usage * 4));
+        for _ in 0..4 {
+            state.step(&mut world, 1);
+            reference_state.step(&mut reference_world, 1);
+            world.gc();
+            assert!(SerializedState::from(&state) == SerializedState::from(&reference_state));
+        }
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut world = World::new(parity_rule, DefaultBuildHasher::new());
+        let mut state = State::create_empty(&mut world);
+        assert_eq!(state.population(), 0);
+        let mut rng = XorShiftRng(0x2468ACE0);
+        let mut expected_population = 0;
+        state.set_cube_pow2(&mut world, math::Vec3::splat(-24), 8, |_, _| {
+            let block = rng.next() % 2;
+            expected_population += block as u128;
+            block
+        });
+        assert_eq!(state.population(), expected_population);
+        let stats = world.stats();
+        assert_eq!(stats.node_count, unsafe { &*world.shared_world_state.nodes.get() }.len());
+        assert_eq!(stats.level_node_counts.iter().sum::<usize>(), stats.node_count);
+        assert_eq!(stats.leaf_node_count, stats.level_node_counts[0]);
+        assert_eq!(stats.memo_hit_count, 0);
+        assert_eq!(stats.memo_miss_count, 0);
+        assert!(stats.snapshot_count > 0);
+        assert_eq!(stats.memory_usage, world.memory_usage());
+        let mut stepped_state = state.clone();
+        stepped_state.step(&mut world, 1);
+        let stats = world.stats();
+        assert!(stats.memo_miss_count > 0);
+        let mut stepped_again_state = state.clone();
+        stepped_again_state.step(&mut world, 1);
+        assert!(world.stats().memo_hit_count > stats.memo_hit_count);
+        assert_eq!(world.stats().memo_miss_count, stats.memo_miss_count);
+        // the cube only grows by one block each step, so it stays inside this substate
+        let substate = stepped_state.get_substate(math::Vec3::splat(-32), 32);
+        let mut expected_population = 0;
+        for x in 0..32 {
+            for y in 0..32 {
+                for z in 0..32 {
+                    if substate.get(math::Vec3::new(x, y, z)) != 0 {
+                        expected_population += 1;
+                    }
+                }
+            }
+        }
+        assert_eq!(stepped_state.population(), expected_population);
+    }
+
+    #[test]
+    fn test_bounding_box() {
+        let mut world = World::new(parity_rule, DefaultBuildHasher::new());
+        let mut state = State::create_empty(&mut world);
+        assert_eq!(state.bounding_box(), None);
+        assert_eq!(state.count_if(|block| block == 0), 1 << 63);
+        state.set(&mut world, math::Vec3::new(-1000, 3, 17), 1);
+        assert_eq!(
+            state.bounding_box(),
+            Some(Region::new(
+                math::Vec3::new(-1000, 3, 17),
+                math::Vec3::new(-999, 4, 18)
+            ))
+        );
+        state.set(&mut world, math::Vec3::new(5, -200_000, 40), 2);
+        state.set(&mut world, math::Vec3::new(-6, 7, 1 << 19), 2);
+        assert_eq!(
+            state.bounding_box(),
+            Some(Region::new(
+                math::Vec3::new(-1000, -200_000, 17),
+                math::Vec3::new(6, 8, (1 << 19) + 1)
+            ))
+        );
+        assert_eq!(state.count_if(|block| block == 2), 2);
+        assert_eq!(state.population(), 3);
+        state.set(&mut world, math::Vec3::new(-1000, 3, 17), 0);
+        assert_eq!(
+            state.bounding_box(),
+            Some(Region::new(
+                math::Vec3::new(-6, -200_000, 40),
+                math::Vec3::new(6, 8, (1 << 19) + 1)
+            ))
+        );
+        let mut rng = XorShiftRng(0x1234);
+        let mut state = State::create_empty(&mut world);
+        state.set_cube_pow2(&mut world, math::Vec3::new(-16, 0, 32), 16, |position, _| {
+            if position.x >= 2 && position.y < 7 && position.z == 9 && rng.next() % 2 == 0 {
+                1
+            } else {
+                0
+            }
+        });
+        let substate = state.get_substate(math::Vec3::new(-16, 0, 32), 16);
+        let mut expected_bounding_box: Option<Region<i32>> = None;
+        for x in 0..16 {
+            for y in 0..16 {
+                for z in 0..16 {
+                    if substate.get(math::Vec3::new(x, y, z)) == 0 {
+                        continue;
+                    }
+                    let position = math::Vec3::new(x as i32 - 16, y as i32, z as i32 + 32);
+                    let block_region = Region::new(position, position + math::Vec3::splat(1));
+                    expected_bounding_box = Some(match expected_bounding_box {
+                        Some(expected_bounding_box) => expected_bounding_box.union(block_region),
+                        None => block_region,
+                    });
+                }
+            }
+        }
+        assert!(expected_bounding_box.is_some());
+        assert_eq!(state.bounding_box(), expected_bounding_box);
+    }
+
+    #[test]
+    fn test_iter_non_default() {
+        let mut world = World::new(parity_rule, DefaultBuildHasher::new());
+        let mut state = State::create_empty(&mut world);
+        let everything = Region::new(
+            math::Vec3::splat(i32::min_value()),
+            math::Vec3::splat(i32::max_value()),
+        );
+        assert_eq!(state.iter_non_default(everything).next(), None);
+        let mut rng = XorShiftRng(0xFEDCBA98);
+        let mut blocks = Vec::new();
+        for _ in 0..100 {
+            let position = math::Vec3::new(rng.next(), rng.next(), rng.next())
+                .map(|v| (v % 4096) as i32 - 2048);
+            let block = rng.next() % 3 + 1;
+            state.set(&mut world, position, block);
+            blocks.retain(|&(v, _)| v != position);
+            blocks.push((position, block));
+        }
+        let sort_key =
+            |&(position, _): &(math::Vec3<i32>, Block)| (position.x, position.y, position.z);
+        blocks.sort_by_key(&sort_key);
+        let mut iterated_blocks: Vec<_> = state.iter_non_default(everything).collect();
+        iterated_blocks.sort_by_key(&sort_key);
+        assert_eq!(iterated_blocks, blocks);
+        let region = Region::new(math::Vec3::new(-1000, -2000, 0), math::Vec3::new(1500, 0, 2048));
+        let expected_blocks: Vec<_> = blocks
+            .iter()
+            .cloned()
+            .filter(|&(position, _)| {
+                region.contains_region(&Region::new(position, position + math::Vec3::splat(1)))
+            }).collect();
+        assert!(!expected_blocks.is_empty());
+        let mut iterated_blocks: Vec<_> = state.iter_non_default(region).collect();
+        iterated_blocks.sort_by_key(&sort_key);
+        assert_eq!(iterated_blocks, expected_blocks);
+        let empty_region = Region::new(math::Vec3::splat(5), math::Vec3::new(5, 10, 10));
+        assert_eq!(state.iter_non_default(empty_region).next(), None);
+    }
+
+    #[test]
+    fn test_region_editing() {
+        const SIZE: usize = 64;
+        let stride = math::Vec3::new(1, SIZE, SIZE * SIZE);
+        let read_blocks = |state: &State<Block, DefaultBuildHasher>| {
+            let mut blocks = vec![0; SIZE * SIZE * SIZE];
+            state
+                .get_substate(math::Vec3::splat(0), SIZE as u32)
+                .get_cube_pow2(math::Vec3::splat(0), SIZE as u32, stride, &mut blocks);
+            blocks
+        };
+        let mut world = World::new(parity_rule, DefaultBuildHasher::new());
+        let mut rng = XorShiftRng(0x31415926);
+        let mut state = State::create_empty(&mut world);
+        state.set_cube_pow2(&mut world, math::Vec3::splat(0), SIZE as u32, |_, _| {
+            rng.next() % 4
+        });
+        let mut source = State::create_empty(&mut world);
+        source.set_cube_pow2(&mut world, math::Vec3::splat(0), SIZE as u32, |_, _| {
+            rng.next() % 4
+        });
+        let mut expected_blocks = read_blocks(&state);
+        let source_blocks = read_blocks(&source);
+        let check_region = |expected_blocks: &mut Vec<Block>,
+                                region: Region<i32>,
+                                f: &Fn(math::Vec3<i32>) -> Block| {
+            for x in region.min.x..region.max.x {
+                for y in region.min.y..region.max.y {
+                    for z in region.min.z..region.max.z {
+                        let position = math::Vec3::new(x, y, z);
+                        expected_blocks[position.map(|v| v as usize).dot(stride)] = f(position);
+                    }
+                }
+            }
+        };
+        let region = Region::new(math::Vec3::new(3, 5, 7), math::Vec3::new(50, 41, 60));
+        state.fill(&mut world, region, 2);
+        check_region(&mut expected_blocks, region, &|_| 2);
+        assert!(read_blocks(&state) == expected_blocks);
+        let region = Region::new(math::Vec3::splat(16), math::Vec3::splat(48));
+        state.fill(&mut world, region, 0);
+        check_region(&mut expected_blocks, region, &|_| 0);
+        assert!(read_blocks(&state) == expected_blocks);
+        let source_region = Region::new(math::Vec3::new(5, 9, 1), math::Vec3::new(40, 30, 33));
+        let position = math::Vec3::new(20, 1, 17);
+        state.paste(&mut world, &source, source_region, position);
+        check_region(
+            &mut expected_blocks,
+            Region::new(position, position + source_region.max - source_region.min),
+            &|v| {
+                let v = v - position + source_region.min;
+                source_blocks[v.map(|v| v as usize).dot(stride)]
+            },
+        );
+        assert!(read_blocks(&state) == expected_blocks);
+        let source_region = Region::new(math::Vec3::splat(0), math::Vec3::splat(32));
+        let position = math::Vec3::new(32, 0, 32);
+        state.paste(&mut world, &source, source_region, position);
+        check_region(
+            &mut expected_blocks,
+            Region::new(position, position + math::Vec3::splat(32)),
+            &|v| source_blocks[(v - position).map(|v| v as usize).dot(stride)],
+        );
+        assert!(read_blocks(&state) == expected_blocks);
+        let substate = source.get_substate(math::Vec3::splat(16), 16);
+        let position = math::Vec3::new(45, 3, 30);
+        state.stamp(&mut world, &substate, position);
+        check_region(
+            &mut expected_blocks,
+            Region::new(position, position + math::Vec3::splat(16)),
+            &|v| {
+                let v = v - position + math::Vec3::splat(16);
+                source_blocks[v.map(|v| v as usize).dot(stride)]
+            },
+        );
+        assert!(read_blocks(&state) == expected_blocks);
+        // filling a huge region is cheap since the filled nodes are shared
+        let region = Region::new(
+            math::Vec3::splat(-1 << 19),
+            math::Vec3::new(1 << 19, 1 << 19, 10),
+        );
+        state.fill(&mut world, region, 3);
+        check_region(
+            &mut expected_blocks,
+            Region::new(math::Vec3::splat(0), math::Vec3::new(64, 64, 10)),
+            &|_| 3,
+        );
+        assert!(read_blocks(&state) == expected_blocks);
+        let unfilled_count = expected_blocks[10 * SIZE * SIZE..]
+            .iter()
+            .filter(|v| **v == 3)
+            .count() as u128;
+        assert_eq!(
+            state.count_if(|block| block == 3),
+            (1 << 40) * ((1 << 19) + 10) + unfilled_count
+        );
+    }
+
+    #[test]
+    fn test_transform() {
+        let transforms = Transform::all();
+        assert_eq!(transforms.len(), 48);
+        for (i, a) in transforms.iter().enumerate() {
+            for b in &transforms[..i] {
+                assert!(a != b);
+            }
+        }
+        let mut world = World::new(parity_rule, DefaultBuildHasher::new());
+        let mut state = State::create_empty(&mut world);
+        let mut rng = XorShiftRng(0x0DDBA11);
+        for _ in 0..50 {
+            let position = math::Vec3::new(rng.next(), rng.next(), rng.next())
+                .map(|v| (v % 40) as i32 - 20);
+            state.set(&mut world, position, rng.next() % 3 + 1);
+        }
+        assert!(state.transformed(&mut world, Transform::identity()) == state);
+        let everything = Region::new(
+            math::Vec3::splat(i32::min_value()),
+            math::Vec3::splat(i32::max_value()),
+        );
+        let sort_key =
+            |&(position, _): &(math::Vec3<i32>, Block)| (position.x, position.y, position.z);
+        for &transform in &transforms {
+            let mut expected_blocks: Vec<_> = state
+                .iter_non_default(everything)
+                .map(|(position, block)| (transform.apply(position), block))
+                .collect();
+            expected_blocks.sort_by_key(&sort_key);
+            let transformed_state = state.transformed(&mut world, transform);
+            let mut blocks: Vec<_> = transformed_state.iter_non_default(everything).collect();
+            blocks.sort_by_key(&sort_key);
+            assert_eq!(blocks, expected_blocks, "transform = {:?}", transform);
+        }
+        let substate = state.get_substate(math::Vec3::splat(0), 32);
+        let transform = Transform::new([2, 0, 1], [true, false, true]);
+        let transformed_substate = substate.transformed(&mut world, transform);
+        for x in 0..32 {
+            for y in 0..32 {
+                for z in 0..32 {
+                    let position = math::Vec3::new(x, y, z);
+                    assert_eq!(
+                        transformed_substate.get(transform.apply_in_cube(position, 32)),
+                        substate.get(position)
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_delta() {
+        let mut world = World::new(parity_rule, DefaultBuildHasher::new());
+        let mut base = State::create_empty(&mut world);
+        let mut rng = XorShiftRng(0x600D5EED);
+        base.set_cube_pow2(&mut world, math::Vec3::splat(-32), 32, |_, _| rng.next() % 2);
+        let delta = SerializedDelta::new(&base, &base);
+        assert!(delta.nodes().is_empty());
+        assert!(State::from_delta(&base, &delta, &mut world).unwrap() == base);
+        let mut state = base.clone();
+        state.set(&mut world, math::Vec3::new(-5, -6, -7), 1);
+        state.set(&mut world, math::Vec3::new(100, 200, 300), 1);
+        let delta = SerializedDelta::new(&base, &state);
+        assert!(delta.nodes().len() < SerializedState::from(&state).nodes().len() / 10);
+        assert!(State::from_delta(&base, &delta, &mut world).unwrap() == state);
+        // the indexes only depend on the base's serialized form, so deltas work in other worlds
+        let mut other_world = World::new(parity_rule, DefaultBuildHasher::new());
+        let other_base = State::from(&SerializedState::from(&base), &mut other_world).unwrap();
+        let other_state = State::from_delta(&other_base, &delta, &mut other_world).unwrap();
+        assert!(SerializedState::from(&other_state) == SerializedState::from(&state));
+        let mut stepped_state = state.clone();
+        stepped_state.step(&mut world, 2);
+        let delta = SerializedDelta::new(&state, &stepped_state);
+        assert!(State::from_delta(&state, &delta, &mut world).unwrap() == stepped_state);
+        assert!(State::from_delta(&base, &delta, &mut world).is_err());
+        // a leaf and the base's root as siblings
+        let base_root = SerializedNodeIndex(delta.base_node_count() - 1);
+        let bad_delta = SerializedDelta::from_parts(
+            delta.base_node_count(),
+            delta.base_fingerprint(),
+            vec![SerializedNode::Nonleaf(
+                [[[SerializedNodeIndex(0), base_root]; 2]; 2],
+            )],
+            SerializedNodeIndex(delta.base_node_count()),
+        ).unwrap();
+        assert!(State::from_delta(&state, &bad_delta, &mut world).is_err());
+        assert!(
+            SerializedDelta::<Block>::from_parts(
+                delta.base_node_count(),
+                delta.base_fingerprint(),
+                Vec::new(),
+                SerializedNodeIndex(delta.base_node_count())
+            ).is_err()
+        );
+        // a different base with the same number of nodes
+        let mut base = State::create_empty(&mut world);
+        base.set(&mut world, math::Vec3::new(1, 2, 3), 1);
+        let mut other_base = State::create_empty(&mut world);
+        other_base.set(&mut world, math::Vec3::new(-3, 2, 3), 1);
+        assert_eq!(
+            SerializedState::from(&base).nodes().len(),
+            SerializedState::from(&other_base).nodes().len()
+        );
+        let mut state = base.clone();
+        state.set(&mut world, math::Vec3::new(1, 2, 4), 1);
+        let delta = SerializedDelta::new(&base, &state);
+        assert!(State::from_delta(&base, &delta, &mut world).unwrap() == state);
+        assert!(State::from_delta(&other_base, &delta, &mut world).is_err());
+    }
 }